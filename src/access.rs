@@ -0,0 +1,203 @@
+use std::sync::Arc;
+
+use crate::{clock::Peer, value::Value, LwwDb};
+
+/// Decides whether the imported ops of a peer are applied, see
+/// [LwwDb::set_access_policy]
+pub trait AccessPolicy: Send + Sync {
+    fn allows(&self, request: &AccessRequest) -> bool;
+}
+
+impl<F> AccessPolicy for F
+where
+    F: Fn(&AccessRequest) -> bool + Send + Sync,
+{
+    fn allows(&self, request: &AccessRequest) -> bool {
+        self(request)
+    }
+}
+
+/// An imported op to check with the [AccessPolicy]
+#[derive(Debug, Clone, Copy)]
+pub struct AccessRequest<'a> {
+    /// The peer that wrote the op
+    pub peer: Peer,
+    pub table: &'a str,
+    /// None if the op deletes the table or defines its schema
+    pub row: Option<&'a str>,
+    /// None if the op deletes the row or the table
+    pub col: Option<&'a str>,
+    /// The written value. It's the total of the peer for
+    /// [AccessKind::Increment], and [Value::Null] for the other kinds than
+    /// [AccessKind::Write].
+    pub value: &'a Value,
+    pub kind: AccessKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AccessKind {
+    /// A write to a cell
+    Write,
+    /// The increments of a counter cell
+    Increment,
+    /// The deletion of a row or a table
+    Delete,
+    /// A table or column definition
+    Define,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Access(Option<Arc<dyn AccessPolicy>>);
+
+impl std::fmt::Debug for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Access(..)"),
+            None => f.write_str("Access(None)"),
+        }
+    }
+}
+
+impl LwwDb {
+    /// Check the imported ops with the policy before they are applied.
+    ///
+    /// The denied ops are handled by the [crate::InvalidOpPolicy] with
+    /// [crate::RejectReason::Denied]. They are not applied, and the version
+    /// stops before them even if the later ops of their peers are applied,
+    /// so they are received again in the next sync and applied once the
+    /// policy allows them. Under [crate::InvalidOpPolicy::Quarantine] they
+    /// are also kept aside and checked again by
+    /// [LwwDb::retry_quarantined_ops].
+    ///
+    /// The local writes are not checked.
+    pub fn set_access_policy(&mut self, policy: impl AccessPolicy + 'static) {
+        self.access.0 = Some(Arc::new(policy));
+    }
+
+    /// Allow all the imported ops again
+    pub fn clear_access_policy(&mut self) {
+        self.access.0 = None;
+    }
+
+    /// Whether the imported op is allowed by the policy
+    pub(crate) fn check_access(&self, request: AccessRequest) -> bool {
+        match &self.access.0 {
+            Some(policy) => policy.allows(&request),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{clock::OpId, ImportStatus, InvalidOpPolicy, RejectReason, TableDef};
+
+    use super::*;
+
+    fn only_table(table: &'static str) -> impl AccessPolicy {
+        move |r: &AccessRequest| r.table == table
+    }
+
+    fn writer() -> LwwDb {
+        let mut db = LwwDb::new();
+        db.set_peer(1);
+        db.define_table("secret", TableDef::new().display_name("Secret"));
+        db.set("public", "a", "x", 1);
+        db.set("secret", "a", "x", 1);
+        db.increment("secret", "a", "n", 2);
+        db.delete_row("secret", "b");
+        db
+    }
+
+    fn denied(status: &ImportStatus) -> Vec<AccessKind> {
+        status
+            .rejected
+            .iter()
+            .map(|r| match r.reason {
+                RejectReason::Denied(kind) => kind,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn report_denied_ops() {
+        let a = writer();
+        let mut b = LwwDb::new();
+        b.set_access_policy(only_table("public"));
+        let status = b
+            .try_import_updates(&a.export_updates(Default::default()))
+            .unwrap();
+        assert_eq!(status.applied, 1);
+        assert_eq!(
+            denied(&status),
+            [
+                AccessKind::Define,
+                AccessKind::Write,
+                AccessKind::Delete,
+                AccessKind::Increment
+            ]
+        );
+        assert_eq!(b.get_cell("public", "a", "x"), Some(&Value::I64(1)));
+        assert_eq!(b.get_cell("secret", "a", "x"), None);
+        assert!(b.table_def("secret").is_none());
+        assert!(!b.version().includes_clock(a.version()));
+    }
+
+    #[test]
+    fn receive_denied_ops_again() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.set("secret", "a", "x", 1);
+        a.set("public", "a", "x", 1);
+        let mut b = LwwDb::new();
+        b.set_access_policy(only_table("public"));
+        let status = b
+            .try_import_updates(&a.export_updates(Default::default()))
+            .unwrap();
+        assert_eq!(denied(&status), [AccessKind::Write]);
+        // the allowed op doesn't include the denied op in the version
+        assert_eq!(b.get_cell("public", "a", "x"), Some(&Value::I64(1)));
+        assert!(!b.version().includes(OpId::new(1, 1)));
+
+        // the denied op is received again, so it's applied once it's allowed
+        a.set("public", "b", "x", 2);
+        b.clear_access_policy();
+        let status = b
+            .try_import_updates(&a.export_updates(b.version().clone()))
+            .unwrap();
+        assert!(status.rejected.is_empty());
+        assert_eq!(b.get_cell("secret", "a", "x"), Some(&Value::I64(1)));
+        assert_eq!(b.version(), a.version());
+        assert!(a.check_eq(&mut b));
+    }
+
+    #[test]
+    fn retry_denied_ops() {
+        let a = writer();
+        let mut b = LwwDb::new();
+        b.set_invalid_op_policy(InvalidOpPolicy::Quarantine);
+        b.set_access_policy(only_table("public"));
+        let status = b
+            .try_import_updates(&a.export_updates(Default::default()))
+            .unwrap();
+        assert_eq!(status.quarantined, 4);
+
+        // the policy is still consulted when the ops are retried
+        let status = b.retry_quarantined_ops();
+        assert_eq!(status.quarantined, 4);
+        assert_eq!(status.applied, 0);
+
+        b.clear_access_policy();
+        let status = b.retry_quarantined_ops();
+        // the schema ops are not counted like in an import
+        assert_eq!(status.applied, 3);
+        assert_eq!(b.quarantined_ops().count(), 0);
+        assert_eq!(b.get_cell("secret", "a", "n"), Some(&Value::I64(2)));
+        assert!(b.table_def("secret").is_some());
+        assert_eq!(b.version(), a.version());
+        let mut a = a;
+        assert!(a.check_eq(&mut b));
+    }
+}
@@ -3,10 +3,12 @@ use std::ops::{Deref, DerefMut};
 use fxhash::FxHashMap;
 use serde::{Deserialize, Serialize};
 
+use crate::error::LwwResult;
+
 pub type Lamport = u32;
 pub type Peer = u64;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct OpId {
     pub lamport: Lamport,
     pub peer: Peer,
@@ -19,7 +21,7 @@ impl OpId {
 }
 
 /// Inclusive range of [OpId].
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VectorClock {
     pub(crate) map: FxHashMap<Peer, Lamport>,
 }
@@ -57,11 +59,33 @@ impl VectorClock {
         }
     }
 
+    /// Include all the ops included in `other`
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (peer, lamport) in other.iter() {
+            let l = self.map.entry(*peer).or_insert(0);
+            *l = (*l).max(*lamport);
+        }
+    }
+
+    /// Whether all the ops included in `other` are included in `self`
+    pub fn includes_clock(&self, other: &VectorClock) -> bool {
+        other
+            .iter()
+            .all(|(peer, lamport)| self.get(peer).is_some_and(|l| l >= lamport))
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         postcard::to_allocvec(self).unwrap()
     }
 
+    /// # Panics
+    ///
+    /// Panics if the data is corrupted. Use [VectorClock::try_decode] for data that is not trusted.
     pub fn decode(encoded: &[u8]) -> Self {
-        postcard::from_bytes(encoded).unwrap()
+        Self::try_decode(encoded).unwrap()
+    }
+
+    pub fn try_decode(encoded: &[u8]) -> LwwResult<Self> {
+        Ok(postcard::from_bytes(encoded)?)
     }
 }
@@ -1,14 +1,17 @@
 mod bool_rle;
 mod delta_rle;
+mod encrypt;
+mod envelope;
+mod stream;
 mod table_snapshot;
-use itertools::izip;
-
 use std::{
     borrow::{Borrow, Cow},
+    collections::BTreeMap,
     hash::Hash,
     sync::Arc,
 };
 
+use ed25519_dalek::Signature;
 use fxhash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
@@ -16,17 +19,37 @@ use smol_str::SmolStr;
 use crate::{
     clock::{Lamport, OpId, Peer, VectorClock},
     encode::delta_rle::DeltaRleEncoder,
-    oplog::OpLogBuilder,
-    table::RowValue,
+    error::{LwwError, LwwResult},
+    import::ImportStatus,
+    oplog::{txn_of, OpLogBuilder},
+    reconcile::ItemKey,
+    schema::SchemaOp,
+    sign::SignedOp,
     value::Value,
     LwwDb,
 };
 
 use self::{
     delta_rle::DeltaRleDecoder,
-    table_snapshot::{decode_snapshot, encode_snapshot},
+    table_snapshot::{decode_legacy_snapshot, decode_snapshot, encode_snapshot},
+};
+pub use self::{
+    encrypt::{PayloadKey, PayloadMeta},
+    envelope::{PayloadHeader, PayloadKind},
+    stream::{StreamProgress, DEFAULT_CHUNK_SIZE},
 };
 
+/// The version of the layout of the updates, [Final] followed by the
+/// [EncodedSignature]s since version 2. Bump it and keep a decoder of the old
+/// layout when the layout changes.
+const UPDATES_VERSION: u16 = 2;
+/// The version of the layout of [EncodedSnapshot], followed by the version of
+/// the db since version 2, and the [EncodedCoerced] ops since version 3
+const SNAPSHOT_VERSION: u16 = 3;
+/// The version of the layout of [EncodedShallowSnapshot], followed by the
+/// [EncodedCoerced] ops since version 2
+const SHALLOW_SNAPSHOT_VERSION: u16 = 2;
+
 #[derive(Serialize, Deserialize)]
 struct Final<'a> {
     str: Vec<Box<str>>,
@@ -43,6 +66,80 @@ struct Final<'a> {
     peer_idx: Cow<'a, [u8]>,
     #[serde(borrow)]
     lamport: Cow<'a, [u8]>,
+    /// The transactions of the ops, (peer index, start lamport, end lamport)
+    txns: Vec<(usize, Lamport, Lamport)>,
+    schema: Vec<EncodedSchemaOp>,
+    counters: Vec<EncodedCounter>,
+    /// The contexts of the writes to multi-value cells, (op index, clock)
+    contexts: Vec<(usize, EncodedClock)>,
+}
+
+/// The layout of [Final] in the payloads without a header
+#[derive(Deserialize)]
+struct LegacyFinal<'a> {
+    str: Vec<Box<str>>,
+    peers: Vec<Peer>,
+    #[serde(borrow)]
+    table: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    row: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    col: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    value: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    peer_idx: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    lamport: Cow<'a, [u8]>,
+}
+
+impl<'a> From<LegacyFinal<'a>> for Final<'a> {
+    fn from(f: LegacyFinal<'a>) -> Self {
+        Self {
+            str: f.str,
+            peers: f.peers,
+            table: f.table,
+            row: f.row,
+            col: f.col,
+            value: f.value,
+            peer_idx: f.peer_idx,
+            lamport: f.lamport,
+            txns: Vec::new(),
+            schema: Vec::new(),
+            counters: Vec::new(),
+            contexts: Vec::new(),
+        }
+    }
+}
+
+/// A [VectorClock], (peer index, lamport)
+type EncodedClock = Vec<(usize, Lamport)>;
+
+/// A counter entry. The strings are indexes into [Final::str].
+#[derive(Serialize, Deserialize)]
+struct EncodedCounter {
+    table: usize,
+    row: usize,
+    col: usize,
+    peer_idx: usize,
+    lamport: Lamport,
+    total: i64,
+}
+
+/// The signature of an op by its peer, see [LwwDb::set_signing_key]
+#[derive(Serialize, Deserialize)]
+struct EncodedSignature {
+    peer_idx: usize,
+    lamport: Lamport,
+    signature: Signature,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncodedSchemaOp {
+    peer_idx: usize,
+    lamport: Lamport,
+    table: SmolStr,
+    op: SchemaOp,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -50,6 +147,31 @@ struct EncodedSnapshot<'a> {
     peers: Vec<Peer>,
     #[serde(borrow)]
     tables: Vec<EncodedTable<'a>>,
+    txns: Vec<(usize, Lamport, Lamport)>,
+    schema: Vec<EncodedSchemaOp>,
+}
+
+/// The layout of [EncodedSnapshot] in the payloads without a header. The
+/// tables have the layout of `table_snapshot::LegacyTable`.
+#[derive(Deserialize)]
+struct LegacySnapshot<'a> {
+    peers: Vec<Peer>,
+    #[serde(borrow)]
+    tables: Vec<EncodedTable<'a>>,
+}
+
+/// The original values of the imported ops that were coerced,
+/// (peer index, lamport, value)
+type EncodedCoerced = Vec<(usize, Lamport, Value)>;
+
+/// The state of the db without the ops before `frontier`
+#[derive(Serialize, Deserialize)]
+struct EncodedShallowSnapshot<'a> {
+    /// The version of the exporter. The peer indexes are into the peers of
+    /// the snapshot.
+    frontier: EncodedClock,
+    #[serde(borrow)]
+    snapshot: EncodedSnapshot<'a>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -72,10 +194,10 @@ impl<T: Hash + Eq + Clone> Register<T> {
         }
     }
 
-    fn register<Q: ?Sized>(&mut self, value: &Q) -> usize
+    fn register<Q>(&mut self, value: &Q) -> usize
     where
         T: Borrow<Q>,
-        Q: Hash + Eq + ToOwned<Owned = T>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = T>,
     {
         if let Some(&id) = self.to_id.get(value) {
             id
@@ -93,124 +215,548 @@ impl<T: Hash + Eq + Clone> Register<T> {
     }
 }
 
+impl Register<Arc<str>> {
+    fn register_str(&mut self, s: &str) -> usize {
+        match self.to_id.get(s) {
+            Some(&id) => id,
+            None => self.register(&Arc::<str>::from(s)),
+        }
+    }
+}
+
+/// Encodes the ops into a [Final]. The ops are split into chunks of about
+/// `chunk_size` ops, each of which can be imported on its own.
+struct UpdatesEncoder<'a> {
+    db: &'a LwwDb,
+    str_pool: Register<Arc<str>>,
+    peer_pool: Register<Peer>,
+    table_en: DeltaRleEncoder,
+    row_en: DeltaRleEncoder,
+    col_en: DeltaRleEncoder,
+    peer_en: DeltaRleEncoder,
+    lamport_en: DeltaRleEncoder,
+    values: Vec<&'a Value>,
+    contexts: Vec<(usize, EncodedClock)>,
+    counters: Vec<EncodedCounter>,
+    schema: Vec<EncodedSchemaOp>,
+    signatures: Vec<EncodedSignature>,
+    /// The transactions of the ops in the chunk
+    txns: FxHashSet<(Peer, Lamport, Lamport)>,
+}
+
+impl<'a> UpdatesEncoder<'a> {
+    fn new(db: &'a LwwDb) -> Self {
+        Self {
+            db,
+            str_pool: Register::new(),
+            peer_pool: Register::new(),
+            table_en: DeltaRleEncoder::new(),
+            row_en: DeltaRleEncoder::new(),
+            col_en: DeltaRleEncoder::new(),
+            peer_en: DeltaRleEncoder::new(),
+            lamport_en: DeltaRleEncoder::new(),
+            values: Vec::new(),
+            contexts: Vec::new(),
+            counters: Vec::new(),
+            schema: Vec::new(),
+            signatures: Vec::new(),
+            txns: FxHashSet::default(),
+        }
+    }
+
+    /// The number of ops in the chunk
+    fn len(&self) -> usize {
+        self.values.len() + self.counters.len() + self.schema.len()
+    }
+
+    fn push_txn(&mut self, id: OpId) {
+        if let Some((start, end)) = self.db.oplog.txn_of(id) {
+            self.txns.insert((id.peer, start, end));
+        }
+    }
+
+    fn push_signature<'b>(&mut self, op: impl FnOnce() -> SignedOp<'b>) {
+        if !self.db.signatures.is_active() {
+            return;
+        }
+
+        let op = op();
+        if let Some(signature) = self.db.signature_of(&op) {
+            let id = op.id();
+            self.signatures.push(EncodedSignature {
+                peer_idx: self.peer_pool.register(&id.peer),
+                lamport: id.lamport,
+                signature,
+            });
+        }
+    }
+
+    fn push_op(
+        &mut self,
+        id: OpId,
+        table: &str,
+        row: Option<&str>,
+        col: Option<&str>,
+        value: &'a Value,
+        context: Option<&VectorClock>,
+    ) {
+        // the op is exported as it was written, not as it's coerced here
+        let value = self.db.coerced.get(&id).unwrap_or(value);
+        self.push_signature(|| SignedOp::op(id, table, row, col, value, context));
+        if let Some(context) = context {
+            let clock = encode_clock(context, &mut self.peer_pool);
+            self.contexts.push((self.values.len(), clock));
+        }
+
+        let mut register = |s: &str| self.str_pool.register_str(s) as i64;
+        self.table_en.push(register(table));
+        self.row_en.push(row.map_or(0, |r| register(r) + 1));
+        self.col_en.push(col.map_or(0, |c| register(c) + 1));
+        self.values.push(value);
+        self.peer_en.push(self.peer_pool.register(&id.peer) as i64);
+        self.lamport_en.push(id.lamport as i64);
+        self.push_txn(id);
+    }
+
+    fn push_counter(&mut self, id: OpId, table: &str, row: &str, col: &str, total: i64) {
+        self.push_signature(|| SignedOp::Counter {
+            id,
+            table,
+            row,
+            col,
+            total,
+        });
+        self.counters.push(EncodedCounter {
+            table: self.str_pool.register_str(table),
+            row: self.str_pool.register_str(row),
+            col: self.str_pool.register_str(col),
+            peer_idx: self.peer_pool.register(&id.peer),
+            lamport: id.lamport,
+            total,
+        });
+    }
+
+    fn push_schema(&mut self, id: OpId, table: &str, op: SchemaOp) {
+        self.push_signature(|| SignedOp::Schema { id, table, op: &op });
+        self.schema.push(EncodedSchemaOp {
+            peer_idx: self.peer_pool.register(&id.peer),
+            lamport: id.lamport,
+            table: table.into(),
+            op,
+        });
+    }
+
+    /// Push the values of the cell that are written by the op, unless they
+    /// are overwritten
+    fn push_update(&mut self, id: OpId, table_name: &str, row_name: &str, col_name: &str) {
+        let db = self.db;
+        let Some(table) = db.tables.get(table_name) else {
+            return;
+        };
+
+        let (row, col) = (Some(row_name), Some(col_name));
+        if let Some(reg) = table.mv_register(row_name, col_name) {
+            if let Some(v) = reg.values().iter().find(|v| v.id == id) {
+                self.push_op(id, table_name, row, col, &v.value, v.context.as_ref());
+            } else if let Some((_, value)) = reg.superseded().filter(|(s, _)| *s == id) {
+                self.push_op(id, table_name, row, col, value, None);
+            }
+
+            return;
+        }
+
+        let counter = table.counter(row_name, col_name);
+        let entry = counter.and_then(|c| c.iter().find(|(entry_id, _)| *entry_id == id));
+        if let Some((_, total)) = entry {
+            self.push_counter(id, table_name, row_name, col_name, total);
+        }
+
+        // the set of a counter cell is kept after the sum overwrites it
+        if let Some((set, value)) = counter.and_then(|c| c.last_set()) {
+            if set == id {
+                self.push_op(id, table_name, row, col, value, None);
+                return;
+            }
+        }
+
+        let (Some(idx), Some(column)) =
+            (table.row_id_to_idx.get(row_name), table.cols.get(col_name))
+        else {
+            return;
+        };
+        // the sum of a counter is rebuilt from its entries
+        if column.id(*idx) == id && counter.is_none_or(|c| c.max_id() != Some(id)) {
+            self.push_op(id, table_name, row, col, column.value(*idx), None);
+        }
+    }
+
+    /// Push the current value of the cell, with all the values of its
+    /// multi-value register or all the entries of its counter
+    fn push_cell(&mut self, table_name: &str, row_name: &str, col_name: &str) {
+        let db = self.db;
+        let Some(table) = db.tables.get(table_name) else {
+            return;
+        };
+
+        let (row, col) = (Some(row_name), Some(col_name));
+        if let Some(reg) = table.mv_register(row_name, col_name) {
+            for v in reg.values() {
+                self.push_op(v.id, table_name, row, col, &v.value, v.context.as_ref());
+            }
+
+            if let Some((id, value)) = reg.superseded() {
+                self.push_op(id, table_name, row, col, value, None);
+            }
+
+            return;
+        }
+
+        let counter = table.counter(row_name, col_name);
+        for (id, total) in counter.into_iter().flat_map(|c| c.iter()) {
+            self.push_counter(id, table_name, row_name, col_name, total);
+        }
+
+        let set = counter.and_then(|c| c.last_set());
+        if let Some((id, value)) = set {
+            self.push_op(id, table_name, row, col, value, None);
+        }
+
+        let (Some(idx), Some(column)) =
+            (table.row_id_to_idx.get(row_name), table.cols.get(col_name))
+        else {
+            return;
+        };
+        let id = column.id(*idx);
+        if id.lamport != 0
+            && counter.is_none_or(|c| c.max_id() != Some(id))
+            && set.is_none_or(|(set, _)| set != id)
+        {
+            self.push_op(id, table_name, row, col, column.value(*idx), None);
+        }
+    }
+
+    /// Encode the chunk and reset the encoder for the next chunk
+    fn finish(&mut self) -> Vec<u8> {
+        let mut this = std::mem::replace(self, UpdatesEncoder::new(self.db));
+        let txns = this
+            .txns
+            .iter()
+            .map(|(peer, start, end)| (this.peer_pool.register(peer), *start, *end))
+            .collect();
+        let f = Final {
+            str: this
+                .str_pool
+                .finish()
+                .into_iter()
+                .map(|s| (*s).to_string().into_boxed_str())
+                .collect(),
+            peers: this.peer_pool.finish(),
+            table: Cow::Owned(this.table_en.finish()),
+            row: Cow::Owned(this.row_en.finish()),
+            col: Cow::Owned(this.col_en.finish()),
+            value: Cow::Owned(postcard::to_allocvec(&this.values).unwrap()),
+            peer_idx: Cow::Owned(this.peer_en.finish()),
+            lamport: Cow::Owned(this.lamport_en.finish()),
+            txns,
+            schema: this.schema,
+            counters: this.counters,
+            contexts: this.contexts,
+        };
+
+        let mut ans = postcard::to_allocvec(&f).unwrap();
+        ans.extend(postcard::to_allocvec(&this.signatures).unwrap());
+        envelope::seal(PayloadKind::Updates, UPDATES_VERSION, &ans, true)
+    }
+}
+
 impl LwwDb {
+    /// Export the updates since `from`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the db is restored from a shallow snapshot and `from`
+    /// doesn't include its frontier. Use [LwwDb::try_export_updates] to
+    /// handle it.
     pub fn export_updates(&self, from: VectorClock) -> Vec<u8> {
-        let mut str_pool: Register<Arc<str>> = Register::new();
-        let mut peer_pool: Register<Peer> = Register::new();
-        let mut table_en = DeltaRleEncoder::new();
-        let mut row_en = DeltaRleEncoder::new();
-        let mut col_en = DeltaRleEncoder::new();
-        let mut peer_en = DeltaRleEncoder::new();
-        let mut lamport_en = DeltaRleEncoder::new();
+        self.try_export_updates(from).unwrap()
+    }
+
+    /// Export the updates since `from`. It fails with
+    /// [LwwError::NeedFullSnapshot] if the db is restored from a shallow
+    /// snapshot and `from` doesn't include its frontier, because the ops
+    /// before the frontier are not kept.
+    pub fn try_export_updates(&self, from: VectorClock) -> LwwResult<Vec<u8>> {
+        let mut ans = None;
+        self.encode_updates(&from, usize::MAX, |chunk| {
+            debug_assert!(ans.is_none());
+            ans = Some(chunk);
+            Ok(())
+        })?;
+        Ok(ans.unwrap())
+    }
+
+    /// Encode the updates since `from` into chunks of about `chunk_size` ops.
+    /// There is at least one chunk.
+    ///
+    /// The ops are encoded in the order of their lamports, so the ops of
+    /// every peer in a chunk follow all its ops in the previous chunks, and
+    /// the version of the importer covers only the ops it has after every
+    /// chunk. The ops of a transaction are in the same chunk.
+    pub(crate) fn encode_updates(
+        &self,
+        from: &VectorClock,
+        chunk_size: usize,
+        mut on_chunk: impl FnMut(Vec<u8>) -> LwwResult<()>,
+    ) -> LwwResult<()> {
+        if let Some(frontier) = self.oplog.shallow_since() {
+            if !from.includes_clock(frontier) {
+                return Err(LwwError::NeedFullSnapshot);
+            }
+        }
+
         let deleted_v = Value::Deleted;
-        let mut values_en: Vec<&Value> = Vec::new();
-        let mut updated_rows = FxHashSet::default();
-        for (id, op) in self.oplog.iter_from(from.clone()) {
+        let mut en = UpdatesEncoder::new(self);
+        // The chunk is not cut before the end of the transactions of its ops
+        let mut txn_end = 0;
+        for (id, op) in self.oplog.iter_from(from) {
             debug_assert!(!from.includes(id));
+            if en.len() >= chunk_size && id.lamport > txn_end {
+                on_chunk(en.finish())?;
+            }
+
+            if let Some((_, end)) = self.oplog.txn_of(id) {
+                txn_end = txn_end.max(end);
+            }
+
             match op {
-                crate::oplog::Op::Update {
-                    table: table_name,
-                    row: row_name,
-                } => {
-                    updated_rows.insert((table_name, row_name));
+                crate::oplog::Op::Update { table, row, col } => {
+                    en.push_update(id, table, row, col);
                 }
                 crate::oplog::Op::DeleteTable { table } => {
-                    table_en.push(str_pool.register(table) as i64);
-                    row_en.push(0);
-                    col_en.push(0);
-                    values_en.push(&deleted_v);
-                    peer_en.push(peer_pool.register(&id.peer) as i64);
-                    lamport_en.push(id.lamport as i64);
+                    en.push_op(id, table, None, None, &deleted_v, None);
                 }
                 crate::oplog::Op::DeleteRow { table, row } => {
-                    table_en.push(str_pool.register(table) as i64);
-                    row_en.push(str_pool.register(row) as i64 + 1);
-                    col_en.push(0);
-                    values_en.push(&deleted_v);
-                    peer_en.push(peer_pool.register(&id.peer) as i64);
-                    lamport_en.push(id.lamport as i64);
+                    en.push_op(id, table, Some(row), None, &deleted_v, None);
+                }
+                crate::oplog::Op::Define { table, col } => {
+                    if let Some((def_id, op)) = self.schema_meta.get(table, col.as_deref()) {
+                        if def_id == id {
+                            en.push_schema(id, table, op);
+                        }
+                    }
                 }
             }
         }
 
-        for (table_name, row_name) in updated_rows {
-            if let Some(table) = self.tables.get(&**table_name) {
-                for RowValue {
-                    col_name,
-                    id,
-                    value,
-                } in table.iter_row_with_id(row_name)
-                {
-                    if !from.includes(id) {
-                        let col_name: Arc<str> = col_name.into();
-                        table_en.push(str_pool.register(table_name) as i64);
-                        row_en.push(str_pool.register(row_name) as i64 + 1);
-                        col_en.push(str_pool.register(&col_name) as i64 + 1);
-                        values_en.push(value);
-                        peer_en.push(peer_pool.register(&id.peer) as i64);
-                        lamport_en.push(id.lamport as i64);
+        if en.len() > 0 || chunk_size == usize::MAX {
+            on_chunk(en.finish())?;
+        }
+
+        Ok(())
+    }
+
+    /// Encode the current state of the items as an updates payload. Only the
+    /// latest ops of the items are encoded, not their history.
+    pub(crate) fn export_items(&self, keys: &[ItemKey]) -> Vec<u8> {
+        let deleted_v = Value::Deleted;
+        let mut en = UpdatesEncoder::new(self);
+        for key in keys {
+            let Some(table) = self.tables.get(&key.table) else {
+                continue;
+            };
+
+            match (&key.row, &key.col) {
+                (None, _) => {
+                    if let Some(id) = table.removed {
+                        en.push_op(id, &key.table, None, None, &deleted_v, None);
+                    }
+                }
+                (Some(row), None) => {
+                    let deleted = table
+                        .row_id_to_idx
+                        .get(row)
+                        .and_then(|i| table.rows[*i].deleted);
+                    if let Some(id) = deleted {
+                        en.push_op(id, &key.table, Some(row), None, &deleted_v, None);
                     }
                 }
+                (Some(row), Some(col)) => en.push_cell(&key.table, row, col),
             }
         }
 
-        let f = Final {
-            str: str_pool
-                .finish()
-                .into_iter()
-                .map(|s| (*s).to_string().into_boxed_str())
-                .collect(),
-            peers: peer_pool.finish(),
-            table: Cow::Owned(table_en.finish()),
-            row: Cow::Owned(row_en.finish()),
-            col: Cow::Owned(col_en.finish()),
-            value: Cow::Owned(postcard::to_allocvec(&values_en).unwrap()),
-            peer_idx: Cow::Owned(peer_en.finish()),
-            lamport: Cow::Owned(lamport_en.finish()),
-        };
+        en.finish()
+    }
 
-        let ans = postcard::to_allocvec(&f).unwrap();
-        zstd::encode_all(&mut ans.as_slice(), 0).unwrap()
+    /// Read the header of a payload exported by [LwwDb::export_updates] or
+    /// [LwwDb::export_snapshot], and verify its checksum. The payloads exported
+    /// before the header was added are reported as version 0.
+    pub fn inspect_payload(bytes: &[u8]) -> LwwResult<PayloadHeader> {
+        envelope::parse_header(bytes).map(|(header, _)| header)
     }
 
+    /// Import the updates exported by [LwwDb::export_updates].
+    ///
+    /// Use [LwwDb::try_import_updates] to find out which ops were rejected by
+    /// the schemas.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the updates are corrupted. Use [LwwDb::try_import_updates] for
+    /// payloads that are not trusted.
     pub fn import_updates(&mut self, bytes: &[u8]) {
-        let bytes = zstd::decode_all(bytes).unwrap();
-        let f = postcard::from_bytes::<Final>(&bytes).unwrap();
-        let peers = f.peers;
-        let str = f.str;
-        let values = postcard::from_bytes::<Vec<Value>>(&f.value).unwrap();
-        let table = DeltaRleDecoder::new(&f.table);
-        let row = DeltaRleDecoder::new(&f.row);
-        let col = DeltaRleDecoder::new(&f.col);
-        let lamport = DeltaRleDecoder::new(&f.lamport);
-        let peer_idx = DeltaRleDecoder::new(&f.peer_idx);
-
-        for (i, (t, r, c, peer_idx, l)) in izip!(table, row, col, peer_idx, lamport).enumerate() {
-            let table = &str[t as usize];
-            let row = if r == 0 {
-                None
-            } else {
-                Some(&str[r as usize - 1])
-            };
-            let col = if c == 0 {
-                None
-            } else {
-                Some(&str[c as usize - 1])
-            };
-            let value = values[i].clone();
-            let peer = peers[peer_idx as usize];
-            let id = OpId {
-                peer,
-                lamport: l as Lamport,
+        self.try_import_updates(bytes).unwrap();
+    }
+
+    /// Import the updates exported by [LwwDb::export_updates].
+    ///
+    /// The whole payload is validated before it's applied, so nothing is
+    /// changed if an error is returned. The ops that violate the registered
+    /// schemas are handled by the [crate::InvalidOpPolicy] and reported in
+    /// the returned [ImportStatus].
+    pub fn try_import_updates(&mut self, bytes: &[u8]) -> LwwResult<ImportStatus> {
+        let (version, bytes) = envelope::open(bytes, PayloadKind::Updates)?;
+        let (f, signatures) = match version {
+            envelope::LEGACY_VERSION => (
+                postcard::from_bytes::<LegacyFinal>(&bytes)?.into(),
+                Vec::new(),
+            ),
+            1 => (postcard::from_bytes::<Final>(&bytes)?, Vec::new()),
+            2 => {
+                let (f, rest) = postcard::take_from_bytes::<Final>(&bytes)?;
+                (f, postcard::from_bytes::<Vec<EncodedSignature>>(rest)?)
+            }
+            v => return Err(LwwError::UnsupportedVersion(v)),
+        };
+        let signatures = decode_signatures(&signatures, &f.peers)?;
+        let values = postcard::from_bytes::<Vec<Value>>(&f.value)?;
+        let txns = decode_txns(&f.txns, &f.peers)?;
+        let schema_ops = decode_schema_ops(&f.schema, &f.peers)?;
+        let counters = decode_counters(&f)?;
+        let contexts = f
+            .contexts
+            .iter()
+            .map(|(i, c)| Ok((*i, decode_clock(c, &f.peers)?)))
+            .collect::<LwwResult<FxHashMap<usize, VectorClock>>>()?;
+        decode_ops(&f, &values, &contexts, |_| {})?;
+
+        // The schema ops are applied first. They don't validate the other ops,
+        // see LwwDb::check_remote_write.
+        let mut status = ImportStatus::default();
+        for (id, table, op) in schema_ops {
+            let signed = || SignedOp::Schema { id, table, op };
+            if self.check_signature(id, signed, &signatures, &mut status) {
+                self.import_schema_op(id, table, op.clone(), &mut status);
+            }
+        }
+
+        // The ops of a transaction are applied together after the other ops
+        let mut txn_ops: BTreeMap<(Peer, Lamport, Lamport), Vec<DecodedOp>> = BTreeMap::new();
+        decode_ops(&f, &values, &contexts, |op| {
+            if let Some((start, end)) = txns.get(&op.id.peer).and_then(|t| txn_of(t, op.id.lamport))
+            {
+                txn_ops
+                    .entry((op.id.peer, start, end))
+                    .or_default()
+                    .push(op);
+                return;
+            }
+
+            let signed = || SignedOp::op(op.id, op.table, op.row, op.col, op.value, op.context);
+            if self.check_signature(op.id, signed, &signatures, &mut status) {
+                self.import_op(op, &mut status);
+            }
+        })?;
+
+        // a forged op drops its whole transaction
+        for (txn, ops) in txn_ops {
+            if self.check_txn_signatures(&ops, &signatures, &mut status) {
+                self.import_txn(txn, ops, &mut status);
+            }
+        }
+
+        for (id, table, row, col, total) in counters {
+            let signed = || SignedOp::Counter {
+                id,
+                table,
+                row,
+                col,
+                total,
             };
+            if !self.check_signature(id, signed, &signatures, &mut status) {
+                continue;
+            }
 
-            self.apply_op(id, table, row.map(|x| &**x), col.map(|x| &**x), value);
+            self.import_counter(id, table, row, col, total, &mut status);
         }
+
+        Ok(status)
     }
 
     pub fn export_snapshot(&self) -> Vec<u8> {
+        let mut peer_pool = Register::new();
+        let version = encode_clock(self.version(), &mut peer_pool);
+        let coerced = self.encode_coerced(&mut peer_pool);
+        let encoded = self.to_encoded_snapshot(peer_pool, true);
+        let mut ans = postcard::to_allocvec(&encoded).unwrap();
+        ans.extend(postcard::to_allocvec(&version).unwrap());
+        ans.extend(postcard::to_allocvec(&coerced).unwrap());
+        envelope::seal(PayloadKind::Snapshot, SNAPSHOT_VERSION, &ans, false)
+    }
+
+    /// Export the state of the db without its history. The db restored from
+    /// it by [LwwDb::from_snapshot] doesn't index the ops of the snapshot, so
+    /// it's cheaper to load, but it can only export the updates since the
+    /// current version, see [LwwDb::try_export_updates].
+    pub fn export_shallow_snapshot(&self) -> Vec<u8> {
+        let mut peer_pool = Register::new();
+        let frontier = encode_clock(self.version(), &mut peer_pool);
+        let coerced = self.encode_coerced(&mut peer_pool);
+        let encoded = EncodedShallowSnapshot {
+            frontier,
+            snapshot: self.to_encoded_snapshot(peer_pool, false),
+        };
+        let mut ans = postcard::to_allocvec(&encoded).unwrap();
+        ans.extend(postcard::to_allocvec(&coerced).unwrap());
+        envelope::seal(
+            PayloadKind::ShallowSnapshot,
+            SHALLOW_SNAPSHOT_VERSION,
+            &ans,
+            false,
+        )
+    }
+
+    /// The version of the shallow snapshot the db is restored from. The
+    /// updates before it can't be exported. It's None if the db has its
+    /// full history.
+    pub fn shallow_frontier(&self) -> Option<&VectorClock> {
+        self.oplog.shallow_since()
+    }
+
+    fn encode_coerced(&self, peer_pool: &mut Register<Peer>) -> EncodedCoerced {
+        self.coerced
+            .iter()
+            .map(|(id, value)| (peer_pool.register(&id.peer), id.lamport, value.clone()))
+            .collect()
+    }
+
+    fn decode_coerced(&mut self, coerced: EncodedCoerced, peers: &[Peer]) -> LwwResult<()> {
+        for (peer_idx, lamport, value) in coerced {
+            let peer = *peers.get(peer_idx).ok_or(LwwError::PeerIndexOutOfRange {
+                index: peer_idx as i64,
+                len: peers.len(),
+            })?;
+            self.coerced.insert(OpId::new(lamport, peer), value);
+        }
+
+        Ok(())
+    }
+
+    fn to_encoded_snapshot(
+        &self,
+        mut peer_pool: Register<Peer>,
+        with_txns: bool,
+    ) -> EncodedSnapshot<'static> {
         let mut ans: Vec<EncodedTable> = Vec::new();
-        let mut peer_pool: Register<Peer> = Register::new();
         for (name, table) in self.iter_tables() {
             ans.push(EncodedTable {
                 str: name.clone(),
@@ -218,60 +764,428 @@ impl LwwDb {
             })
         }
 
-        let encoded = EncodedSnapshot {
+        let txns = if with_txns {
+            self.oplog
+                .iter_txns_from(&Default::default())
+                .map(|(peer, start, end)| (peer_pool.register(&peer), start, end))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let schema = self
+            .schema_meta
+            .iter()
+            .map(|(table, id, op)| EncodedSchemaOp {
+                peer_idx: peer_pool.register(&id.peer),
+                lamport: id.lamport,
+                table: table.clone(),
+                op,
+            })
+            .collect();
+        EncodedSnapshot {
             peers: peer_pool.finish(),
             tables: ans,
-        };
-
-        postcard::to_allocvec(&encoded).unwrap()
+            txns,
+            schema,
+        }
     }
 
+    /// Create a db from the snapshot exported by [LwwDb::export_snapshot] or
+    /// [LwwDb::export_shallow_snapshot].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot is corrupted. Use [LwwDb::try_from_snapshot] for
+    /// payloads that are not trusted.
     pub fn from_snapshot(data: &[u8]) -> Self {
-        let encoded: EncodedSnapshot = postcard::from_bytes(data).unwrap();
+        Self::try_from_snapshot(data).unwrap()
+    }
+
+    pub fn try_from_snapshot(data: &[u8]) -> LwwResult<Self> {
+        let kinds = [PayloadKind::Snapshot, PayloadKind::ShallowSnapshot];
+        let (header, data) = envelope::open_one_of(data, &kinds)?;
+        match (header.kind, header.version) {
+            (PayloadKind::Snapshot, envelope::LEGACY_VERSION) => {
+                let legacy: LegacySnapshot = postcard::from_bytes(&data)?;
+                let encoded = EncodedSnapshot {
+                    peers: legacy.peers,
+                    tables: legacy.tables,
+                    txns: Vec::new(),
+                    schema: Vec::new(),
+                };
+                Self::from_encoded_snapshot(encoded, None, true)
+            }
+            (PayloadKind::Snapshot, 1) => {
+                Self::from_encoded_snapshot(postcard::from_bytes(&data)?, None, false)
+            }
+            (PayloadKind::Snapshot, 2) => {
+                let (encoded, rest) = postcard::take_from_bytes(&data)?;
+                Self::from_encoded_snapshot(encoded, Some(postcard::from_bytes(rest)?), false)
+            }
+            (PayloadKind::Snapshot, 3) => {
+                let (encoded, rest): (EncodedSnapshot, _) = postcard::take_from_bytes(&data)?;
+                let (version, rest) = postcard::take_from_bytes(rest)?;
+                let peers = encoded.peers.clone();
+                let mut db = Self::from_encoded_snapshot(encoded, Some(version), false)?;
+                db.decode_coerced(postcard::from_bytes(rest)?, &peers)?;
+                Ok(db)
+            }
+            (PayloadKind::ShallowSnapshot, 1) => {
+                let encoded: EncodedShallowSnapshot = postcard::from_bytes(&data)?;
+                let frontier = decode_clock(&encoded.frontier, &encoded.snapshot.peers)?;
+                Self::from_encoded_shallow_snapshot(encoded.snapshot, frontier)
+            }
+            (PayloadKind::ShallowSnapshot, 2) => {
+                let (encoded, rest): (EncodedShallowSnapshot, _) =
+                    postcard::take_from_bytes(&data)?;
+                let frontier = decode_clock(&encoded.frontier, &encoded.snapshot.peers)?;
+                let peers = encoded.snapshot.peers.clone();
+                let mut db = Self::from_encoded_shallow_snapshot(encoded.snapshot, frontier)?;
+                db.decode_coerced(postcard::from_bytes(rest)?, &peers)?;
+                Ok(db)
+            }
+            (_, v) => Err(LwwError::UnsupportedVersion(v)),
+        }
+    }
+
+    fn from_encoded_shallow_snapshot(
+        encoded: EncodedSnapshot,
+        frontier: VectorClock,
+    ) -> LwwResult<Self> {
         let mut db = LwwDb::new();
+        for (id, table, op) in decode_schema_ops(&encoded.schema, &encoded.peers)? {
+            if !frontier.includes(id) {
+                return Err(LwwError::InvalidOp("op is not included in the frontier"));
+            }
+
+            db.schema_meta.apply(id, table, op.clone());
+        }
+
+        // the ops that are not included in the frontier were received
+        // without their history, so they are kept in the log
         let mut oplog_builder = OpLogBuilder::default();
         for table in encoded.tables {
             let v = decode_snapshot(&table.table, &encoded.peers, |c| match c {
+                table_snapshot::Change::DelTable { id } if !frontier.includes(id) => {
+                    oplog_builder.record_delete_table(id, table.str.clone());
+                }
+                table_snapshot::Change::DelRow { row, id } if !frontier.includes(id) => {
+                    oplog_builder.record_delete_row(id, table.str.clone(), row.clone());
+                }
+                table_snapshot::Change::Value { row, col, id } if !frontier.includes(id) => {
+                    oplog_builder.record_update(id, table.str.clone(), row.clone(), col.clone());
+                }
+                _ => {}
+            })?;
+            db.tables.insert(table.str, v);
+        }
+
+        db.oplog = oplog_builder.build();
+        db.oplog.set_shallow(frontier);
+        Ok(db)
+    }
+
+    /// Restore the db from the snapshot. Its version is the max of the ops in
+    /// it if `version` is None. The tables have the legacy layout if `legacy`
+    /// is true.
+    fn from_encoded_snapshot(
+        encoded: EncodedSnapshot,
+        version: Option<EncodedClock>,
+        legacy: bool,
+    ) -> LwwResult<Self> {
+        let version = version
+            .map(|v| decode_clock(&v, &encoded.peers))
+            .transpose()?;
+        let mut db = LwwDb::new();
+        let mut oplog_builder = OpLogBuilder::default();
+        for (peer, txns) in decode_txns(&encoded.txns, &encoded.peers)? {
+            for (start, end) in txns {
+                oplog_builder.record_txn(peer, start, end);
+            }
+        }
+
+        for (id, table, op) in decode_schema_ops(&encoded.schema, &encoded.peers)? {
+            db.schema_meta.apply(id, table, op.clone());
+            oplog_builder.record_define(id, table.clone(), op.col().cloned());
+        }
+
+        for table in encoded.tables {
+            let on_change = |c: table_snapshot::Change| match c {
                 table_snapshot::Change::DelTable { id } => {
                     oplog_builder.record_delete_table(id, table.str.clone());
                 }
                 table_snapshot::Change::DelRow { row, id } => {
                     oplog_builder.record_delete_row(id, table.str.clone(), row.clone());
                 }
-                table_snapshot::Change::Value { row, id } => {
-                    oplog_builder.record_update(id, table.str.clone(), row.clone());
+                table_snapshot::Change::Value { row, col, id } => {
+                    oplog_builder.record_update(id, table.str.clone(), row.clone(), col.clone());
                 }
-            });
+            };
+            let v = if legacy {
+                decode_legacy_snapshot(&table.table, &encoded.peers, on_change)?
+            } else {
+                decode_snapshot(&table.table, &encoded.peers, on_change)?
+            };
             db.tables.insert(table.str, v);
         }
 
         db.oplog = oplog_builder.build();
-        db
+        if let Some(version) = version {
+            db.oplog.set_version(version);
+        }
+
+        Ok(db)
     }
 
-    fn apply_op(
-        &mut self,
-        id: OpId,
-        table: &str,
-        row: Option<&str>,
-        col: Option<&str>,
-        value: Value,
-    ) {
-        match value {
-            Value::Deleted => match (row, col) {
-                (None, None) => self.delete_table_(table, Some(id)),
-                (Some(row), None) => self.delete_row_(table, row, Some(id)),
-                (Some(row), Some(col)) => self.set_(table, row, col, value, Some(id)),
-                (None, Some(_)) => unreachable!(),
-            },
-            _ => self.set_(table, row.unwrap(), col.unwrap(), value, Some(id)),
+    /// Apply the op with `value`, which replaces the value of the op if it's coerced
+    pub(crate) fn apply_op(&mut self, op: &DecodedOp, value: Value) {
+        let DecodedOp {
+            id,
+            table,
+            row,
+            col,
+            ..
+        } = *op;
+        if value != *op.value {
+            self.coerced.insert(id, op.value.clone());
         }
+
+        let context = op.context.cloned();
+        match (row, col) {
+            (None, None) => self.delete_table_(table, Some(id)),
+            (Some(row), None) => self.delete_row_(table, row, Some(id)),
+            (Some(row), Some(col)) => self.set_with_context_(table, row, col, value, id, context),
+            (None, Some(_)) => unreachable!(),
+        }
+
+        self.oplog.include(id);
     }
 }
 
+fn encode_clock(clock: &VectorClock, peer_pool: &mut Register<Peer>) -> EncodedClock {
+    clock
+        .iter()
+        .map(|(peer, lamport)| (peer_pool.register(peer), *lamport))
+        .collect()
+}
+
+fn decode_clock(clock: &EncodedClock, peers: &[Peer]) -> LwwResult<VectorClock> {
+    let mut ans = VectorClock::new();
+    for (peer_idx, lamport) in clock {
+        let peer = *peers.get(*peer_idx).ok_or(LwwError::PeerIndexOutOfRange {
+            index: *peer_idx as i64,
+            len: peers.len(),
+        })?;
+        ans.insert(peer, *lamport);
+    }
+
+    Ok(ans)
+}
+
+/// Decode the transaction ranges, (peer index, start lamport, end lamport)
+fn decode_txns(
+    txns: &[(usize, Lamport, Lamport)],
+    peers: &[Peer],
+) -> LwwResult<FxHashMap<Peer, BTreeMap<Lamport, Lamport>>> {
+    let mut ans: FxHashMap<Peer, BTreeMap<Lamport, Lamport>> = FxHashMap::default();
+    for (peer_idx, start, end) in txns {
+        let peer = *peers.get(*peer_idx).ok_or(LwwError::PeerIndexOutOfRange {
+            index: *peer_idx as i64,
+            len: peers.len(),
+        })?;
+        if start > end || *start == 0 {
+            return Err(LwwError::InvalidOp("invalid transaction range"));
+        }
+
+        ans.entry(peer).or_default().insert(*start, *end);
+    }
+
+    Ok(ans)
+}
+
+fn decode_schema_ops<'a>(
+    ops: &'a [EncodedSchemaOp],
+    peers: &[Peer],
+) -> LwwResult<Vec<(OpId, &'a SmolStr, &'a SchemaOp)>> {
+    ops.iter()
+        .map(|op| {
+            let peer = *peers
+                .get(op.peer_idx)
+                .ok_or(LwwError::PeerIndexOutOfRange {
+                    index: op.peer_idx as i64,
+                    len: peers.len(),
+                })?;
+            if op.lamport == 0 {
+                return Err(LwwError::InvalidOp("lamport out of range"));
+            }
+
+            Ok((OpId::new(op.lamport, peer), &op.table, &op.op))
+        })
+        .collect()
+}
+
+fn decode_signatures(
+    signatures: &[EncodedSignature],
+    peers: &[Peer],
+) -> LwwResult<FxHashMap<OpId, Signature>> {
+    signatures
+        .iter()
+        .map(|s| {
+            let peer = *peers.get(s.peer_idx).ok_or(LwwError::PeerIndexOutOfRange {
+                index: s.peer_idx as i64,
+                len: peers.len(),
+            })?;
+            Ok((OpId::new(s.lamport, peer), s.signature))
+        })
+        .collect()
+}
+
+/// A counter entry decoded from [Final], (id, table, row, col, total)
+type DecodedCounter<'a> = (OpId, &'a str, &'a str, &'a str, i64);
+
+fn decode_counters<'a>(f: &'a Final) -> LwwResult<Vec<DecodedCounter<'a>>> {
+    let get_str = |index: usize| -> LwwResult<&'a str> {
+        f.str
+            .get(index)
+            .map(|s| &**s)
+            .ok_or(LwwError::StrIndexOutOfRange {
+                index: index as i64,
+                len: f.str.len(),
+            })
+    };
+    f.counters
+        .iter()
+        .map(|c| {
+            let peer = *f
+                .peers
+                .get(c.peer_idx)
+                .ok_or(LwwError::PeerIndexOutOfRange {
+                    index: c.peer_idx as i64,
+                    len: f.peers.len(),
+                })?;
+            if c.lamport == 0 {
+                return Err(LwwError::InvalidOp("lamport out of range"));
+            }
+
+            Ok((
+                OpId::new(c.lamport, peer),
+                get_str(c.table)?,
+                get_str(c.row)?,
+                get_str(c.col)?,
+                c.total,
+            ))
+        })
+        .collect()
+}
+
+/// An op decoded from [Final]
+pub(crate) struct DecodedOp<'a> {
+    pub id: OpId,
+    pub table: &'a str,
+    pub row: Option<&'a str>,
+    pub col: Option<&'a str>,
+    pub value: &'a Value,
+    /// The version of the writer if it's written to a multi-value column
+    pub context: Option<&'a VectorClock>,
+}
+
+/// Decode and validate the ops in [Final]. The ops are passed to `on_op` in order.
+///
+/// It returns an error if any op is invalid, but the ops before the invalid
+/// one have already been passed to `on_op`.
+fn decode_ops<'a>(
+    f: &'a Final,
+    values: &'a [Value],
+    contexts: &'a FxHashMap<usize, VectorClock>,
+    mut on_op: impl FnMut(DecodedOp<'a>),
+) -> LwwResult<()> {
+    let get_str = |index: i64| -> LwwResult<&'a str> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| f.str.get(i))
+            .map(|s| &**s)
+            .ok_or(LwwError::StrIndexOutOfRange {
+                index,
+                len: f.str.len(),
+            })
+    };
+    let mut table = DeltaRleDecoder::new(&f.table);
+    let mut row = DeltaRleDecoder::new(&f.row);
+    let mut col = DeltaRleDecoder::new(&f.col);
+    let mut lamport = DeltaRleDecoder::new(&f.lamport);
+    let mut peer_idx = DeltaRleDecoder::new(&f.peer_idx);
+    let mut values = values.iter().enumerate();
+    loop {
+        let (t, r, c, p, l) = match (
+            table.try_next()?,
+            row.try_next()?,
+            col.try_next()?,
+            peer_idx.try_next()?,
+            lamport.try_next()?,
+        ) {
+            (Some(t), Some(r), Some(c), Some(p), Some(l)) => (t, r, c, p, l),
+            (None, None, None, None, None) => break,
+            _ => return Err(LwwError::ColumnLengthMismatch),
+        };
+
+        let (index, value) = values.next().ok_or(LwwError::ColumnLengthMismatch)?;
+        let table = get_str(t)?;
+        let row = if r == 0 { None } else { Some(get_str(r - 1)?) };
+        let col = if c == 0 { None } else { Some(get_str(c - 1)?) };
+        let peer = usize::try_from(p)
+            .ok()
+            .and_then(|p| f.peers.get(p).copied())
+            .ok_or(LwwError::PeerIndexOutOfRange {
+                index: p,
+                len: f.peers.len(),
+            })?;
+        if l < 0 || l > Lamport::MAX as i64 {
+            return Err(LwwError::InvalidOp("lamport out of range"));
+        }
+
+        if l == 0 && (peer != 0 || *value != Value::Null) {
+            return Err(LwwError::InvalidOp("only empty cells can have lamport 0"));
+        }
+
+        match (row, col, value) {
+            (None, Some(_), _) => return Err(LwwError::InvalidOp("column without row")),
+            (None, None, v) | (Some(_), None, v) if *v != Value::Deleted => {
+                return Err(LwwError::InvalidOp(
+                    "only deletion can target a row or a table",
+                ))
+            }
+            _ => {}
+        }
+
+        let context = contexts.get(&index);
+        if context.is_some() && col.is_none() {
+            return Err(LwwError::InvalidOp("only cells can have a context"));
+        }
+
+        on_op(DecodedOp {
+            id: OpId {
+                peer,
+                lamport: l as Lamport,
+            },
+            table,
+            row,
+            col,
+            value,
+            context,
+        });
+    }
+
+    if values.next().is_some() {
+        return Err(LwwError::ColumnLengthMismatch);
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test_encode_from {
     use super::*;
+    use crate::IndexKind;
 
     #[test]
     fn test_basic() {
@@ -322,6 +1236,260 @@ mod test_encode_from {
         assert!(db.check_eq(&mut c_db));
     }
 
+    #[test]
+    fn test_counter() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        a.increment("posts", "1", "likes", 1);
+        a.increment("posts", "2", "likes", 1);
+        b.increment("posts", "1", "likes", 1);
+        b.increment("posts", "1", "likes", 2);
+        b.delete_row("posts", "2");
+        a.import_updates(&b.export_updates(Default::default()));
+        b.import_updates(&a.export_updates(Default::default()));
+        assert!(a.check_eq(&mut b));
+        assert_eq!(a.get_cell("posts", "1", "likes"), Some(&Value::I64(4)));
+        assert_eq!(a.get_cell("posts", "2", "likes"), Some(&Value::Null));
+
+        let version = a.version().clone();
+        a.increment("posts", "1", "likes", -1);
+        b.import_updates(&a.export_updates(version));
+        assert_eq!(b.get_cell("posts", "1", "likes"), Some(&Value::I64(3)));
+
+        let mut c = LwwDb::from_snapshot(&b.export_snapshot());
+        assert!(b.check_eq(&mut c));
+        c.set_peer(3);
+        c.increment("posts", "1", "likes", 1);
+        a.import_updates(&c.export_updates(Default::default()));
+        assert_eq!(a.get_cell("posts", "1", "likes"), Some(&Value::I64(4)));
+    }
+
+    #[test]
+    fn set_then_increment() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        a.increment("posts", "1", "likes", 5);
+        let increment = a.export_updates(Default::default());
+        // c receives the increment of a before the set of b
+        let mut c = LwwDb::new();
+        c.set_peer(3);
+        c.import_updates(&a.export_updates(Default::default()));
+        b.import_updates(&a.export_updates(Default::default()));
+        b.set("posts", "1", "likes", 100);
+        let after_set = b.version().clone();
+        b.increment("posts", "1", "likes", 1);
+        assert_eq!(b.get_cell("posts", "1", "likes"), Some(&Value::I64(1)));
+
+        // the set is exported even if the sum overwrites it
+        c.import_updates(&b.export_updates(c.version().clone()));
+        a.import_updates(&b.export_updates(a.version().clone()));
+        assert!(a.check_eq(&mut c));
+        assert!(a.check_eq(&mut b));
+        assert_eq!(a.get_cell("posts", "1", "likes"), Some(&Value::I64(1)));
+
+        let mut d = LwwDb::from_snapshot(&b.export_snapshot());
+        assert!(b.check_eq(&mut d));
+        d.import_updates(&a.export_updates(Default::default()));
+        assert!(b.check_eq(&mut d));
+
+        // the set is kept when it arrives after the newer increment
+        let mut e = LwwDb::new();
+        e.import_updates(&increment);
+        e.import_updates(&b.export_updates(after_set));
+        e.import_updates(&b.export_updates(Default::default()));
+        assert!(a.check_eq(&mut e));
+        let mut f = LwwDb::new();
+        f.import_updates(&e.export_updates(Default::default()));
+        assert!(a.check_eq(&mut f));
+    }
+
+    #[test]
+    fn test_multi_value() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.set_multi_value("contracts", "price", true);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        b.set_multi_value("contracts", "price", true);
+        a.set("contracts", "1", "price", 100);
+        b.import_updates(&a.export_updates(Default::default()));
+        a.set("contracts", "1", "price", 110);
+        b.set("contracts", "1", "price", 120);
+        a.import_updates(&b.export_updates(Default::default()));
+        b.import_updates(&a.export_updates(Default::default()));
+        assert!(a.check_eq(&mut b));
+        assert_eq!(
+            a.get_cell_conflicts("contracts", "1", "price"),
+            vec![&Value::I64(110), &Value::I64(120)]
+        );
+
+        let mut c = LwwDb::from_snapshot(&a.export_snapshot());
+        assert!(a.check_eq(&mut c));
+
+        // picking one of the values resolves the conflict
+        let version = b.version().clone();
+        b.set("contracts", "1", "price", 110);
+        a.import_updates(&b.export_updates(version));
+        assert_eq!(
+            a.get_cell_conflicts("contracts", "1", "price"),
+            vec![&Value::I64(110)]
+        );
+    }
+
+    #[test]
+    fn sync_superseded_writes_without_context() {
+        let mut x = LwwDb::new();
+        x.set_peer(1);
+        for i in 0..4 {
+            x.set("contracts", &i.to_string(), "price", i);
+        }
+        x.set("contracts", "a", "price", "x");
+        let mut y = LwwDb::new();
+        y.set_peer(2);
+        y.set("contracts", "a", "price", "y");
+        let mut z = LwwDb::new();
+        z.set_peer(3);
+        z.set_multi_value("contracts", "price", true);
+        z.import_updates(&x.export_updates(Default::default()));
+        z.set("contracts", "a", "price", "z");
+
+        // the write of y is older than the write of x that z has seen
+        let mut a = LwwDb::new();
+        a.import_updates(&x.export_updates(Default::default()));
+        a.import_updates(&z.export_updates(Default::default()));
+        let mut relayed = LwwDb::new();
+        relayed.import_updates(&a.export_updates(Default::default()));
+        let mut restored = LwwDb::from_snapshot(&a.export_snapshot());
+        let mut b = LwwDb::new();
+        b.import_updates(&y.export_updates(Default::default()));
+        b.import_updates(&x.export_updates(Default::default()));
+        b.import_updates(&z.export_updates(Default::default()));
+        for db in [&mut a, &mut relayed, &mut restored] {
+            db.import_updates(&y.export_updates(Default::default()));
+            assert_eq!(
+                db.get_cell_conflicts("contracts", "a", "price"),
+                vec![&Value::from("z")]
+            );
+            assert!(db.check_eq(&mut b));
+        }
+    }
+
+    #[test]
+    fn reject_partly_forged_transaction() {
+        let key = crate::PeerKey::generate();
+        let mut a = LwwDb::new();
+        a.set_signing_key(key.clone());
+        a.set("table", "c", "x", 0);
+        a.transaction(|txn| {
+            txn.set("table", "a", "x", 1);
+            txn.set("table", "b", "x", 2);
+        });
+
+        // the relay replaces the signature of an op of the transaction
+        let data = a.export_updates(Default::default());
+        let (_, bytes) = envelope::open(&data, PayloadKind::Updates).unwrap();
+        let (f, rest) = postcard::take_from_bytes::<Final>(&bytes).unwrap();
+        let mut signatures: Vec<EncodedSignature> = postcard::from_bytes(rest).unwrap();
+        let other = signatures
+            .iter()
+            .find(|s| s.lamport == 1)
+            .unwrap()
+            .signature;
+        signatures
+            .iter_mut()
+            .find(|s| s.lamport == 3)
+            .unwrap()
+            .signature = other;
+        let mut tampered = postcard::to_allocvec(&f).unwrap();
+        tampered.extend(postcard::to_allocvec(&signatures).unwrap());
+        let tampered = envelope::seal(PayloadKind::Updates, UPDATES_VERSION, &tampered, true);
+
+        let mut b = LwwDb::new();
+        b.trust_peer(key.public_key());
+        let status = b.try_import_updates(&tampered).unwrap();
+        let peer = key.peer();
+        assert_eq!(status.forged, [OpId::new(2, peer), OpId::new(3, peer)]);
+        assert_eq!(status.applied, 1);
+        assert_eq!(b.get_cell("table", "c", "x"), Some(&Value::I64(0)));
+        assert_eq!(b.get_cell("table", "a", "x"), None);
+
+        // the transaction is received again from an honest peer
+        let status = b
+            .try_import_updates(&a.export_updates(b.version().clone()))
+            .unwrap();
+        assert!(status.forged.is_empty());
+        assert_eq!(b.get_cell("table", "b", "x"), Some(&Value::I64(2)));
+        assert!(a.check_eq(&mut b));
+    }
+
+    #[test]
+    fn test_index() {
+        let sorted = |rows: LwwResult<Vec<&SmolStr>>| {
+            let mut rows: Vec<String> = rows.unwrap().into_iter().map(|r| r.to_string()).collect();
+            rows.sort();
+            rows
+        };
+
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.set("people", "a", "age", 30);
+        a.set("people", "b", "age", 20);
+        a.create_index("people", "age", IndexKind::Ordered);
+        a.create_index("people", "name", IndexKind::Hash);
+        a.set("people", "a", "name", "Alice");
+        a.set("people", "c", "age", 25.5);
+        a.set("people", "c", "name", "Alice");
+        assert_eq!(
+            sorted(a.find_rows("people", "name", &"Alice".into())),
+            ["a", "c"]
+        );
+        let in_range: Vec<_> = a
+            .find_rows_in_range("people", "age", Value::from(21)..)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.as_str())
+            .collect();
+        assert_eq!(in_range, ["c", "a"]);
+        assert!(matches!(
+            a.find_rows_in_range("people", "name", ..),
+            Err(LwwError::IndexUnavailable)
+        ));
+        assert!(matches!(
+            a.find_rows("people", "email", &"x".into()),
+            Err(LwwError::IndexUnavailable)
+        ));
+
+        // the index of the new peer is updated by the imported ops
+        let mut b = LwwDb::from_snapshot(&a.export_snapshot());
+        b.set_peer(2);
+        assert_eq!(
+            sorted(b.find_rows("people", "name", &"Alice".into())),
+            ["a", "c"]
+        );
+        a.set("people", "a", "name", "Bob");
+        a.delete_row("people", "c");
+        b.import_updates(&a.export_updates(b.version().clone()));
+        assert_eq!(
+            sorted(b.find_rows("people", "name", &"Alice".into())),
+            Vec::<String>::new()
+        );
+        assert_eq!(sorted(b.find_rows("people", "name", &"Bob".into())), ["a"]);
+        assert_eq!(
+            sorted(b.find_rows_in_range("people", "age", ..)),
+            ["a", "b"]
+        );
+
+        b.delete_table("people");
+        b.set("people", "d", "age", 1);
+        assert_eq!(sorted(b.find_rows_in_range("people", "age", ..)), ["d"]);
+        assert!(b.drop_index("people", "age"));
+        assert!(b.find_rows_in_range("people", "age", ..).is_err());
+    }
+
     #[test]
     fn test_snapshot_basic() {
         let mut db = LwwDb::new();
@@ -340,4 +1508,219 @@ mod test_encode_from {
         c_db.import_updates(&new_db.export_updates(Default::default()));
         assert!(db.check_eq(&mut c_db));
     }
+
+    #[test]
+    fn test_snapshot_deleted_row() {
+        let mut db = LwwDb::new();
+        db.set("table", "a", "b", "value");
+        db.set("table", "b", "b", "value");
+        db.delete_row("table", "b");
+        let mut new_db = LwwDb::from_snapshot(&db.export_snapshot());
+        assert!(db.check_eq(&mut new_db));
+        let table = &new_db.tables["table"];
+        assert!(table.rows[table.row_id_to_idx["b"]].deleted.is_some());
+        assert!(table.rows[table.row_id_to_idx["a"]].deleted.is_none());
+    }
+
+    #[test]
+    fn test_import_version_1() {
+        let mut db = LwwDb::new();
+        db.set("table", "a", "b", "value");
+        db.delete_row("table", "c");
+        let data = db.export_updates(Default::default());
+        let (_, bytes) = envelope::open(&data, PayloadKind::Updates).unwrap();
+        let (f, _) = postcard::take_from_bytes::<Final>(&bytes).unwrap();
+        let v1 = envelope::seal(
+            PayloadKind::Updates,
+            1,
+            &postcard::to_allocvec(&f).unwrap(),
+            true,
+        );
+        let mut new_db = LwwDb::new();
+        new_db.import_updates(&v1);
+        assert!(db.check_eq(&mut new_db));
+    }
+
+    #[test]
+    fn test_import_invalid() {
+        let mut db = LwwDb::new();
+        db.set("table", "a", "b", "value");
+        db.set("table", "a", "c", 1);
+        db.delete_row("table", "c");
+        let data = db.export_updates(Default::default());
+        let mut new_db = LwwDb::new();
+        assert!(matches!(
+            new_db.try_import_updates(&data[..data.len() / 2]),
+            Err(LwwError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            new_db.try_import_updates(&db.export_snapshot()),
+            Err(LwwError::UnexpectedPayloadKind { .. })
+        ));
+
+        // out of range string index, in the layout of version 1 that has no
+        // signatures
+        let (_, bytes) = envelope::open(&data, PayloadKind::Updates).unwrap();
+        let mut f = postcard::from_bytes::<Final>(&bytes).unwrap();
+        f.str.pop();
+        let corrupted = postcard::to_allocvec(&f).unwrap();
+        let corrupted = envelope::seal(PayloadKind::Updates, 1, &corrupted, true);
+        assert!(matches!(
+            new_db.try_import_updates(&corrupted),
+            Err(LwwError::StrIndexOutOfRange { .. })
+        ));
+
+        // missing values
+        let mut f = postcard::from_bytes::<Final>(&bytes).unwrap();
+        let mut values = postcard::from_bytes::<Vec<Value>>(&f.value).unwrap();
+        values.pop();
+        f.value = Cow::Owned(postcard::to_allocvec(&values).unwrap());
+        let corrupted = postcard::to_allocvec(&f).unwrap();
+        let corrupted = envelope::seal(PayloadKind::Updates, 1, &corrupted, true);
+        assert!(matches!(
+            new_db.try_import_updates(&corrupted),
+            Err(LwwError::ColumnLengthMismatch)
+        ));
+
+        // nothing is applied
+        assert_eq!(new_db.iter_tables().count(), 0);
+        assert!(new_db.version().is_empty());
+        new_db.try_import_updates(&data).unwrap();
+        assert!(db.check_eq(&mut new_db));
+    }
+
+    #[test]
+    fn test_snapshot_invalid() {
+        let mut db = LwwDb::new();
+        db.set("table", "a", "b", "value");
+        let data = db.export_snapshot();
+        assert!(LwwDb::try_from_snapshot(&data[..data.len() - 1]).is_err());
+        let (_, body) = envelope::open(&data, PayloadKind::Snapshot).unwrap();
+        let mut encoded: EncodedSnapshot = postcard::from_bytes(&body).unwrap();
+        encoded.peers.clear();
+        let corrupted = postcard::to_allocvec(&encoded).unwrap();
+        let corrupted = envelope::seal(PayloadKind::Snapshot, 1, &corrupted, false);
+        assert!(matches!(
+            LwwDb::try_from_snapshot(&corrupted),
+            Err(LwwError::PeerIndexOutOfRange { .. })
+        ));
+
+        // a payload of a newer format
+        let newer = envelope::seal(PayloadKind::Snapshot, SNAPSHOT_VERSION + 1, &body, false);
+        assert!(matches!(
+            LwwDb::try_from_snapshot(&newer),
+            Err(LwwError::UnsupportedVersion(_))
+        ));
+        assert_eq!(
+            LwwDb::inspect_payload(&data).unwrap(),
+            PayloadHeader {
+                kind: PayloadKind::Snapshot,
+                version: SNAPSHOT_VERSION,
+                flags: 0
+            }
+        );
+        assert!(VectorClock::try_decode(&[0xff]).is_err());
+    }
+
+    /// The payloads of the ops in [read_legacy_payloads], exported before the
+    /// header was added
+    const LEGACY_UPDATES: &[u8] = &[
+        40, 181, 47, 253, 0, 88, 37, 2, 0, 162, 5, 16, 22, 128, 169, 233, 67, 188, 204, 104, 247,
+        240, 0, 3, 51, 48, 3, 29, 45, 116, 118, 55, 43, 147, 12, 151, 39, 238, 227, 227, 105, 254,
+        236, 25, 110, 175, 50, 179, 251, 244, 143, 217, 227, 99, 222, 209, 61, 192, 2, 221, 57,
+        128, 151, 140, 248, 112, 193, 8, 14, 161, 202, 102, 80, 200, 41, 11, 0,
+    ];
+    const LEGACY_SNAPSHOT: &[u8] = &[
+        1, 1, 2, 5, 111, 116, 104, 101, 114, 21, 40, 181, 47, 253, 0, 88, 97, 0, 0, 1, 0, 6, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 5, 116, 97, 98, 108, 101, 45, 40, 181, 47, 253, 0, 88, 33, 1, 0, 0, 2,
+        1, 97, 1, 98, 2, 1, 120, 1, 121, 5, 0, 1, 1, 1, 1, 2, 1, 2, 2, 1, 115, 2, 2, 1, 2, 2, 0, 2,
+        1, 1, 1, 0, 1, 4,
+    ];
+
+    #[test]
+    fn read_legacy_payloads() {
+        assert_eq!(
+            LwwDb::inspect_payload(LEGACY_UPDATES).unwrap().version,
+            envelope::LEGACY_VERSION
+        );
+        let mut a = LwwDb::new();
+        a.try_import_updates(LEGACY_UPDATES).unwrap();
+        let mut b = LwwDb::try_from_snapshot(LEGACY_SNAPSHOT).unwrap();
+        let mut expected = LwwDb::new();
+        expected.set_peer(1);
+        expected.set("table", "a", "x", 1);
+        expected.set("table", "a", "y", "s");
+        expected.set("table", "b", "x", 2.5);
+        expected.delete_row("table", "b");
+        expected.set("other", "a", "x", 1);
+        expected.delete_table("other");
+        assert!(a.check_eq(&mut expected));
+        assert!(b.check_eq(&mut expected));
+        assert_eq!(a.version(), expected.version());
+        assert_eq!(b.version(), expected.version());
+    }
+
+    #[test]
+    fn test_sparse_snapshot() {
+        let mut db = LwwDb::new();
+        for i in 0..1000 {
+            db.set("table", &i.to_string(), &(i % 500).to_string(), i);
+        }
+
+        let data = db.export_snapshot();
+        let mut new_db = LwwDb::from_snapshot(&data);
+        assert!(new_db.tables["table"].cols.values().all(|c| c.is_sparse()));
+        assert!(db.check_eq(&mut new_db));
+        assert_eq!(new_db.get_cell("table", "501", "1"), Some(&Value::I64(501)));
+        assert_eq!(new_db.get_cell("table", "501", "2"), Some(&Value::Null));
+    }
+
+    #[test]
+    fn test_shallow_snapshot() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        for i in 0..10 {
+            a.set("table", &i.to_string(), "x", i);
+        }
+        a.set("table", "1", "x", "overwritten");
+        a.delete_row("table", "2");
+        a.increment("table", "3", "n", 2);
+
+        let mut b = LwwDb::from_snapshot(&a.export_shallow_snapshot());
+        b.set_peer(2);
+        assert!(a.check_eq(&mut b));
+        assert_eq!(b.version(), a.version());
+        assert_eq!(b.shallow_frontier(), Some(a.version()));
+        assert_eq!(b.oplog.iter_from(&Default::default()).count(), 0);
+        assert!(a.shallow_frontier().is_none());
+        assert!(matches!(
+            b.try_export_updates(Default::default()),
+            Err(LwwError::NeedFullSnapshot)
+        ));
+
+        // it serves the peers at or above the frontier
+        let mut c = LwwDb::from_snapshot(&a.export_snapshot());
+        c.set_peer(3);
+        b.set("table", "1", "x", "b");
+        c.delete_row("table", "4");
+        b.import_updates(&c.export_updates(b.version().clone()));
+        c.import_updates(&b.export_updates(c.version().clone()));
+        a.import_updates(&b.export_updates(a.version().clone()));
+        assert!(a.check_eq(&mut b));
+        assert!(a.check_eq(&mut c));
+        assert_eq!(b.get_cell("table", "1", "x"), Some(&Value::from("b")));
+
+        let mut shallow = LwwDb::from_snapshot(&b.export_shallow_snapshot());
+        assert!(b.check_eq(&mut shallow));
+        assert_eq!(
+            LwwDb::inspect_payload(&b.export_shallow_snapshot())
+                .unwrap()
+                .kind,
+            PayloadKind::ShallowSnapshot
+        );
+        assert!(matches!(
+            LwwDb::try_from_snapshot(&b.export_updates(a.version().clone())),
+            Err(LwwError::UnexpectedPayloadKind { .. })
+        ));
+    }
 }
@@ -1,3 +1,5 @@
+use crate::error::{LwwError, LwwResult};
+
 pub(super) struct BoolRleEncoder {
     buffer: Vec<u8>,
     last_value: bool,
@@ -23,6 +25,21 @@ impl BoolRleEncoder {
         }
     }
 
+    /// Push `value` `n` times
+    pub fn push_n(&mut self, value: bool, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        if value == self.last_value {
+            self.repeat += n;
+        } else {
+            self.flush();
+            self.last_value = value;
+            self.repeat = n;
+        }
+    }
+
     fn flush(&mut self) {
         leb128::write::unsigned(&mut self.buffer, self.repeat as u64).unwrap();
         self.repeat = 0;
@@ -53,22 +70,50 @@ impl<'a> BoolRleDecoder<'a> {
     }
 }
 
-impl<'a> Iterator for BoolRleDecoder<'a> {
-    type Item = bool;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a> BoolRleDecoder<'a> {
+    /// Like [Iterator::next], but returns an error on malformed input instead of panicking
+    pub fn try_next(&mut self) -> LwwResult<Option<bool>> {
         while self.repeat == 0 {
             if self.buffer.is_empty() {
-                return None;
+                return Ok(None);
             }
 
-            let repeat = leb128::read::unsigned(&mut self.buffer).unwrap();
+            let repeat =
+                leb128::read::unsigned(&mut self.buffer).map_err(|_| LwwError::MalformedColumn)?;
             self.repeat = repeat as usize;
             self.value = !self.value;
         }
 
         self.repeat -= 1;
-        Some(self.value)
+        Ok(Some(self.value))
+    }
+
+    /// Take the next run of the same value, the run is at most `max` long.
+    /// `max` should be greater than 0.
+    pub fn try_next_run(&mut self, max: usize) -> LwwResult<Option<(bool, usize)>> {
+        debug_assert!(max > 0);
+        while self.repeat == 0 {
+            if self.buffer.is_empty() {
+                return Ok(None);
+            }
+
+            let repeat =
+                leb128::read::unsigned(&mut self.buffer).map_err(|_| LwwError::MalformedColumn)?;
+            self.repeat = repeat as usize;
+            self.value = !self.value;
+        }
+
+        let n = self.repeat.min(max);
+        self.repeat -= n;
+        Ok(Some((self.value, n)))
+    }
+}
+
+impl<'a> Iterator for BoolRleDecoder<'a> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().unwrap()
     }
 }
 
@@ -132,6 +177,21 @@ mod tests {
         assert_eq!(decoded, ans);
     }
 
+    #[test]
+    fn bool_rle_runs() {
+        let mut encoder = BoolRleEncoder::new();
+        encoder.push_n(true, 3);
+        encoder.push_n(false, 0);
+        encoder.push(true);
+        encoder.push_n(false, 5);
+        let encoded = encoder.finish();
+        let mut decoder = BoolRleDecoder::new(&encoded);
+        assert_eq!(decoder.try_next_run(2).unwrap(), Some((true, 2)));
+        assert_eq!(decoder.try_next_run(10).unwrap(), Some((true, 2)));
+        assert_eq!(decoder.try_next_run(10).unwrap(), Some((false, 5)));
+        assert_eq!(decoder.try_next_run(10).unwrap(), None);
+    }
+
     #[test]
     fn bool_rle_2() {
         let mut encoder = BoolRleEncoder::new();
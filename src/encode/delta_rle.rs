@@ -1,3 +1,5 @@
+use crate::error::{LwwError, LwwResult};
+
 pub(super) struct DeltaRleEncoder {
     buffer: Vec<u8>,
     last_value: i64,
@@ -59,21 +61,33 @@ impl<'a> DeltaRleDecoder<'a> {
     }
 }
 
-impl<'a> Iterator for DeltaRleDecoder<'a> {
-    type Item = i64;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a> DeltaRleDecoder<'a> {
+    /// Like [Iterator::next], but returns an error on malformed input instead of panicking
+    pub(crate) fn try_next(&mut self) -> LwwResult<Option<i64>> {
         if self.repeat == 0 {
             if self.buffer.is_empty() {
-                return None;
+                return Ok(None);
             }
 
-            self.repeat = leb128::read::signed(&mut self.buffer).unwrap();
-            self.last_delta = leb128::read::signed(&mut self.buffer).unwrap();
+            self.repeat =
+                leb128::read::signed(&mut self.buffer).map_err(|_| LwwError::MalformedColumn)?;
+            self.last_delta =
+                leb128::read::signed(&mut self.buffer).map_err(|_| LwwError::MalformedColumn)?;
+            if self.repeat <= 0 {
+                return Err(LwwError::MalformedColumn);
+            }
         }
         self.repeat -= 1;
-        self.value += self.last_delta;
-        Some(self.value)
+        self.value = self.value.wrapping_add(self.last_delta);
+        Ok(Some(self.value))
+    }
+}
+
+impl<'a> Iterator for DeltaRleDecoder<'a> {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().unwrap()
     }
 }
 
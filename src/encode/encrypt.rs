@@ -0,0 +1,276 @@
+use std::borrow::Cow;
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::VectorClock,
+    error::{LwwError, LwwResult},
+    import::ImportStatus,
+    LwwDb,
+};
+
+use super::envelope::{self, PayloadKind};
+
+/// The version of the layout of [EncryptedBody]
+const ENCRYPTED_VERSION: u16 = 1;
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit key of XChaCha20-Poly1305 to encrypt the payloads, see
+/// [LwwDb::export_updates_encrypted]
+#[derive(Clone)]
+pub struct PayloadKey([u8; 32]);
+
+impl PayloadKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// A random key
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        getrandom::getrandom(&mut bytes).unwrap();
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for PayloadKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PayloadKey(..)")
+    }
+}
+
+/// The metadata of an encrypted payload. It's not encrypted, so a relay can
+/// route and store the payloads without the key, but it's authenticated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayloadMeta {
+    /// The kind of the encrypted payload
+    pub kind: PayloadKind,
+    /// The ops in the payload are not included in `from`
+    pub from: VectorClock,
+    /// The version of the exporter, which includes all the ops in the payload
+    pub to: VectorClock,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedBody<'a> {
+    /// The encoded [PayloadMeta], which is the associated data
+    #[serde(borrow)]
+    meta: Cow<'a, [u8]>,
+    nonce: [u8; NONCE_LEN],
+    /// The encrypted payload, with its own header
+    #[serde(borrow)]
+    ciphertext: Cow<'a, [u8]>,
+}
+
+fn encrypt(payload: &[u8], meta: &PayloadMeta, key: &PayloadKey) -> Vec<u8> {
+    let meta = postcard::to_allocvec(meta).unwrap();
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).unwrap();
+    let cipher = XChaCha20Poly1305::new(key.0.as_ref().into());
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: payload,
+                aad: &meta,
+            },
+        )
+        .unwrap();
+    let body = EncryptedBody {
+        meta: Cow::Owned(meta),
+        nonce,
+        ciphertext: Cow::Owned(ciphertext),
+    };
+    let body = postcard::to_allocvec(&body).unwrap();
+    envelope::seal(PayloadKind::Encrypted, ENCRYPTED_VERSION, &body, false)
+}
+
+fn open_body(bytes: &[u8]) -> LwwResult<EncryptedBody<'_>> {
+    let (version, body) = envelope::open(bytes, PayloadKind::Encrypted)?;
+    // the encrypted payloads are never compressed by the envelope
+    let Cow::Borrowed(body) = body else {
+        return Err(LwwError::InvalidHeader);
+    };
+
+    match version {
+        1 => Ok(postcard::from_bytes(body)?),
+        v => Err(LwwError::UnsupportedVersion(v)),
+    }
+}
+
+/// Decrypt the payload and check it's of the `kind`
+fn decrypt(bytes: &[u8], kind: &[PayloadKind], key: &PayloadKey) -> LwwResult<Vec<u8>> {
+    let body = open_body(bytes)?;
+    let meta: PayloadMeta = postcard::from_bytes(&body.meta)?;
+    if !kind.contains(&meta.kind) {
+        return Err(LwwError::UnexpectedPayloadKind {
+            expected: kind[0],
+            found: meta.kind,
+        });
+    }
+
+    let cipher = XChaCha20Poly1305::new(key.0.as_ref().into());
+    cipher
+        .decrypt(
+            XNonce::from_slice(&body.nonce),
+            Payload {
+                msg: &body.ciphertext,
+                aad: &body.meta,
+            },
+        )
+        .map_err(|_| LwwError::DecryptionFailed)
+}
+
+impl LwwDb {
+    /// Export the updates since `from` like [LwwDb::export_updates], and
+    /// encrypt them with the key. Only `from`, the current version and the
+    /// kind of the payload are readable without the key, see
+    /// [LwwDb::inspect_encrypted_payload].
+    pub fn export_updates_encrypted(&self, from: VectorClock, key: &PayloadKey) -> Vec<u8> {
+        let payload = self.export_updates(from.clone());
+        let meta = PayloadMeta {
+            kind: PayloadKind::Updates,
+            from,
+            to: self.version().clone(),
+        };
+        encrypt(&payload, &meta, key)
+    }
+
+    /// Export the snapshot like [LwwDb::export_snapshot], and encrypt it with
+    /// the key
+    pub fn export_snapshot_encrypted(&self, key: &PayloadKey) -> Vec<u8> {
+        let meta = PayloadMeta {
+            kind: PayloadKind::Snapshot,
+            from: VectorClock::new(),
+            to: self.version().clone(),
+        };
+        encrypt(&self.export_snapshot(), &meta, key)
+    }
+
+    /// Decrypt the updates exported by [LwwDb::export_updates_encrypted] and
+    /// import them like [LwwDb::try_import_updates].
+    ///
+    /// It fails with [LwwError::DecryptionFailed] if the key is wrong or the
+    /// payload or its metadata are tampered with.
+    pub fn try_import_updates_encrypted(
+        &mut self,
+        bytes: &[u8],
+        key: &PayloadKey,
+    ) -> LwwResult<ImportStatus> {
+        let payload = decrypt(bytes, &[PayloadKind::Updates], key)?;
+        self.try_import_updates(&payload)
+    }
+
+    /// Decrypt the snapshot exported by [LwwDb::export_snapshot_encrypted] and
+    /// create a db from it like [LwwDb::try_from_snapshot]
+    pub fn try_from_snapshot_encrypted(bytes: &[u8], key: &PayloadKey) -> LwwResult<Self> {
+        let kinds = [PayloadKind::Snapshot, PayloadKind::ShallowSnapshot];
+        let payload = decrypt(bytes, &kinds, key)?;
+        Self::try_from_snapshot(&payload)
+    }
+
+    /// Read the metadata of an encrypted payload without the key. It's only
+    /// authenticated when the payload is decrypted.
+    pub fn inspect_encrypted_payload(bytes: &[u8]) -> LwwResult<PayloadMeta> {
+        let body = open_body(bytes)?;
+        Ok(postcard::from_bytes(&body.meta)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{clock::OpId, value::Value};
+
+    use super::*;
+
+    #[test]
+    fn encrypt_and_authenticate() {
+        let key = PayloadKey::generate();
+        let mut db = LwwDb::new();
+        db.set("table", "a", "x", "secret");
+        let from = db.version().clone();
+        db.set("table", "b", "x", 1);
+        let data = db.export_updates_encrypted(from.clone(), &key);
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+        let meta = LwwDb::inspect_encrypted_payload(&data).unwrap();
+        assert_eq!(meta.kind, PayloadKind::Updates);
+        assert_eq!(&meta.from, &from);
+        assert_eq!(&meta.to, db.version());
+
+        let mut new_db = LwwDb::new();
+        new_db.try_import_updates_encrypted(&data, &key).unwrap();
+        assert_eq!(new_db.get_cell("table", "b", "x"), Some(&Value::I64(1)));
+        assert_eq!(new_db.get_cell("table", "a", "x"), None);
+        assert!(matches!(
+            new_db.try_import_updates_encrypted(&data, &PayloadKey::new([7; 32])),
+            Err(LwwError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            new_db.try_import_updates(&data),
+            Err(LwwError::UnexpectedPayloadKind { .. })
+        ));
+
+        let snapshot = db.export_snapshot_encrypted(&key);
+        let mut restored = LwwDb::try_from_snapshot_encrypted(&snapshot, &key).unwrap();
+        assert!(db.check_eq(&mut restored));
+        assert!(matches!(
+            LwwDb::try_from_snapshot_encrypted(&data, &key),
+            Err(LwwError::UnexpectedPayloadKind { .. })
+        ));
+    }
+
+    /// Change the body of the encrypted payload, and seal it again with a
+    /// valid checksum, so only the authentication can catch it
+    fn tamper(data: &[u8], f: impl FnOnce(&mut Vec<u8>, &mut Vec<u8>)) -> Vec<u8> {
+        let body = open_body(data).unwrap();
+        let mut meta = body.meta.to_vec();
+        let mut ciphertext = body.ciphertext.to_vec();
+        f(&mut meta, &mut ciphertext);
+        let body = EncryptedBody {
+            meta: Cow::Owned(meta),
+            nonce: body.nonce,
+            ciphertext: Cow::Owned(ciphertext),
+        };
+        let body = postcard::to_allocvec(&body).unwrap();
+        envelope::seal(PayloadKind::Encrypted, ENCRYPTED_VERSION, &body, false)
+    }
+
+    #[test]
+    fn reject_tampered_payload() {
+        let key = PayloadKey::generate();
+        let mut db = LwwDb::new();
+        db.set("table", "a", "x", 1);
+        let data = db.export_updates_encrypted(Default::default(), &key);
+        let mut new_db = LwwDb::new();
+
+        let tampered = tamper(&data, |_, ciphertext| ciphertext[0] ^= 1);
+        assert!(matches!(
+            new_db.try_import_updates_encrypted(&tampered, &key),
+            Err(LwwError::DecryptionFailed)
+        ));
+
+        let tampered = tamper(&data, |meta, _| {
+            let mut decoded: PayloadMeta = postcard::from_bytes(meta).unwrap();
+            decoded.from.extend_to_include(OpId::new(1, 1));
+            *meta = postcard::to_allocvec(&decoded).unwrap();
+        });
+        let meta = LwwDb::inspect_encrypted_payload(&tampered).unwrap();
+        assert_eq!(meta.from.get(&1), Some(&1));
+        assert!(matches!(
+            new_db.try_import_updates_encrypted(&tampered, &key),
+            Err(LwwError::DecryptionFailed)
+        ));
+
+        assert!(new_db.version().is_empty());
+        new_db.try_import_updates_encrypted(&data, &key).unwrap();
+        assert!(db.check_eq(&mut new_db));
+    }
+}
@@ -0,0 +1,235 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LwwError, LwwResult};
+
+const MAGIC: &[u8; 4] = b"LWWT";
+/// The header of all the exported payloads:
+///
+/// ```text
+/// | magic "LWWT" | kind u8 | version u16 LE | flags u8 | crc32 u32 LE | body |
+/// ```
+///
+/// The checksum covers the kind, the version, the flags and the body.
+///
+/// The payloads exported before the header was added have none. They are
+/// read as [LEGACY_VERSION], the updates are compressed by zstd and the
+/// snapshots are not.
+const HEADER_LEN: usize = 12;
+pub(super) const LEGACY_VERSION: u16 = 0;
+const ZSTD_MAGIC: &[u8; 4] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// The body is compressed by zstd
+const FLAG_COMPRESSED: u8 = 1;
+const KNOWN_FLAGS: u8 = FLAG_COMPRESSED;
+
+/// The kind of an exported payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadKind {
+    /// Exported by [crate::LwwDb::export_updates]
+    Updates,
+    /// Exported by [crate::LwwDb::export_snapshot]
+    Snapshot,
+    /// Exported by [crate::LwwDb::export_shallow_snapshot]
+    ShallowSnapshot,
+    /// Exported by [crate::LwwDb::export_updates_encrypted] or
+    /// [crate::LwwDb::export_snapshot_encrypted]
+    Encrypted,
+}
+
+impl PayloadKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            PayloadKind::Updates => 0,
+            PayloadKind::Snapshot => 1,
+            PayloadKind::ShallowSnapshot => 2,
+            PayloadKind::Encrypted => 3,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(PayloadKind::Updates),
+            1 => Some(PayloadKind::Snapshot),
+            2 => Some(PayloadKind::ShallowSnapshot),
+            3 => Some(PayloadKind::Encrypted),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadKind::Updates => write!(f, "updates"),
+            PayloadKind::Snapshot => write!(f, "snapshot"),
+            PayloadKind::ShallowSnapshot => write!(f, "shallow snapshot"),
+            PayloadKind::Encrypted => write!(f, "encrypted"),
+        }
+    }
+}
+
+/// The header of an exported payload, see [crate::LwwDb::inspect_payload]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadHeader {
+    pub kind: PayloadKind,
+    pub version: u16,
+    pub flags: u8,
+}
+
+/// Wrap the body with the header. The body is compressed if `compress` is true.
+pub(super) fn seal(kind: PayloadKind, version: u16, body: &[u8], compress: bool) -> Vec<u8> {
+    let (flags, body) = if compress {
+        let compressed = zstd::encode_all(body, 0).unwrap();
+        (FLAG_COMPRESSED, Cow::Owned(compressed))
+    } else {
+        (0, Cow::Borrowed(body))
+    };
+
+    let mut ans = Vec::with_capacity(HEADER_LEN + body.len());
+    ans.extend_from_slice(MAGIC);
+    ans.push(kind.to_byte());
+    ans.extend_from_slice(&version.to_le_bytes());
+    ans.push(flags);
+    let crc = checksum(&ans[4..8], &body);
+    ans.extend_from_slice(&crc.to_le_bytes());
+    ans.extend_from_slice(&body);
+    ans
+}
+
+/// Check the header and the checksum of the payload
+pub(super) fn parse_header(bytes: &[u8]) -> LwwResult<(PayloadHeader, &[u8])> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok((legacy_header(bytes), bytes));
+    }
+
+    if bytes.len() < HEADER_LEN {
+        return Err(LwwError::InvalidHeader);
+    }
+
+    let kind = PayloadKind::from_byte(bytes[4]).ok_or(LwwError::InvalidHeader)?;
+    let version = u16::from_le_bytes([bytes[5], bytes[6]]);
+    let flags = bytes[7];
+    let crc = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    let body = &bytes[HEADER_LEN..];
+    if checksum(&bytes[4..8], body) != crc {
+        return Err(LwwError::ChecksumMismatch);
+    }
+
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(LwwError::UnsupportedFlags(flags));
+    }
+
+    Ok((
+        PayloadHeader {
+            kind,
+            version,
+            flags,
+        },
+        body,
+    ))
+}
+
+/// The header of a payload without one. Only the updates were compressed.
+fn legacy_header(bytes: &[u8]) -> PayloadHeader {
+    if bytes.starts_with(ZSTD_MAGIC) {
+        PayloadHeader {
+            kind: PayloadKind::Updates,
+            version: LEGACY_VERSION,
+            flags: FLAG_COMPRESSED,
+        }
+    } else {
+        PayloadHeader {
+            kind: PayloadKind::Snapshot,
+            version: LEGACY_VERSION,
+            flags: 0,
+        }
+    }
+}
+
+/// Check the payload is of the `kind`, and return its version and its
+/// decompressed body
+pub(super) fn open(bytes: &[u8], kind: PayloadKind) -> LwwResult<(u16, Cow<'_, [u8]>)> {
+    open_one_of(bytes, &[kind]).map(|(header, body)| (header.version, body))
+}
+
+/// Check the payload is of one of the `kinds`, and return its header and its
+/// decompressed body
+pub(super) fn open_one_of<'a>(
+    bytes: &'a [u8],
+    kinds: &[PayloadKind],
+) -> LwwResult<(PayloadHeader, Cow<'a, [u8]>)> {
+    let (header, body) = parse_header(bytes)?;
+    if !kinds.contains(&header.kind) {
+        return Err(LwwError::UnexpectedPayloadKind {
+            expected: kinds[0],
+            found: header.kind,
+        });
+    }
+
+    let body = if header.flags & FLAG_COMPRESSED != 0 {
+        Cow::Owned(zstd::decode_all(body)?)
+    } else {
+        Cow::Borrowed(body)
+    };
+    Ok((header, body))
+}
+
+fn checksum(header: &[u8], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let sealed = seal(PayloadKind::Snapshot, 3, b"hello", true);
+        let (version, body) = open(&sealed, PayloadKind::Snapshot).unwrap();
+        assert_eq!((version, body.as_ref()), (3, b"hello".as_slice()));
+        assert!(matches!(
+            open(&sealed, PayloadKind::Updates),
+            Err(LwwError::UnexpectedPayloadKind { .. })
+        ));
+
+        let mut corrupted = sealed.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(&corrupted, PayloadKind::Snapshot),
+            Err(LwwError::ChecksumMismatch)
+        ));
+        let mut corrupted = sealed.clone();
+        corrupted[5] = 4;
+        assert!(matches!(
+            open(&corrupted, PayloadKind::Snapshot),
+            Err(LwwError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            open(b"LWWT", PayloadKind::Snapshot),
+            Err(LwwError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn open_legacy_payloads() {
+        let (version, body) = open(b"hello", PayloadKind::Snapshot).unwrap();
+        assert_eq!(
+            (version, body.as_ref()),
+            (LEGACY_VERSION, b"hello".as_slice())
+        );
+        let compressed = zstd::encode_all(b"hello".as_slice(), 0).unwrap();
+        let (version, body) = open(&compressed, PayloadKind::Updates).unwrap();
+        assert_eq!(
+            (version, body.as_ref()),
+            (LEGACY_VERSION, b"hello".as_slice())
+        );
+        assert!(matches!(
+            open(&compressed, PayloadKind::Snapshot),
+            Err(LwwError::UnexpectedPayloadKind { .. })
+        ));
+    }
+}
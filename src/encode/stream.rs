@@ -0,0 +1,271 @@
+use std::io::{Read, Write};
+
+use crate::{
+    clock::VectorClock,
+    error::{LwwError, LwwResult},
+    import::ImportStatus,
+    LwwDb,
+};
+
+/// The default number of ops in a chunk of [LwwDb::export_updates_to]
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// The max length of a chunk in bytes, so a corrupted length can't make the
+/// importer allocate too much memory
+const MAX_CHUNK_LEN: usize = 256 * 1024 * 1024;
+
+/// The progress of [LwwDb::export_updates_to] or [LwwDb::import_updates_from]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamProgress {
+    /// The number of chunks written or imported
+    pub chunks: usize,
+    /// The number of bytes written or read
+    pub bytes: u64,
+}
+
+impl LwwDb {
+    /// Write the updates since `from` to the writer in chunks of about
+    /// `chunk_size` ops. Only one chunk is kept in memory at a time. A chunk
+    /// is larger if a transaction doesn't fit in it, and it fails if a chunk
+    /// is over 256 MiB, so use a smaller `chunk_size` for large values.
+    ///
+    /// Every chunk is a payload of [LwwDb::export_updates] prefixed by its
+    /// length, and the stream ends with an empty chunk. `on_progress` is
+    /// called after every chunk.
+    pub fn export_updates_to(
+        &self,
+        from: VectorClock,
+        mut writer: impl Write,
+        chunk_size: usize,
+        mut on_progress: impl FnMut(StreamProgress),
+    ) -> LwwResult<()> {
+        let mut progress = StreamProgress::default();
+        self.encode_updates(&from, chunk_size.max(1), |chunk| {
+            write_chunk(&mut writer, &chunk)?;
+            progress.chunks += 1;
+            progress.bytes += 4 + chunk.len() as u64;
+            on_progress(progress);
+            Ok(())
+        })?;
+        write_chunk(&mut writer, &[])?;
+        writer.flush().map_err(LwwError::Io)
+    }
+
+    /// Import the updates written by [LwwDb::export_updates_to], one chunk at
+    /// a time. `on_progress` is called after every chunk.
+    ///
+    /// Every chunk is validated and applied like [LwwDb::try_import_updates].
+    /// If an error is returned, the chunks before the failed one stay
+    /// applied, and the version only includes the ops before the failed
+    /// chunk, so the import can be resumed by exporting the updates since the
+    /// version.
+    pub fn import_updates_from(
+        &mut self,
+        mut reader: impl Read,
+        mut on_progress: impl FnMut(StreamProgress),
+    ) -> LwwResult<ImportStatus> {
+        let mut status = ImportStatus::default();
+        let mut progress = StreamProgress::default();
+        let mut buf = Vec::new();
+        loop {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len).map_err(LwwError::Io)?;
+            let len = u32::from_le_bytes(len) as usize;
+            if len == 0 {
+                return Ok(status);
+            }
+
+            if len > MAX_CHUNK_LEN {
+                return Err(LwwError::InvalidOp("chunk is too large"));
+            }
+
+            // the buffer grows with the data that is read, not with the length
+            buf.clear();
+            let read = (&mut reader)
+                .take(len as u64)
+                .read_to_end(&mut buf)
+                .map_err(LwwError::Io)?;
+            if read < len {
+                return Err(LwwError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let chunk_status = self.try_import_updates(&buf)?;
+            status.applied += chunk_status.applied;
+            status.rejected.extend(chunk_status.rejected);
+            status.quarantined += chunk_status.quarantined;
+            status.forged.extend(chunk_status.forged);
+            progress.chunks += 1;
+            progress.bytes += 4 + len as u64;
+            on_progress(progress);
+        }
+    }
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> LwwResult<()> {
+    if chunk.len() > MAX_CHUNK_LEN {
+        return Err(LwwError::InvalidOp("chunk is too large"));
+    }
+
+    let len = chunk.len() as u32;
+    writer
+        .write_all(&len.to_le_bytes())
+        .and_then(|_| writer.write_all(chunk))
+        .map_err(LwwError::Io)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{clock::OpId, PeerKey, Transaction, Value};
+
+    use super::*;
+
+    #[test]
+    fn stream_in_chunks() {
+        let mut db = LwwDb::new();
+        for i in 0..100 {
+            db.set("table", &i.to_string(), "x", i);
+            db.increment("table", &i.to_string(), "n", 1);
+        }
+
+        db.delete_row("table", "3");
+        let mut txn = Transaction::new();
+        txn.set("table", "a", "x", 1);
+        txn.set("table", "b", "x", 2);
+        db.commit(txn);
+
+        let mut data = Vec::new();
+        let mut chunks = 0;
+        db.export_updates_to(Default::default(), &mut data, 16, |p| chunks = p.chunks)
+            .unwrap();
+        assert!(chunks > 5);
+
+        let mut new_db = LwwDb::new();
+        let mut read = 0;
+        let status = new_db
+            .import_updates_from(data.as_slice(), |p| read = p.bytes)
+            .unwrap();
+        assert!(status.rejected.is_empty());
+        assert_eq!(read as usize, data.len() - 4);
+        assert!(db.check_eq(&mut new_db));
+        assert_eq!(new_db.get_cell("table", "9", "n"), Some(&Value::I64(1)));
+
+        // a truncated stream is an error
+        let mut new_db = LwwDb::new();
+        assert!(matches!(
+            new_db.import_updates_from(&data[..data.len() - 1], |_| {}),
+            Err(LwwError::Io(_))
+        ));
+
+        // so is a chunk that is too large
+        let mut data = ((MAX_CHUNK_LEN + 1) as u32).to_le_bytes().to_vec();
+        data.extend([0; 16]);
+        assert!(matches!(
+            new_db.import_updates_from(data.as_slice(), |_| {}),
+            Err(LwwError::InvalidOp(_))
+        ));
+    }
+
+    #[test]
+    fn report_forged_ops_of_all_chunks() {
+        let key = PeerKey::generate();
+        let mut a = LwwDb::new();
+        a.set_signing_key(key.clone());
+        for i in 0..10 {
+            a.set("table", &i.to_string(), "x", i);
+        }
+
+        // mallory relays the ops of a with an unsigned op under the id of a
+        let mut mallory = LwwDb::new();
+        mallory.import_updates(&a.export_updates(Default::default()));
+        mallory.set_peer(key.peer());
+        mallory.set("table", "forged", "x", 1);
+        let mut data = Vec::new();
+        mallory
+            .export_updates_to(Default::default(), &mut data, 2, |_| {})
+            .unwrap();
+        assert!(chunks(&data).len() > 5);
+
+        let mut b = LwwDb::new();
+        b.trust_peer(key.public_key());
+        let status = b.import_updates_from(data.as_slice(), |_| {}).unwrap();
+        assert_eq!(status.forged, [OpId::new(11, key.peer())]);
+        assert_eq!(status.applied, 10);
+        assert_eq!(b.get_cell("table", "forged", "x"), None);
+    }
+
+    /// The chunks of the stream
+    fn chunks(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut ans = Vec::new();
+        loop {
+            let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+            if len == 0 {
+                return ans;
+            }
+
+            ans.push(&data[4..4 + len]);
+            data = &data[4 + len..];
+        }
+    }
+
+    #[test]
+    fn resume_interrupted_stream() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        for i in 0..50 {
+            a.set("table", &i.to_string(), "x", i);
+            b.set("table", &i.to_string(), "y", i);
+            b.import_updates(&a.export_updates(b.version().clone()));
+            a.import_updates(&b.export_updates(a.version().clone()));
+        }
+
+        a.delete_row("table", "3");
+        a.set("table", "4", "x", "changed");
+        let mut data = Vec::new();
+        a.export_updates_to(Default::default(), &mut data, 10, |_| {})
+            .unwrap();
+        let chunks = chunks(&data);
+        assert!(chunks.len() > 4);
+
+        // the stream is cut after the second chunk
+        let mut c = LwwDb::new();
+        let cut: usize = chunks[..2].iter().map(|c| 4 + c.len()).sum();
+        assert!(c.import_updates_from(&data[..cut + 2], |_| {}).is_err());
+        for peer in [1, 2] {
+            assert!(c.version().get(&peer) < a.version().get(&peer));
+        }
+
+        c.import_updates(&a.export_updates(c.version().clone()));
+        assert!(a.check_eq(&mut c));
+        assert_eq!(c.version(), a.version());
+    }
+
+    #[test]
+    fn keep_transaction_in_one_chunk() {
+        let mut db = LwwDb::new();
+        for i in 0..10 {
+            db.set("table", &i.to_string(), "x", i);
+        }
+
+        let mut txn = Transaction::new();
+        for i in 0..10 {
+            txn.set("txn", &i.to_string(), "x", i);
+        }
+
+        db.commit(txn);
+        db.set("table", "a", "x", 1);
+        let mut data = Vec::new();
+        db.export_updates_to(Default::default(), &mut data, 3, |_| {})
+            .unwrap();
+        let mut new_db = LwwDb::new();
+        for chunk in chunks(&data) {
+            new_db.import_updates(chunk);
+            let rows = (0..10)
+                .filter(|i| new_db.get_cell("txn", &i.to_string(), "x").is_some())
+                .count();
+            assert!(rows == 0 || rows == 10, "{} rows of the transaction", rows);
+        }
+
+        assert!(db.check_eq(&mut new_db));
+    }
+}
@@ -1,18 +1,21 @@
 use std::borrow::Cow;
 
+use fxhash::FxHashMap;
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 
 use crate::{
     clock::{Lamport, OpId, Peer},
-    table::{Column, LwwTable, Row},
+    error::{LwwError, LwwResult},
+    table::{Column, IndexKind, LwwTable, Row},
     value::Value,
 };
 
 use super::{
     bool_rle::{BoolRleDecoder, BoolRleEncoder},
+    decode_clock,
     delta_rle::{DeltaRleDecoder, DeltaRleEncoder},
-    Register,
+    encode_clock, EncodedClock, Register,
 };
 type PeerIdx = usize;
 
@@ -46,6 +49,63 @@ struct EncodedTable<'a> {
     row_deleted: Cow<'a, [u8]>,
     deleted_peer_idx: Vec<PeerIdx>,
     deleted_lamport: Vec<Lamport>,
+
+    /// The counter entries, (col index, row index, peer, lamport, total)
+    counters: Vec<(usize, usize, PeerIdx, Lamport, i64)>,
+    /// The values of the multi-value cells
+    mv: Vec<EncodedMvValue>,
+    /// The indexed columns, they are rebuilt after decoding
+    indexes: Vec<(SmolStr, IndexKind)>,
+}
+
+/// The layout of [EncodedTable] before the counters, the multi-value cells
+/// and the indexes were added
+#[derive(Deserialize)]
+struct LegacyTable<'a> {
+    table_deleted: Option<(PeerIdx, Lamport)>,
+    row_names: Vec<SmolStr>,
+    col_names: Vec<SmolStr>,
+    #[serde(borrow)]
+    has_value: Cow<'a, [u8]>,
+    values: Vec<Value>,
+    #[serde(borrow)]
+    lamport: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    peer_idx: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    row_deleted: Cow<'a, [u8]>,
+    deleted_peer_idx: Vec<PeerIdx>,
+    deleted_lamport: Vec<Lamport>,
+}
+
+impl<'a> From<LegacyTable<'a>> for EncodedTable<'a> {
+    fn from(t: LegacyTable<'a>) -> Self {
+        Self {
+            table_deleted: t.table_deleted,
+            row_names: t.row_names,
+            col_names: t.col_names,
+            has_value: t.has_value,
+            values: t.values,
+            lamport: t.lamport,
+            peer_idx: t.peer_idx,
+            row_deleted: t.row_deleted,
+            deleted_peer_idx: t.deleted_peer_idx,
+            deleted_lamport: t.deleted_lamport,
+            counters: Vec::new(),
+            mv: Vec::new(),
+            indexes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncodedMvValue {
+    col: usize,
+    row: usize,
+    peer_idx: PeerIdx,
+    lamport: Lamport,
+    value: Value,
+    context: Option<EncodedClock>,
 }
 
 pub(crate) fn encode_snapshot(table: &LwwTable, peer_pool: &mut Register<Peer>) -> Vec<u8> {
@@ -54,19 +114,18 @@ pub(crate) fn encode_snapshot(table: &LwwTable, peer_pool: &mut Register<Peer>)
     let mut lamport = DeltaRleEncoder::new();
     let mut peer_idx = DeltaRleEncoder::new();
     for (_col_name, col) in table.cols.iter() {
-        assert_eq!(col.value.len(), table.rows.len());
-        debug_assert_eq!(col.value.len(), col.lamport.len());
-        debug_assert_eq!(col.value.len(), col.peer.len());
-        for ((v, l), peer) in col.value.iter().zip(&col.lamport).zip(&col.peer) {
-            if *l != 0 {
-                has_value_encoder.push(true);
-                peer_idx.push(peer_pool.register(peer) as i64);
-                lamport.push(*l as i64);
-                values.push(v.clone());
-            } else {
-                has_value_encoder.push(false);
-            }
+        assert_eq!(col.len(), table.rows.len());
+        let mut next_row = 0;
+        for (i, v, id) in col.iter() {
+            has_value_encoder.push_n(false, i - next_row);
+            has_value_encoder.push(true);
+            peer_idx.push(peer_pool.register(&id.peer) as i64);
+            lamport.push(id.lamport as i64);
+            values.push(v.clone());
+            next_row = i + 1;
         }
+
+        has_value_encoder.push_n(false, col.len() - next_row);
     }
 
     let mut row_deleted_encoder = BoolRleEncoder::new();
@@ -82,6 +141,66 @@ pub(crate) fn encode_snapshot(table: &LwwTable, peer_pool: &mut Register<Peer>)
         }
     }
 
+    let col_idx: FxHashMap<&SmolStr, usize> =
+        table.cols.keys().enumerate().map(|(i, c)| (c, i)).collect();
+    let mut counters = Vec::new();
+    for (col, rows) in table.counters.iter() {
+        for (row, counter) in rows.iter() {
+            for (id, total) in counter.iter() {
+                counters.push((
+                    col_idx[col],
+                    table.row_id_to_idx[row],
+                    peer_pool.register(&id.peer),
+                    id.lamport,
+                    total,
+                ));
+            }
+        }
+    }
+
+    let mut counter_sets = Vec::new();
+    for (col, rows) in table.counters.iter() {
+        for (row, counter) in rows.iter() {
+            if let Some((id, value)) = counter.last_set() {
+                counter_sets.push((
+                    col_idx[col],
+                    table.row_id_to_idx[row],
+                    peer_pool.register(&id.peer),
+                    id.lamport,
+                    value.clone(),
+                ));
+            }
+        }
+    }
+
+    let mut mv = Vec::new();
+    for (col, rows) in table.mv.iter() {
+        for (row, reg) in rows.iter() {
+            for v in reg.values() {
+                mv.push(EncodedMvValue {
+                    col: col_idx[col],
+                    row: table.row_id_to_idx[row],
+                    peer_idx: peer_pool.register(&v.id.peer),
+                    lamport: v.id.lamport,
+                    value: v.value.clone(),
+                    context: v.context.as_ref().map(|c| encode_clock(c, peer_pool)),
+                });
+            }
+
+            // it's merged like the other values, so it's superseded again
+            if let Some((id, value)) = reg.superseded() {
+                mv.push(EncodedMvValue {
+                    col: col_idx[col],
+                    row: table.row_id_to_idx[row],
+                    peer_idx: peer_pool.register(&id.peer),
+                    lamport: id.lamport,
+                    value: value.clone(),
+                    context: None,
+                });
+            }
+        }
+    }
+
     let f = EncodedTable {
         table_deleted: table
             .removed
@@ -95,29 +214,86 @@ pub(crate) fn encode_snapshot(table: &LwwTable, peer_pool: &mut Register<Peer>)
         row_deleted: Cow::Owned(row_deleted_encoder.finish()),
         deleted_peer_idx,
         deleted_lamport,
+        counters,
+        mv,
+        indexes: table
+            .indexes
+            .iter()
+            .map(|(col, index)| (col.clone(), index.kind()))
+            .collect(),
     };
 
-    let data = postcard::to_allocvec(&f).unwrap();
+    // The sets of the counter cells are appended after the table, so the
+    // tables encoded without them can still be decoded
+    let mut data = postcard::to_allocvec(&f).unwrap();
+    if !counter_sets.is_empty() {
+        data.extend(postcard::to_allocvec(&counter_sets).unwrap());
+    }
+
     zstd::encode_all(&mut data.as_slice(), 0).unwrap()
 }
 
 pub(super) enum Change<'a> {
-    DelTable { id: OpId },
-    DelRow { row: &'a SmolStr, id: OpId },
-    Value { row: &'a SmolStr, id: OpId },
+    DelTable {
+        id: OpId,
+    },
+    DelRow {
+        row: &'a SmolStr,
+        id: OpId,
+    },
+    Value {
+        row: &'a SmolStr,
+        col: &'a SmolStr,
+        id: OpId,
+    },
 }
 
 pub(crate) fn decode_snapshot(
     encoded: &[u8],
     peers: &[Peer],
+    on_change: impl FnMut(Change),
+) -> LwwResult<LwwTable> {
+    let bytes = zstd::decode_all(encoded)?;
+    let (f, rest) = postcard::take_from_bytes::<EncodedTable>(&bytes)?;
+    let counter_sets = if rest.is_empty() {
+        Vec::new()
+    } else {
+        postcard::from_bytes(rest)?
+    };
+    decode_table(f, counter_sets, peers, on_change)
+}
+
+/// Decode a table of a snapshot without a header, which has no counters,
+/// multi-value cells or indexes
+pub(crate) fn decode_legacy_snapshot(
+    encoded: &[u8],
+    peers: &[Peer],
+    on_change: impl FnMut(Change),
+) -> LwwResult<LwwTable> {
+    let bytes = zstd::decode_all(encoded)?;
+    let f = postcard::from_bytes::<LegacyTable>(&bytes)?;
+    decode_table(f.into(), Vec::new(), peers, on_change)
+}
+
+fn decode_table(
+    f: EncodedTable,
+    counter_sets: Vec<(usize, usize, PeerIdx, Lamport, Value)>,
+    peers: &[Peer],
     mut on_change: impl FnMut(Change),
-) -> LwwTable {
-    let bytes = zstd::decode_all(encoded).unwrap();
-    let f = postcard::from_bytes::<EncodedTable>(&bytes).unwrap();
+) -> LwwResult<LwwTable> {
+    let get_peer = |idx: usize| {
+        peers
+            .get(idx)
+            .copied()
+            .ok_or(LwwError::PeerIndexOutOfRange {
+                index: idx as i64,
+                len: peers.len(),
+            })
+    };
     let mut table = LwwTable::new();
     if let Some(d) = f.table_deleted {
         let id = OpId {
-            peer: peers[d.0],
+            peer: get_peer(d.0)?,
             lamport: d.1,
         };
         on_change(Change::DelTable { id });
@@ -140,52 +316,181 @@ pub(crate) fn decode_snapshot(
         .enumerate()
         .map(|(i, x)| (x.row_id.clone(), i))
         .collect();
+    if table.row_id_to_idx.len() != table.rows.len() {
+        return Err(LwwError::InvalidOp("duplicated row id"));
+    }
+
+    for col_name in f.col_names.iter() {
+        if table.cols.contains_key(col_name) {
+            return Err(LwwError::InvalidOp("duplicated column name"));
+        }
 
-    for col in f.col_names {
         let col = table
             .cols
-            .entry(col.clone())
+            .entry(col_name.clone())
             .or_insert_with(|| Column::with_len(f.row_names.len()));
 
-        let mut num = 0;
-        for (i, row) in f.row_names.iter().enumerate() {
-            if has_value_iter.next().unwrap() {
-                let l = lampoort.next().unwrap();
-                let p = peer_idx.next().unwrap();
-                let v = value_iter.next().unwrap();
-                let p = peers[p as usize];
+        // Skip the empty cells run by run, so a sparse table is decoded
+        // without visiting every empty cell
+        let mut start = 0;
+        while start < f.row_names.len() {
+            let (has_value, n) = has_value_iter
+                .try_next_run(f.row_names.len() - start)?
+                .ok_or(LwwError::ColumnLengthMismatch)?;
+            let end = start + n;
+            if !has_value {
+                start = end;
+                continue;
+            }
+
+            for (i, row) in f.row_names[start..end].iter().enumerate() {
+                let i = start + i;
+                let l = lampoort.try_next()?.ok_or(LwwError::ColumnLengthMismatch)?;
+                let p = peer_idx.try_next()?.ok_or(LwwError::ColumnLengthMismatch)?;
+                let v = value_iter.next().ok_or(LwwError::ColumnLengthMismatch)?;
+                if l <= 0 || l > Lamport::MAX as i64 {
+                    return Err(LwwError::InvalidOp("lamport out of range"));
+                }
+
+                let p = usize::try_from(p)
+                    .ok()
+                    .and_then(|p| peers.get(p).copied())
+                    .ok_or(LwwError::PeerIndexOutOfRange {
+                        index: p,
+                        len: peers.len(),
+                    })?;
                 let id = OpId {
                     lamport: l as Lamport,
                     peer: p,
                 };
-                on_change(Change::Value { row, id });
-                assert!(l > 0);
-                num += 1;
-                col.lamport[i] = l as Lamport;
-                col.value[i] = v;
-                col.peer[i] = p;
+                on_change(Change::Value {
+                    row,
+                    col: col_name,
+                    id,
+                });
+                col.set(i, v, id);
             }
+
+            start = end;
         }
+    }
 
-        col.num = num;
+    if has_value_iter.try_next()?.is_some()
+        || lampoort.try_next()?.is_some()
+        || peer_idx.try_next()?.is_some()
+        || value_iter.next().is_some()
+    {
+        return Err(LwwError::ColumnLengthMismatch);
     }
 
     let mut row_deleted_iter = BoolRleDecoder::new(&f.row_deleted);
-    for (row, (peer_idx, lamport)) in f
-        .row_names
-        .iter()
-        .zip(f.deleted_peer_idx.iter().zip(f.deleted_lamport.iter()))
-    {
-        if row_deleted_iter.next().unwrap() {
+    let mut deleted = f.deleted_peer_idx.iter().zip(f.deleted_lamport.iter());
+    if f.deleted_peer_idx.len() != f.deleted_lamport.len() {
+        return Err(LwwError::ColumnLengthMismatch);
+    }
+
+    for (row_idx, row) in f.row_names.iter().enumerate() {
+        let is_deleted = row_deleted_iter
+            .try_next()?
+            .ok_or(LwwError::RowCountMismatch {
+                expected: f.row_names.len(),
+                actual: row_idx,
+            })?;
+        if is_deleted {
+            let (peer_idx, lamport) = deleted.next().ok_or(LwwError::ColumnLengthMismatch)?;
             let id = OpId {
-                peer: peers[*peer_idx],
+                peer: get_peer(*peer_idx)?,
                 lamport: *lamport,
             };
             on_change(Change::DelRow { row, id });
-            let row_idx = table.row_id_to_idx.get(row).unwrap();
-            table.rows.get_mut(*row_idx).unwrap().deleted = Some(id);
+            table.rows[row_idx].deleted = Some(id);
+        }
+    }
+
+    if deleted.next().is_some() {
+        return Err(LwwError::ColumnLengthMismatch);
+    }
+
+    if row_deleted_iter.try_next()?.is_some() {
+        return Err(LwwError::RowCountMismatch {
+            expected: f.row_names.len(),
+            actual: f.row_names.len() + 1,
+        });
+    }
+
+    // The sets of the counter cells, which are overwritten by the sums
+    for (col_idx, row_idx, peer_idx, lamport, value) in counter_sets {
+        let (Some(col), Some(row)) = (f.col_names.get(col_idx), f.row_names.get(row_idx)) else {
+            return Err(LwwError::InvalidOp("counter cell out of range"));
+        };
+        if lamport == 0 {
+            return Err(LwwError::InvalidOp("lamport out of range"));
+        }
+
+        let id = OpId {
+            peer: get_peer(peer_idx)?,
+            lamport,
+        };
+        on_change(Change::Value { row, col, id });
+        table
+            .counters
+            .entry(col.clone())
+            .or_default()
+            .entry(row.clone())
+            .or_default()
+            .reset(id, value);
+    }
+
+    // The sums of the counters are already in the columns
+    for (col_idx, row_idx, peer_idx, lamport, total) in f.counters {
+        let (Some(col), Some(row)) = (f.col_names.get(col_idx), f.row_names.get(row_idx)) else {
+            return Err(LwwError::InvalidOp("counter cell out of range"));
+        };
+        if lamport == 0 {
+            return Err(LwwError::InvalidOp("lamport out of range"));
+        }
+
+        let id = OpId {
+            peer: get_peer(peer_idx)?,
+            lamport,
+        };
+        on_change(Change::Value { row, col, id });
+        table
+            .counters
+            .entry(col.clone())
+            .or_default()
+            .entry(row.clone())
+            .or_default()
+            .merge(id, total);
+    }
+
+    // The latest values of the multi-value cells are already in the columns
+    for v in f.mv {
+        let (Some(col), Some(row)) = (f.col_names.get(v.col), f.row_names.get(v.row)) else {
+            return Err(LwwError::InvalidOp("multi-value cell out of range"));
+        };
+        if v.lamport == 0 {
+            return Err(LwwError::InvalidOp("lamport out of range"));
         }
+
+        let id = OpId {
+            peer: get_peer(v.peer_idx)?,
+            lamport: v.lamport,
+        };
+        let context = v.context.map(|c| decode_clock(&c, peers)).transpose()?;
+        on_change(Change::Value { row, col, id });
+        table
+            .mv
+            .entry(col.clone())
+            .or_default()
+            .entry(row.clone())
+            .or_default()
+            .merge(id, v.value, context);
+    }
+
+    for (col, kind) in f.indexes {
+        table.create_index(&col, kind);
     }
 
-    table
+    Ok(table)
 }
@@ -0,0 +1,55 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum LwwError {
+    #[error("failed to decompress the payload: {0}")]
+    Decompress(#[from] std::io::Error),
+    #[error("failed to deserialize the payload: {0}")]
+    Deserialize(#[from] postcard::Error),
+    #[error("malformed run-length encoded column")]
+    MalformedColumn,
+    #[error("string index {index} is out of range (len = {len})")]
+    StrIndexOutOfRange { index: i64, len: usize },
+    #[error("peer index {index} is out of range (len = {len})")]
+    PeerIndexOutOfRange { index: i64, len: usize },
+    #[error("encoded columns have mismatched lengths")]
+    ColumnLengthMismatch,
+    #[error("expected {expected} rows, found {actual}")]
+    RowCountMismatch { expected: usize, actual: usize },
+    #[error("invalid op: {0}")]
+    InvalidOp(&'static str),
+    #[error("the history of the requested version is not available")]
+    HistoryUnavailable,
+    #[error("schema violation: {0}")]
+    SchemaViolation(crate::schema::SchemaViolation),
+    #[error("the column has no index that supports the lookup")]
+    IndexUnavailable,
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("the payload doesn't have a valid header")]
+    InvalidHeader,
+    #[error("the checksum of the payload doesn't match")]
+    ChecksumMismatch,
+    #[error("expected a {expected} payload, found a {found} payload")]
+    UnexpectedPayloadKind {
+        expected: crate::PayloadKind,
+        found: crate::PayloadKind,
+    },
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("unsupported payload flags {0:#x}")]
+    UnsupportedFlags(u8),
+    /// The db is restored from a shallow snapshot, and the requested updates
+    /// are older than its history
+    #[error("the updates before the shallow frontier are not kept, a full snapshot is needed")]
+    NeedFullSnapshot,
+    /// The key is wrong, or the encrypted payload or its metadata are
+    /// tampered with
+    #[error("failed to authenticate the encrypted payload")]
+    DecryptionFailed,
+    #[error("invalid Ed25519 public key")]
+    InvalidPublicKey,
+}
+
+pub type LwwResult<T> = Result<T, LwwError>;
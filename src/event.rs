@@ -1,17 +1,287 @@
+use std::fmt::Debug;
+
 use smol_str::SmolStr;
 
 use crate::value::Value;
 
+/// The change of a single cell. `old` and `new` are [Value::Null] when the
+/// cell is empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellChange {
+    pub col: SmolStr,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
+    /// A row that had no value gets its first values
     Insert {
-        row_id: u64,
-        partial_data: Vec<(SmolStr, Value)>,
+        table: SmolStr,
+        row_id: SmolStr,
+        partial_data: Vec<CellChange>,
     },
+    /// The row is deleted. `partial_data` contains the cells cleared by the deletion.
+    ///
+    /// It's also emitted for every row removed by a table deletion, before the
+    /// [Event::DeleteTable] event. The rows that keep the values newer than the
+    /// deletion get an [Event::Update] instead.
     Delete {
-        row_id: u64,
+        table: SmolStr,
+        row_id: SmolStr,
+        partial_data: Vec<CellChange>,
     },
     Update {
-        row_id: u64,
-        partial_data: Vec<(SmolStr, Value)>,
+        table: SmolStr,
+        row_id: SmolStr,
+        partial_data: Vec<CellChange>,
+    },
+    DeleteTable {
+        table: SmolStr,
+    },
+    /// All the changes made by a transaction, reported together
+    Transaction {
+        events: Vec<Event>,
     },
 }
+
+impl Event {
+    /// The table of the event. It's `None` for [Event::Transaction]
+    pub fn table(&self) -> Option<&SmolStr> {
+        match self {
+            Event::Insert { table, .. }
+            | Event::Delete { table, .. }
+            | Event::Update { table, .. }
+            | Event::DeleteTable { table } => Some(table),
+            Event::Transaction { .. } => None,
+        }
+    }
+}
+
+pub type Listener = Box<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+/// The listeners of a [crate::LwwDb].
+///
+/// Listeners are not inherited by a cloned db.
+#[derive(Default)]
+pub(crate) struct Observer {
+    listeners: Vec<(SubscriptionId, Listener)>,
+    next_id: usize,
+    /// The events of the transaction being applied
+    batch: Option<Vec<Event>>,
+}
+
+impl Clone for Observer {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Debug for Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Observer")
+            .field("listeners", &self.listeners.len())
+            .finish()
+    }
+}
+
+impl Observer {
+    pub fn subscribe(&mut self, listener: Listener) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.listeners.push((id, listener));
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len = self.listeners.len();
+        self.listeners.retain(|(x, _)| *x != id);
+        self.listeners.len() != len
+    }
+
+    /// Events are only computed when someone is listening
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        if let Some(batch) = &mut self.batch {
+            batch.push(event);
+            return;
+        }
+
+        for (_, listener) in &self.listeners {
+            listener(&event);
+        }
+    }
+
+    /// Collect the following events until [Observer::end_batch]
+    pub fn start_batch(&mut self) {
+        if !self.is_empty() {
+            self.batch = Some(Vec::new());
+        }
+    }
+
+    /// Emit the collected events as one [Event::Transaction]
+    pub fn end_batch(&mut self) {
+        if let Some(events) = self.batch.take() {
+            if !events.is_empty() {
+                self.emit(Event::Transaction { events });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::LwwDb;
+
+    use super::*;
+
+    fn record(db: &mut LwwDb) -> Arc<Mutex<Vec<Event>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        db.subscribe(Box::new(move |e| {
+            events_clone.lock().unwrap().push(e.clone())
+        }));
+        events
+    }
+
+    fn change(col: &str, old: impl Into<Value>, new: impl Into<Value>) -> CellChange {
+        CellChange {
+            col: col.into(),
+            old: old.into(),
+            new: new.into(),
+        }
+    }
+
+    #[test]
+    fn local_events() {
+        let mut db = LwwDb::new();
+        let events = record(&mut db);
+        db.set("table", "a", "x", 1);
+        db.set("table", "a", "x", 2);
+        db.set("table", "a", "x", 2);
+        db.delete("table", "a", "x");
+        db.set("table", "a", "y", "v");
+        db.delete_row("table", "a");
+        db.delete_table("table");
+        assert_eq!(
+            events.lock().unwrap().as_slice(),
+            &[
+                Event::Insert {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("x", Value::Null, 1)],
+                },
+                Event::Update {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("x", 1, 2)],
+                },
+                Event::Update {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("x", 2, Value::Null)],
+                },
+                Event::Insert {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("y", Value::Null, "v")],
+                },
+                Event::Delete {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("y", "v", Value::Null)],
+                },
+                Event::DeleteTable {
+                    table: "table".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn import_events() {
+        let mut db = LwwDb::new();
+        db.set("table", "a", "x", 1);
+        db.set("table", "b", "x", 2);
+        let mut new_db = LwwDb::new();
+        let events = record(&mut new_db);
+        new_db.import_updates(&db.export_updates(Default::default()));
+        let mut events = events.lock().unwrap().clone();
+        events.sort_by_key(|e| match e {
+            Event::Insert { row_id, .. } => row_id.clone(),
+            _ => unreachable!(),
+        });
+        assert_eq!(
+            events,
+            vec![
+                Event::Insert {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("x", Value::Null, 1)],
+                },
+                Event::Insert {
+                    table: "table".into(),
+                    row_id: "b".into(),
+                    partial_data: vec![change("x", Value::Null, 2)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn concurrent_table_deletion_events() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.set("table", "a", "x", 1);
+        a.set("table", "b", "x", 2);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        b.import_updates(&a.export_updates(Default::default()));
+        b.set("table", "b", "y", 3);
+        b.set("table", "b", "y", 4);
+        a.delete_table("table");
+
+        // the row b keeps its newer value, so it's not deleted
+        let events = record(&mut b);
+        b.import_updates(&a.export_updates(Default::default()));
+        assert_eq!(b.get_cell("table", "b", "y"), Some(&Value::I64(4)));
+        assert_eq!(
+            events.lock().unwrap().as_slice(),
+            &[
+                Event::Delete {
+                    table: "table".into(),
+                    row_id: "a".into(),
+                    partial_data: vec![change("x", 1, Value::Null)],
+                },
+                Event::Update {
+                    table: "table".into(),
+                    row_id: "b".into(),
+                    partial_data: vec![change("x", 2, Value::Null)],
+                },
+                Event::DeleteTable {
+                    table: "table".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let mut db = LwwDb::new();
+        let events = Arc::new(Mutex::new(0));
+        let events_clone = events.clone();
+        let id = db.subscribe(Box::new(move |_| *events_clone.lock().unwrap() += 1));
+        db.set("table", "a", "x", 1);
+        assert!(db.unsubscribe(id));
+        db.set("table", "a", "x", 2);
+        assert_eq!(*events.lock().unwrap(), 1);
+    }
+}
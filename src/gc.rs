@@ -0,0 +1,148 @@
+use fxhash::FxHashSet;
+
+use crate::{
+    clock::{OpId, VectorClock},
+    LwwDb,
+};
+
+/// The result of [LwwDb::gc]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct GcStatus {
+    /// The number of row, table and cell tombstones that were dropped
+    pub tombstones: usize,
+    /// The number of rows that were removed because nothing was left in them
+    pub rows: usize,
+    /// The number of ops that were removed from the log
+    pub ops: usize,
+}
+
+impl LwwDb {
+    /// Drop the tombstones and the log entries of the deletions included in
+    /// `stable`, and remove the rows and the tables that have nothing left.
+    ///
+    /// `stable` must be a version that every replica has seen, and every op
+    /// that is not included in it must be created after it, e.g. the minimum
+    /// of the versions of all the replicas. Otherwise an older op that
+    /// arrives later is not suppressed by the dropped tombstones anymore.
+    ///
+    /// The version of the db is not changed, and [LwwDb::export_updates]
+    /// from any version that includes `stable` is not affected. The
+    /// collected deletions can't be undone. The signatures of the ops that
+    /// are overwritten are dropped as well.
+    pub fn gc(&mut self, stable: &VectorClock) -> GcStatus {
+        let mut status = GcStatus::default();
+        for table in self.tables.values_mut() {
+            let (tombstones, rows) = table.gc(stable);
+            status.tombstones += tombstones;
+            status.rows += rows;
+        }
+
+        self.tables.retain(|_, table| {
+            !table.rows.is_empty() || table.removed.is_some() || !table.indexes.is_empty()
+        });
+        let tables = &self.tables;
+        status.ops = self.oplog.gc(stable, |table, row| {
+            tables
+                .get(table)
+                .is_some_and(|t| t.row_id_to_idx.contains_key(row))
+        });
+        self.prune_signatures();
+        if !self.coerced.is_empty() {
+            let live = self.live_ops();
+            self.coerced.retain(|id, _| live.contains(id));
+        }
+
+        status
+    }
+
+    /// The ids of the ops that own a value, a tombstone or a definition
+    pub(crate) fn live_ops(&self) -> FxHashSet<OpId> {
+        let mut live = FxHashSet::default();
+        for table in self.tables.values() {
+            live.extend(table.removed);
+            live.extend(table.rows.iter().filter_map(|r| r.deleted));
+            for col in table.cols.values() {
+                live.extend(col.iter().map(|(_, _, id)| id));
+            }
+
+            for reg in table.mv.values().flat_map(|m| m.values()) {
+                live.extend(reg.values().iter().map(|v| v.id));
+                live.extend(reg.superseded().map(|(id, _)| id));
+            }
+
+            for counter in table.counters.values().flat_map(|m| m.values()) {
+                live.extend(counter.iter().map(|(id, _)| id));
+                live.extend(counter.last_set().map(|(id, _)| id));
+            }
+        }
+
+        live.extend(self.schema_meta.iter().map(|(_, id, _)| id));
+        live
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::value::Value;
+
+    use super::*;
+
+    fn sync(a: &mut LwwDb, b: &mut LwwDb) {
+        a.import_updates(&b.export_updates(a.version().clone()));
+        b.import_updates(&a.export_updates(b.version().clone()));
+    }
+
+    #[test]
+    fn collect_stable_tombstones() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        for row in ["1", "2", "3"] {
+            a.set("table", row, "x", 1);
+            a.set("table", row, "y", 2);
+        }
+        a.set("other", "1", "x", 1);
+        a.delete_row("table", "1");
+        a.delete("table", "2", "x");
+        a.delete_table("other");
+        sync(&mut a, &mut b);
+
+        let stable = a.version().clone();
+        b.delete_row("table", "3");
+        sync(&mut a, &mut b);
+        let version = a.version().clone();
+        let status = a.gc(&stable);
+        assert_eq!(
+            status,
+            GcStatus {
+                tombstones: 3,
+                rows: 1,
+                ops: 5,
+            }
+        );
+        assert_eq!(a.version(), &version);
+        assert!(!a.tables.contains_key("other"));
+        let table = &a.tables["table"];
+        assert!(!table.row_id_to_idx.contains_key("1"));
+        assert_eq!(a.get_cell("table", "2", "x"), None);
+        // the tombstone that is not stable yet is kept
+        assert!(table.rows[table.row_id_to_idx["3"]].deleted.is_some());
+
+        // the updates since the watermark are still exported correctly
+        b.gc(&stable);
+        a.set("table", "1", "x", 3);
+        b.delete_row("table", "2");
+        sync(&mut a, &mut b);
+        assert!(a.check_eq(&mut b));
+        assert_eq!(a.get_cell("table", "1", "x"), Some(&Value::I64(3)));
+
+        // a new replica gets the same state without the collected deletions
+        let mut c = LwwDb::new();
+        c.import_updates(&a.export_updates(Default::default()));
+        let mut d = LwwDb::from_snapshot(&a.export_snapshot());
+        assert!(a.check_eq(&mut c));
+        assert!(a.check_eq(&mut d));
+    }
+}
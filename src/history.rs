@@ -0,0 +1,333 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use fxhash::FxHashMap;
+use smol_str::SmolStr;
+
+use crate::{
+    clock::{OpId, VectorClock},
+    error::{LwwError, LwwResult},
+    table::LwwTable,
+    value::Value,
+    LwwDb,
+};
+
+/// Every op applied to the db, including the ones that were overwritten, so
+/// the db can be checked out at a past version.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct History {
+    ops: BTreeMap<OpId, HistoryOp>,
+    /// The version when the history was enabled. The ops before it are only
+    /// kept if they were still visible at that time.
+    start: VectorClock,
+}
+
+#[derive(Debug, Clone)]
+enum HistoryOp {
+    Set {
+        table: SmolStr,
+        row: SmolStr,
+        col: SmolStr,
+        value: Value,
+        context: Option<VectorClock>,
+    },
+    DeleteRow {
+        table: SmolStr,
+        row: SmolStr,
+    },
+    DeleteTable {
+        table: SmolStr,
+    },
+    Counter {
+        table: SmolStr,
+        row: SmolStr,
+        col: SmolStr,
+        total: i64,
+    },
+}
+
+impl History {
+    fn from_db(db: &LwwDb) -> Self {
+        let mut history = History {
+            ops: BTreeMap::new(),
+            start: db.version().clone(),
+        };
+        for (name, table) in db.iter_tables() {
+            if let Some(id) = table.removed {
+                history.record_delete_table(id, name);
+            }
+
+            for row in table.rows.iter() {
+                if let Some(id) = row.deleted {
+                    history.record_delete_row(id, name, &row.row_id);
+                }
+            }
+
+            for (col_name, col) in table.cols.iter() {
+                for (i, value, id) in col.iter() {
+                    let row = &table.rows[i].row_id;
+                    history.record_set(id, name, row, col_name, value.clone(), None);
+                }
+            }
+
+            for (col_name, rows) in table.mv.iter() {
+                for (row, reg) in rows.iter() {
+                    for v in reg.values() {
+                        let (value, context) = (v.value.clone(), v.context.clone());
+                        history.record_set(v.id, name, row, col_name, value, context);
+                    }
+
+                    if let Some((id, value)) = reg.superseded() {
+                        history.record_set(id, name, row, col_name, value.clone(), None);
+                    }
+                }
+            }
+
+            // the sums of the counters are replaced by their entries
+            for (col_name, rows) in table.counters.iter() {
+                for (row, counter) in rows.iter() {
+                    for (id, total) in counter.iter() {
+                        history.record_counter(id, name, row, col_name, total);
+                    }
+                }
+            }
+        }
+
+        history
+    }
+
+    pub fn record_set(
+        &mut self,
+        id: OpId,
+        table: &str,
+        row: &str,
+        col: &str,
+        value: Value,
+        context: Option<VectorClock>,
+    ) {
+        self.ops.insert(
+            id,
+            HistoryOp::Set {
+                table: table.into(),
+                row: row.into(),
+                col: col.into(),
+                value,
+                context,
+            },
+        );
+    }
+
+    pub fn record_delete_row(&mut self, id: OpId, table: &str, row: &str) {
+        self.ops.insert(
+            id,
+            HistoryOp::DeleteRow {
+                table: table.into(),
+                row: row.into(),
+            },
+        );
+    }
+
+    pub fn record_delete_table(&mut self, id: OpId, table: &str) {
+        self.ops.insert(
+            id,
+            HistoryOp::DeleteTable {
+                table: table.into(),
+            },
+        );
+    }
+
+    pub fn record_counter(&mut self, id: OpId, table: &str, row: &str, col: &str, total: i64) {
+        self.ops.insert(
+            id,
+            HistoryOp::Counter {
+                table: table.into(),
+                row: row.into(),
+                col: col.into(),
+                total,
+            },
+        );
+    }
+
+    fn checkout(&self, version: &VectorClock) -> LwwResult<DbView> {
+        if self
+            .start
+            .iter()
+            .any(|(peer, lamport)| !version.includes(OpId::new(*lamport, *peer)))
+        {
+            return Err(LwwError::HistoryUnavailable);
+        }
+
+        let mut tables: FxHashMap<SmolStr, LwwTable> = FxHashMap::default();
+        for (id, op) in self.ops.iter() {
+            if !version.includes(*id) {
+                continue;
+            }
+
+            match op {
+                HistoryOp::Set {
+                    table,
+                    row,
+                    col,
+                    value,
+                    context,
+                } => {
+                    tables.entry(table.clone()).or_default().set_with_context(
+                        row,
+                        col,
+                        value.clone(),
+                        *id,
+                        context.clone(),
+                    );
+                }
+                HistoryOp::DeleteRow { table, row } => {
+                    tables
+                        .entry(table.clone())
+                        .or_default()
+                        .delete_row(row, *id);
+                }
+                HistoryOp::DeleteTable { table } => {
+                    tables.entry(table.clone()).or_default().delete_table(*id);
+                }
+                HistoryOp::Counter {
+                    table,
+                    row,
+                    col,
+                    total,
+                } => {
+                    tables
+                        .entry(table.clone())
+                        .or_default()
+                        .merge_counter(row, col, *id, *total);
+                }
+            }
+        }
+
+        Ok(DbView {
+            tables,
+            version: version.clone(),
+        })
+    }
+}
+
+/// A read-only view of the db at a past version. See [LwwDb::checkout].
+#[derive(Debug, Clone)]
+pub struct DbView {
+    tables: FxHashMap<SmolStr, LwwTable>,
+    version: VectorClock,
+}
+
+impl Display for DbView {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "DbView {{")?;
+        crate::fmt_tables(f, &self.tables)?;
+        writeln!(f, "}}")
+    }
+}
+
+impl DbView {
+    pub fn version(&self) -> &VectorClock {
+        &self.version
+    }
+
+    pub fn get_cell(&self, table_str: &str, row: &str, col: &str) -> Option<&Value> {
+        self.tables
+            .get(table_str)
+            .and_then(|table| table.get_cell(row, col))
+    }
+
+    pub fn iter_row(
+        &self,
+        table_str: &str,
+        row: &str,
+    ) -> impl Iterator<Item = (&str, &Value)> + '_ {
+        self.tables
+            .get(table_str)
+            .map(|table| table.iter_row(row))
+            .into_iter()
+            .flatten()
+    }
+
+    pub fn iter_tables(&self) -> impl Iterator<Item = (&SmolStr, &LwwTable)> {
+        self.tables.iter()
+    }
+}
+
+impl LwwDb {
+    /// Start keeping the overwritten values so [LwwDb::checkout] can read the
+    /// past versions of the db.
+    ///
+    /// Only the versions that include the current version can be checked out.
+    pub fn enable_history(&mut self) {
+        if self.history.is_none() {
+            self.history = Some(History::from_db(self));
+        }
+    }
+
+    pub fn is_history_enabled(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Get a read-only view of the db at the given version
+    ///
+    /// It returns [LwwError::HistoryUnavailable] if the history is not enabled
+    /// or the version is older than the moment the history was enabled.
+    pub fn checkout(&self, version: &VectorClock) -> LwwResult<DbView> {
+        self.history
+            .as_ref()
+            .ok_or(LwwError::HistoryUnavailable)?
+            .checkout(version)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checkout_past_versions() {
+        let mut db = LwwDb::new();
+        db.set_peer(1);
+        db.set("t", "a", "x", 0);
+        db.enable_history();
+        db.set("t", "a", "x", 1);
+        let v1 = db.version().clone();
+        db.set("t", "a", "x", 2);
+        db.set("t", "b", "x", 2);
+        let v2 = db.version().clone();
+        db.delete_row("t", "a");
+        db.delete_table("t");
+        db.set("t", "c", "x", 3);
+
+        let view = db.checkout(&v1).unwrap();
+        assert_eq!(view.get_cell("t", "a", "x"), Some(&Value::I64(1)));
+        assert_eq!(view.get_cell("t", "b", "x"), None);
+        let view = db.checkout(&v2).unwrap();
+        assert_eq!(view.get_cell("t", "a", "x"), Some(&Value::I64(2)));
+        assert_eq!(view.get_cell("t", "b", "x"), Some(&Value::I64(2)));
+        let view = db.checkout(db.version()).unwrap();
+        assert_eq!(view.get_cell("t", "a", "x"), None);
+        assert_eq!(view.get_cell("t", "c", "x"), Some(&Value::I64(3)));
+        assert!(matches!(
+            db.checkout(&Default::default()),
+            Err(LwwError::HistoryUnavailable)
+        ));
+    }
+
+    #[test]
+    fn checkout_keeps_overwritten_remote_ops() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        b.enable_history();
+        a.set("t", "r", "x", "a");
+        let a_version = a.version().clone();
+        b.set("t", "r", "x", "b");
+        b.set("t", "r", "x", "b2");
+        b.import_updates(&a.export_updates(Default::default()));
+        assert_eq!(b.get_cell("t", "r", "x"), Some(&"b2".into()));
+
+        // the op of `a` lost, but it's the winner at the version of `a`
+        let view = b.checkout(&a_version).unwrap();
+        assert_eq!(view.get_cell("t", "r", "x"), Some(&"a".into()));
+    }
+}
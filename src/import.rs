@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+
+use smol_str::SmolStr;
+
+use crate::{
+    access::{AccessKind, AccessRequest},
+    clock::{Lamport, OpId, Peer, VectorClock},
+    encode::DecodedOp,
+    schema::{InvalidOpPolicy, SchemaOp, SchemaViolation},
+    value::Value,
+    LwwDb,
+};
+
+/// The result of an import
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ImportStatus {
+    /// The number of ops that were applied, including the ones that lost to
+    /// newer local values
+    pub applied: usize,
+    /// The ops that were dropped
+    pub rejected: Vec<RejectedOp>,
+    /// The number of ops that were kept aside, see [LwwDb::quarantined_ops]
+    pub quarantined: usize,
+    /// The ops that were dropped because they are not signed by their peer,
+    /// see [LwwDb::trust_peer]
+    pub forged: Vec<OpId>,
+}
+
+/// An op imported from another peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteOp {
+    pub id: OpId,
+    pub table: SmolStr,
+    /// None if the op deletes the table
+    pub row: Option<SmolStr>,
+    /// None if the op deletes the row or the table
+    pub col: Option<SmolStr>,
+    pub value: Value,
+    /// The version of the writer if it's written to a multi-value column
+    pub context: Option<VectorClock>,
+}
+
+/// A rejected op. The counter entries have the total of their peer as the
+/// value, and the schema ops have no row and [Value::Null] as the value.
+#[derive(Debug, Clone)]
+pub struct RejectedOp {
+    pub op: RemoteOp,
+    pub reason: RejectReason,
+    /// The transaction of the op, (peer, start lamport, end lamport)
+    pub(crate) txn: Option<(Peer, Lamport, Lamport)>,
+    pub(crate) kind: RejectedKind,
+}
+
+/// How the rejected op is applied when it's retried
+#[derive(Debug, Clone)]
+pub(crate) enum RejectedKind {
+    Op,
+    Counter,
+    Schema(SchemaOp),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum RejectReason {
+    Schema(SchemaViolation),
+    /// Another op of the same transaction was rejected
+    Transaction,
+    /// The op is denied by the access policy, see [LwwDb::set_access_policy]
+    Denied(AccessKind),
+}
+
+impl RemoteOp {
+    fn new(op: &DecodedOp) -> Self {
+        Self {
+            id: op.id,
+            table: op.table.into(),
+            row: op.row.map(Into::into),
+            col: op.col.map(Into::into),
+            value: op.value.clone(),
+            context: op.context.cloned(),
+        }
+    }
+
+    fn as_decoded(&self) -> DecodedOp<'_> {
+        DecodedOp {
+            id: self.id,
+            table: &self.table,
+            row: self.row.as_deref(),
+            col: self.col.as_deref(),
+            value: &self.value,
+            context: self.context.as_ref(),
+        }
+    }
+}
+
+impl LwwDb {
+    /// The imported ops that violated the schema or were denied by the access
+    /// policy under [InvalidOpPolicy::Quarantine], ordered by id
+    pub fn quarantined_ops(&self) -> impl Iterator<Item = &RejectedOp> {
+        self.quarantine.values()
+    }
+
+    /// Remove the quarantined ops without applying them
+    pub fn take_quarantined_ops(&mut self) -> Vec<RejectedOp> {
+        std::mem::take(&mut self.quarantine).into_values().collect()
+    }
+
+    /// Try to apply the quarantined ops again, e.g. after the schema or the
+    /// access policy is changed. The ops that are still invalid are handled
+    /// by the current [InvalidOpPolicy].
+    pub fn retry_quarantined_ops(&mut self) -> ImportStatus {
+        let mut status = ImportStatus::default();
+        let mut ops = Vec::new();
+        let mut counters = Vec::new();
+        let mut txns: BTreeMap<(Peer, Lamport, Lamport), Vec<RemoteOp>> = BTreeMap::new();
+        // The version can include an op that violated the schema when a later
+        // op of its peer is applied, so they are all retried. Applying an op
+        // again doesn't change the db.
+        for (_, q) in std::mem::take(&mut self.quarantine) {
+            match (q.kind, q.txn) {
+                (RejectedKind::Schema(op), _) => {
+                    self.import_schema_op(q.op.id, &q.op.table, op, &mut status)
+                }
+                (RejectedKind::Counter, _) => counters.push(q.op),
+                (RejectedKind::Op, Some(txn)) => txns.entry(txn).or_default().push(q.op),
+                (RejectedKind::Op, None) => ops.push(q.op),
+            }
+        }
+
+        for op in ops {
+            self.import_op(op.as_decoded(), &mut status);
+        }
+
+        for (txn, ops) in txns {
+            self.import_txn(
+                txn,
+                ops.iter().map(|op| op.as_decoded()).collect(),
+                &mut status,
+            );
+        }
+
+        for op in counters {
+            let (Some(row), Some(col), Value::I64(total)) = (&op.row, &op.col, op.value) else {
+                unreachable!()
+            };
+            self.import_counter(op.id, &op.table, row, col, total, &mut status);
+        }
+
+        status
+    }
+
+    /// Check the remote op with the access policy and the schema and apply it
+    pub(crate) fn import_op(&mut self, op: DecodedOp, status: &mut ImportStatus) {
+        match self.check_remote_op(&op) {
+            Ok(value) => {
+                let value = value.unwrap_or_else(|| op.value.clone());
+                self.apply_op(&op, value);
+                status.applied += 1;
+            }
+            Err(reason) => self.reject(RemoteOp::new(&op), reason, None, RejectedKind::Op, status),
+        }
+    }
+
+    /// Check the remote schema op with the access policy and apply it
+    pub(crate) fn import_schema_op(
+        &mut self,
+        id: OpId,
+        table: &str,
+        op: SchemaOp,
+        status: &mut ImportStatus,
+    ) {
+        let col = op.col().cloned();
+        let allowed = self.check_access(AccessRequest {
+            peer: id.peer,
+            table,
+            row: None,
+            col: col.as_deref(),
+            value: &Value::Null,
+            kind: AccessKind::Define,
+        });
+        if allowed {
+            self.apply_schema_op(id, table, op);
+            self.oplog.include(id);
+            return;
+        }
+
+        let remote = RemoteOp {
+            id,
+            table: table.into(),
+            row: None,
+            col,
+            value: Value::Null,
+            context: None,
+        };
+        let reason = RejectReason::Denied(AccessKind::Define);
+        self.reject(remote, reason, None, RejectedKind::Schema(op), status);
+    }
+
+    /// Check the remote counter entry with the access policy and the schema
+    /// and merge it
+    pub(crate) fn import_counter(
+        &mut self,
+        id: OpId,
+        table: &str,
+        row: &str,
+        col: &str,
+        total: i64,
+        status: &mut ImportStatus,
+    ) {
+        let value = Value::I64(total);
+        let allowed = self.check_access(AccessRequest {
+            peer: id.peer,
+            table,
+            row: Some(row),
+            col: Some(col),
+            value: &value,
+            kind: AccessKind::Increment,
+        });
+        let checked = if !allowed {
+            Err(RejectReason::Denied(AccessKind::Increment))
+        } else {
+            match self.check_remote_write(table, col, &value) {
+                Ok(None) => Ok(()),
+                // the total can't be coerced, because the value of the cell
+                // is the sum of the totals
+                Ok(Some(_)) => self
+                    .schemas
+                    .get(table)
+                    .map_or(Ok(()), |s| s.check(table, col, &value)),
+                Err(e) => Err(e),
+            }
+            .map_err(RejectReason::Schema)
+        };
+        let reason = match checked {
+            Ok(()) => {
+                self.merge_counter_(table, row, col, id, total);
+                self.oplog.include(id);
+                status.applied += 1;
+                return;
+            }
+            Err(reason) => reason,
+        };
+
+        let remote = RemoteOp {
+            id,
+            table: table.into(),
+            row: Some(row.into()),
+            col: Some(col.into()),
+            value,
+            context: None,
+        };
+        self.reject(remote, reason, None, RejectedKind::Counter, status);
+    }
+
+    /// Apply the ops of a transaction. If any op is invalid, none of them is applied.
+    pub(crate) fn import_txn(
+        &mut self,
+        txn: (Peer, Lamport, Lamport),
+        ops: Vec<DecodedOp>,
+        status: &mut ImportStatus,
+    ) {
+        let checked: Vec<_> = ops.iter().map(|op| self.check_remote_op(op)).collect();
+        if checked.iter().any(|c| c.is_err()) {
+            // the whole transaction is received again if an op is denied
+            if checked
+                .iter()
+                .any(|c| matches!(c, Err(RejectReason::Denied(_))))
+            {
+                for op in &ops {
+                    self.oplog.exclude(op.id);
+                }
+            }
+
+            for (op, c) in ops.iter().zip(checked) {
+                let reason = c.err().unwrap_or(RejectReason::Transaction);
+                self.reject(
+                    RemoteOp::new(op),
+                    reason,
+                    Some(txn),
+                    RejectedKind::Op,
+                    status,
+                );
+            }
+
+            return;
+        }
+
+        let (peer, start, end) = txn;
+        self.observer.start_batch();
+        for (op, value) in ops.into_iter().zip(checked) {
+            let value = value.unwrap().unwrap_or_else(|| op.value.clone());
+            self.apply_op(&op, value);
+            status.applied += 1;
+        }
+
+        self.oplog.record_txn(peer, start, end);
+        self.observer.end_batch();
+    }
+
+    /// Returns the value to write if the op is valid
+    fn check_remote_op(&self, op: &DecodedOp) -> Result<Option<Value>, RejectReason> {
+        let kind = match op.col {
+            Some(_) => AccessKind::Write,
+            None => AccessKind::Delete,
+        };
+        let allowed = self.check_access(AccessRequest {
+            peer: op.id.peer,
+            table: op.table,
+            row: op.row,
+            col: op.col,
+            value: if op.col.is_some() {
+                op.value
+            } else {
+                &Value::Null
+            },
+            kind,
+        });
+        if !allowed {
+            return Err(RejectReason::Denied(kind));
+        }
+
+        match op.col {
+            Some(col) => self
+                .check_remote_write(op.table, col, op.value)
+                .map_err(RejectReason::Schema),
+            // deleting a row or a table is always valid
+            None => Ok(None),
+        }
+    }
+
+    fn reject(
+        &mut self,
+        op: RemoteOp,
+        reason: RejectReason,
+        txn: Option<(Peer, Lamport, Lamport)>,
+        kind: RejectedKind,
+        status: &mut ImportStatus,
+    ) {
+        if matches!(reason, RejectReason::Denied(_)) {
+            self.oplog.exclude(op.id);
+        }
+
+        let rejected = RejectedOp {
+            op,
+            reason,
+            txn,
+            kind,
+        };
+        if self.invalid_op_policy == InvalidOpPolicy::Quarantine {
+            self.quarantine.insert(rejected.op.id, rejected);
+            status.quarantined += 1;
+        } else {
+            status.rejected.push(rejected);
+        }
+    }
+}
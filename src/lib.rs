@@ -1,29 +1,79 @@
 #![doc = include_str!("../README.md")]
 
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
+use access::Access;
 use clock::Peer;
-use event::Event;
-use fxhash::FxHashMap;
+use event::Observer;
+use fxhash::{FxHashMap, FxHashSet};
+use history::History;
 use oplog::OpLog;
+use schema::SchemaMeta;
+use sign::Signatures;
 use smol_str::SmolStr;
 use table::LwwTable;
-use value::Value;
 
+mod access;
 pub(crate) mod clock;
 mod encode;
+mod error;
 mod event;
+mod gc;
+mod history;
+mod import;
 mod oplog;
+mod query;
+mod reconcile;
+mod schema;
+mod sign;
+mod storage;
+mod sync;
 pub(crate) mod table;
+mod txn;
+mod undo;
 pub(crate) mod value;
 
+pub use access::{AccessKind, AccessPolicy, AccessRequest};
 pub use clock::{OpId, VectorClock};
+pub use encode::{
+    PayloadHeader, PayloadKey, PayloadKind, PayloadMeta, StreamProgress, DEFAULT_CHUNK_SIZE,
+};
+pub use error::{LwwError, LwwResult};
+pub use event::{CellChange, Event, Listener, SubscriptionId};
+pub use gc::GcStatus;
+pub use history::DbView;
+pub use import::{ImportStatus, RejectReason, RejectedOp, RemoteOp};
+pub use query::{Aggregate, AggregateRow, Order, Predicate, Query, QueryRow, View, ViewDef};
+pub use reconcile::ReconcileMessage;
+pub use schema::{
+    ColumnDef, ColumnSchema, InvalidOpPolicy, SchemaViolation, TableDef, TableSchema, ValueType,
+};
+pub use sign::{PeerKey, PeerPublicKey};
+pub use storage::Storage;
+pub use sync::{SyncMessage, SyncSession};
+pub use table::IndexKind;
+pub use txn::Transaction;
+pub use undo::UndoManager;
+pub use value::Value;
 
 #[derive(Debug, Clone)]
 pub struct LwwDb {
     peer: Peer,
     tables: FxHashMap<SmolStr, LwwTable>,
     oplog: OpLog,
+    observer: Observer,
+    history: Option<History>,
+    undo: Option<UndoManager>,
+    schemas: FxHashMap<SmolStr, TableSchema>,
+    invalid_op_policy: InvalidOpPolicy,
+    quarantine: BTreeMap<OpId, RejectedOp>,
+    /// The original values of the imported ops that were coerced
+    coerced: BTreeMap<OpId, Value>,
+    schema_meta: SchemaMeta,
+    multi_value: FxHashMap<SmolStr, FxHashSet<SmolStr>>,
+    views: FxHashMap<SmolStr, View>,
+    signatures: Signatures,
+    access: Access,
 }
 
 impl Default for LwwDb {
@@ -35,20 +85,29 @@ impl Default for LwwDb {
 impl Display for LwwDb {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "LwwDb {{")?;
-        let mut s = String::new();
-        for (name, table) in &self.tables {
-            s.push_str(&format!("# {}\n", name));
-            s.push_str(&format!("{}\n\n", table));
-        }
-
-        let s = s.trim();
-        for line in s.split('\n') {
-            writeln!(f, "  {}", line)?;
-        }
+        fmt_tables(f, &self.tables)?;
         writeln!(f, "}}")
     }
 }
 
+pub(crate) fn fmt_tables(
+    f: &mut std::fmt::Formatter<'_>,
+    tables: &FxHashMap<SmolStr, LwwTable>,
+) -> std::fmt::Result {
+    let mut s = String::new();
+    for (name, table) in tables {
+        s.push_str(&format!("# {}\n", name));
+        s.push_str(&format!("{}\n\n", table));
+    }
+
+    let s = s.trim();
+    for line in s.split('\n') {
+        writeln!(f, "  {}", line)?;
+    }
+
+    Ok(())
+}
+
 impl LwwDb {
     pub fn new() -> Self {
         let mut id = [0u8; 8];
@@ -57,6 +116,18 @@ impl LwwDb {
             peer: Peer::from_be_bytes(id),
             tables: Default::default(),
             oplog: Default::default(),
+            observer: Default::default(),
+            history: None,
+            undo: None,
+            schemas: Default::default(),
+            invalid_op_policy: Default::default(),
+            quarantine: Default::default(),
+            coerced: Default::default(),
+            schema_meta: Default::default(),
+            multi_value: Default::default(),
+            views: Default::default(),
+            signatures: Default::default(),
+            access: Default::default(),
         }
     }
 
@@ -102,8 +173,74 @@ impl LwwDb {
             .flatten()
     }
 
+    /// The values of the cell that were written concurrently, ordered by
+    /// id. The last one is the value returned by [LwwDb::get_cell].
+    ///
+    /// Only the cells of the multi-value columns can have more than one
+    /// value, see [LwwDb::set_multi_value].
+    pub fn get_cell_conflicts(&self, table_str: &str, row: &str, col: &str) -> Vec<&Value> {
+        let Some(table) = self.tables.get(table_str) else {
+            return Vec::new();
+        };
+
+        match table.mv_register(row, col) {
+            Some(reg) => reg.values().iter().map(|v| &v.value).collect(),
+            None => table
+                .get_cell(row, col)
+                .filter(|v| **v != Value::Null)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Keep the concurrent writes to the column instead of picking the one
+    /// with the greater [OpId]. A local write to the column supersedes all
+    /// the values this db has seen, which resolves the conflicts.
+    ///
+    /// It only changes the local writes. The writes of the peers that don't
+    /// enable it replace each other like in the other columns, and the
+    /// latest of them is kept as a conflict of the multi-value cells.
+    pub fn set_multi_value(&mut self, table_str: &str, col: &str, enabled: bool) {
+        if enabled {
+            self.multi_value
+                .entry(table_str.into())
+                .or_default()
+                .insert(col.into());
+        } else if let Some(cols) = self.multi_value.get_mut(table_str) {
+            cols.remove(col);
+        }
+    }
+
+    pub fn is_multi_value(&self, table_str: &str, col: &str) -> bool {
+        self.multi_value
+            .get(table_str)
+            .is_some_and(|cols| cols.contains(col))
+    }
+
+    /// The context of a local write to a multi-value column
+    fn local_context(&self, local: bool, table_str: &str, col: &str) -> Option<VectorClock> {
+        (local && self.is_multi_value(table_str, col)).then(|| self.version().clone())
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the value violates the schema of the table. Use
+    /// [LwwDb::try_set] to handle the violation.
     pub fn set(&mut self, table_str: &str, row: &str, col: &str, value: impl Into<Value>) {
-        self.set_(table_str, row, col, value.into(), None)
+        self.try_set(table_str, row, col, value).unwrap()
+    }
+
+    pub fn try_set(
+        &mut self,
+        table_str: &str,
+        row: &str,
+        col: &str,
+        value: impl Into<Value>,
+    ) -> LwwResult<()> {
+        let value = value.into();
+        self.check_schema(table_str, col, &value)?;
+        self.set_(table_str, row, col, value, None);
+        Ok(())
     }
 
     pub(crate) fn set_(
@@ -114,7 +251,21 @@ impl LwwDb {
         value: impl Into<Value>,
         id: Option<OpId>,
     ) {
-        self.inner_set_(table_str, row, col, value.into(), id)
+        self.inner_set_(table_str, row, col, value.into(), id, None)
+    }
+
+    /// Apply a remote write, which has a context if it's written to a
+    /// multi-value column
+    pub(crate) fn set_with_context_(
+        &mut self,
+        table_str: &str,
+        row: &str,
+        col: &str,
+        value: Value,
+        id: OpId,
+        context: Option<VectorClock>,
+    ) {
+        self.inner_set_(table_str, row, col, value, Some(id), context)
     }
 
     fn inner_set_(
@@ -124,8 +275,19 @@ impl LwwDb {
         col: &str,
         value: Value,
         id: Option<OpId>,
+        context: Option<VectorClock>,
     ) {
+        let local = id.is_none();
+        let context = context.or_else(|| self.local_context(local, table_str, col));
         let id = id.unwrap_or_else(|| self.next_id());
+        if let Some(history) = &mut self.history {
+            history.record_set(id, table_str, row, col, value.clone(), context.clone());
+        }
+
+        let restores = (local && self.undo.is_some())
+            .then(|| self.cells_to_restore(table_str, row, Some(col), id));
+
+        let track = self.tracks_changes(table_str);
         let table = if let Some(table) = self.tables.get_mut(table_str) {
             table
         } else {
@@ -133,13 +295,41 @@ impl LwwDb {
             self.tables.get_mut(table_str).unwrap()
         };
 
-        if table.set(row, col, value, id) {
-            self.oplog.record_update(id, table_str.into(), row.into())
+        let before = track.then(|| table.live_row_values(row));
+        if table.set_with_context(row, col, value, id, context) {
+            self.oplog
+                .record_update(id, table_str.into(), row.into(), col.into());
+            if let (Some(undo), Some(restores)) = (&mut self.undo, restores) {
+                undo.record(restores);
+            }
+
+            if let Some(before) = before {
+                self.emit_row_diff(table_str, row, before);
+            }
         }
     }
 
+    /// # Panics
+    ///
+    /// Panics if the column is not nullable. Use [LwwDb::try_delete] to
+    /// handle the violation.
     pub fn delete(&mut self, table_str: &str, row: &str, col: &str) {
+        self.try_delete(table_str, row, col).unwrap()
+    }
+
+    pub fn try_delete(&mut self, table_str: &str, row: &str, col: &str) -> LwwResult<()> {
+        self.check_schema(table_str, col, &Value::Null)?;
+        let context = self.local_context(true, table_str, col);
         let id = self.next_id();
+        if let Some(history) = &mut self.history {
+            history.record_set(id, table_str, row, col, Value::Null, context.clone());
+        }
+
+        let restores = self
+            .undo
+            .is_some()
+            .then(|| self.cells_to_restore(table_str, row, Some(col), id));
+        let track = self.tracks_changes(table_str);
         let table = if let Some(table) = self.tables.get_mut(table_str) {
             table
         } else {
@@ -147,8 +337,83 @@ impl LwwDb {
             self.tables.get_mut(table_str).unwrap()
         };
 
-        if table.delete(row, col, id) {
-            self.oplog.record_update(id, table_str.into(), row.into())
+        let before = track.then(|| table.live_row_values(row));
+        if table.set_with_context(row, col, Value::Null, id, context) {
+            self.oplog
+                .record_update(id, table_str.into(), row.into(), col.into());
+            if let (Some(undo), Some(restores)) = (&mut self.undo, restores) {
+                undo.record(restores);
+            }
+
+            if let Some(before) = before {
+                self.emit_row_diff(table_str, row, before);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add `delta` to the counter cell. The concurrent increments of
+    /// different peers are summed instead of overwriting each other.
+    ///
+    /// A `set` or `delete` newer than the latest increment overwrites the sum,
+    /// and `delete_row` drops the increments that are older than it.
+    /// Increments are not recorded by the [UndoManager].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the column doesn't accept [Value::I64] in the schema. Use
+    /// [LwwDb::try_increment] to handle the violation.
+    pub fn increment(&mut self, table_str: &str, row: &str, col: &str, delta: i64) {
+        self.try_increment(table_str, row, col, delta).unwrap()
+    }
+
+    pub fn try_increment(
+        &mut self,
+        table_str: &str,
+        row: &str,
+        col: &str,
+        delta: i64,
+    ) -> LwwResult<()> {
+        self.check_schema(table_str, col, &Value::I64(delta))?;
+        let id = self.next_id();
+        let total = self
+            .tables
+            .get(table_str)
+            .map(|t| t.counter_total(row, col, id.peer))
+            .unwrap_or(0);
+        self.merge_counter_(table_str, row, col, id, total.wrapping_add(delta));
+        Ok(())
+    }
+
+    /// Merge the counter entry of `id.peer`, whose increments add up to `total`
+    pub(crate) fn merge_counter_(
+        &mut self,
+        table_str: &str,
+        row: &str,
+        col: &str,
+        id: OpId,
+        total: i64,
+    ) {
+        if let Some(history) = &mut self.history {
+            history.record_counter(id, table_str, row, col, total);
+        }
+
+        let track = self.tracks_changes(table_str);
+        let table = if let Some(table) = self.tables.get_mut(table_str) {
+            table
+        } else {
+            self.create_table(table_str);
+            self.tables.get_mut(table_str).unwrap()
+        };
+
+        let before = track.then(|| table.live_row_values(row));
+        if table.merge_counter(row, col, id, total) {
+            self.oplog
+                .record_update(id, table_str.into(), row.into(), col.into());
+            if let Some(before) = before {
+                self.emit_row_diff(table_str, row, before);
+            }
         }
     }
 
@@ -157,7 +422,15 @@ impl LwwDb {
     }
 
     pub(crate) fn delete_row_(&mut self, table_str: &str, row: &str, id: Option<OpId>) {
+        let local = id.is_none();
         let id = id.unwrap_or_else(|| self.next_id());
+        if let Some(history) = &mut self.history {
+            history.record_delete_row(id, table_str, row);
+        }
+
+        let restores =
+            (local && self.undo.is_some()).then(|| self.cells_to_restore(table_str, row, None, id));
+        let track = self.tracks_changes(table_str);
         let table = if let Some(table) = self.tables.get_mut(table_str) {
             table
         } else {
@@ -165,9 +438,17 @@ impl LwwDb {
             self.tables.get_mut(table_str).unwrap()
         };
 
+        let before = track.then(|| table.live_row_values(row));
         if table.delete_row(row, id) {
             self.oplog
-                .record_delete_row(id, table_str.into(), row.into())
+                .record_delete_row(id, table_str.into(), row.into());
+            if let (Some(undo), Some(restores)) = (&mut self.undo, restores) {
+                undo.record(restores);
+            }
+
+            if let Some(before) = before {
+                self.emit_row_diff(table_str, row, before);
+            }
         }
     }
 
@@ -177,6 +458,11 @@ impl LwwDb {
 
     pub(crate) fn delete_table_(&mut self, table_str: &str, id: Option<OpId>) {
         let id = id.unwrap_or_else(|| self.next_id());
+        if let Some(history) = &mut self.history {
+            history.record_delete_table(id, table_str);
+        }
+
+        let track = self.tracks_changes(table_str);
         let table = if let Some(table) = self.tables.get_mut(table_str) {
             table
         } else {
@@ -184,9 +470,75 @@ impl LwwDb {
             self.tables.get_mut(table_str).unwrap()
         };
 
+        let before: Option<Vec<_>> = track.then(|| {
+            table
+                .rows
+                .iter()
+                .map(|r| (r.row_id.clone(), table.live_row_values(&r.row_id)))
+                .collect()
+        });
         if table.delete_table(id) {
-            self.oplog.record_delete_table(id, table_str.into())
+            self.oplog.record_delete_table(id, table_str.into());
+            if let Some(before) = before {
+                for (row, values) in before {
+                    self.emit_row_diff(table_str, &row, values);
+                }
+
+                self.observer.emit(Event::DeleteTable {
+                    table: table_str.into(),
+                });
+            }
+        }
+    }
+
+    /// Index the values of the column, so the rows can be looked up by value
+    /// with [LwwDb::find_rows] and [LwwDb::find_rows_in_range].
+    ///
+    /// The index is kept up to date by every change to the table, and is
+    /// saved in the snapshot.
+    pub fn create_index(&mut self, table_str: &str, col: &str, kind: IndexKind) {
+        if !self.tables.contains_key(table_str) {
+            self.create_table(table_str);
         }
+
+        self.tables
+            .get_mut(table_str)
+            .unwrap()
+            .create_index(col, kind);
+    }
+
+    pub fn drop_index(&mut self, table_str: &str, col: &str) -> bool {
+        self.tables
+            .get_mut(table_str)
+            .is_some_and(|t| t.drop_index(col))
+    }
+
+    /// The ids of the rows whose value in the column equals `value`. An
+    /// [Value::I64] never equals a [Value::Double], so `2` doesn't find `2.0`.
+    ///
+    /// It returns [LwwError::IndexUnavailable] if the column is not indexed.
+    pub fn find_rows(&self, table_str: &str, col: &str, value: &Value) -> LwwResult<Vec<&SmolStr>> {
+        self.tables
+            .get(table_str)
+            .ok_or(LwwError::IndexUnavailable)?
+            .find_rows(col, value)
+    }
+
+    /// The ids of the rows whose value in the column is in the range, ordered
+    /// by value
+    ///
+    /// It returns [LwwError::IndexUnavailable] if the column doesn't have an
+    /// [IndexKind::Ordered] index.
+    pub fn find_rows_in_range(
+        &self,
+        table_str: &str,
+        col: &str,
+        range: impl std::ops::RangeBounds<Value>,
+    ) -> LwwResult<Vec<&SmolStr>> {
+        self.tables
+            .get(table_str)
+            .ok_or(LwwError::IndexUnavailable)?
+            .find_rows_in_range(col, range)
     }
 
     pub fn iter_tables(&self) -> impl Iterator<Item = (&SmolStr, &LwwTable)> {
@@ -209,8 +561,84 @@ impl LwwDb {
         self.tables.insert(name.into(), LwwTable::new());
     }
 
-    pub fn subscribe(&mut self, _listener: Box<dyn Fn(&Event)>) {
-        todo!()
+    /// Register a listener that is called after every local or imported
+    /// change that is visible in the tables.
+    pub fn subscribe(&mut self, listener: Listener) -> SubscriptionId {
+        self.observer.subscribe(listener)
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.observer.unsubscribe(id)
+    }
+
+    /// Compare the row with its values before the op and emit the event
+    fn emit_row_diff(&mut self, table_str: &str, row: &str, before: Option<Vec<(SmolStr, Value)>>) {
+        let after = self
+            .tables
+            .get(table_str)
+            .and_then(|t| t.live_row_values(row));
+        self.update_views(table_str, row, before.as_deref(), after.as_deref());
+        if self.observer.is_empty() {
+            return;
+        }
+
+        // the row is deleted only if it's gone, a deletion that is older than
+        // some of its values only clears the other values
+        let deleted = before.is_some() && after.is_none();
+        let (before, after) = (before.unwrap_or_default(), after.unwrap_or_default());
+
+        let mut partial_data = Vec::new();
+        for (col, old) in before.iter() {
+            let new = after
+                .iter()
+                .find(|(c, _)| c == col)
+                .map(|(_, v)| v.clone())
+                .unwrap_or(Value::Null);
+            if *old != new {
+                partial_data.push(CellChange {
+                    col: col.clone(),
+                    old: old.clone(),
+                    new,
+                });
+            }
+        }
+
+        for (col, new) in after.iter() {
+            if !before.iter().any(|(c, _)| c == col) {
+                partial_data.push(CellChange {
+                    col: col.clone(),
+                    old: Value::Null,
+                    new: new.clone(),
+                });
+            }
+        }
+
+        if partial_data.is_empty() {
+            return;
+        }
+
+        let table = SmolStr::new(table_str);
+        let row_id = SmolStr::new(row);
+        let event = if deleted {
+            Event::Delete {
+                table,
+                row_id,
+                partial_data,
+            }
+        } else if before.is_empty() {
+            Event::Insert {
+                table,
+                row_id,
+                partial_data,
+            }
+        } else {
+            Event::Update {
+                table,
+                row_id,
+                partial_data,
+            }
+        };
+        self.observer.emit(event);
     }
 
     pub fn version(&self) -> &VectorClock {
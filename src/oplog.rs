@@ -1,4 +1,7 @@
-use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
 use fxhash::{FxHashMap, FxHashSet};
 use smol_str::SmolStr;
@@ -9,21 +12,48 @@ use crate::clock::{Lamport, OpId, Peer, VectorClock};
 pub(crate) struct OpLog {
     str_pool: FxHashSet<Arc<str>>,
     map: FxHashMap<Peer, BTreeMap<Lamport, Op>>,
+    /// The lamport ranges of the transactions of each peer, start -> end (inclusive)
+    txns: FxHashMap<Peer, BTreeMap<Lamport, Lamport>>,
+    /// The version, which includes the applied ops up to the first missing op
+    /// of each peer
     vector_clock: VectorClock,
+    /// The max ids of the applied ops of each peer
+    applied: VectorClock,
+    /// The ops that were received but not applied, e.g. because they were
+    /// denied, so they are received again in the next sync
+    missing: FxHashMap<Peer, BTreeSet<Lamport>>,
     max_lamport: Lamport,
+    /// The version of the shallow snapshot the log starts from. The ops
+    /// included in it are not in the log.
+    shallow_since: Option<VectorClock>,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Op {
-    Update { table: Arc<str>, row: Arc<str> },
-    DeleteTable { table: Arc<str> },
-    DeleteRow { table: Arc<str>, row: Arc<str> },
+    Update {
+        table: Arc<str>,
+        row: Arc<str>,
+        col: Arc<str>,
+    },
+    DeleteTable {
+        table: Arc<str>,
+    },
+    DeleteRow {
+        table: Arc<str>,
+        row: Arc<str>,
+    },
+    /// Set the table definition, or the column definition if `col` is given
+    Define {
+        table: Arc<str>,
+        col: Option<Arc<str>>,
+    },
 }
 
 #[derive(Default)]
 pub(crate) struct OpLogBuilder {
     str_pool: FxHashSet<Arc<str>>,
     ops: FxHashMap<Peer, Vec<(Lamport, Op)>>,
+    txns: FxHashMap<Peer, BTreeMap<Lamport, Lamport>>,
 }
 
 fn get_or_intern(pool: &mut FxHashSet<Arc<str>>, s: &str) -> Arc<str> {
@@ -37,13 +67,14 @@ fn get_or_intern(pool: &mut FxHashSet<Arc<str>>, s: &str) -> Arc<str> {
 }
 
 impl OpLogBuilder {
-    pub fn record_update(&mut self, id: OpId, table: SmolStr, row: SmolStr) {
+    pub fn record_update(&mut self, id: OpId, table: SmolStr, row: SmolStr, col: SmolStr) {
         let table = get_or_intern(&mut self.str_pool, &table);
         let row = get_or_intern(&mut self.str_pool, &row);
+        let col = get_or_intern(&mut self.str_pool, &col);
         self.ops
             .entry(id.peer)
             .or_default()
-            .push((id.lamport, Op::Update { table, row }));
+            .push((id.lamport, Op::Update { table, row, col }));
     }
 
     pub(crate) fn record_delete_row(&mut self, id: OpId, table: SmolStr, row: SmolStr) {
@@ -63,6 +94,19 @@ impl OpLogBuilder {
             .push((id.lamport, Op::DeleteTable { table }));
     }
 
+    pub(crate) fn record_define(&mut self, id: OpId, table: SmolStr, col: Option<SmolStr>) {
+        let table = get_or_intern(&mut self.str_pool, &table);
+        let col = col.map(|c| get_or_intern(&mut self.str_pool, &c));
+        self.ops
+            .entry(id.peer)
+            .or_default()
+            .push((id.lamport, Op::Define { table, col }));
+    }
+
+    pub(crate) fn record_txn(&mut self, peer: Peer, start: Lamport, end: Lamport) {
+        self.txns.entry(peer).or_default().insert(start, end);
+    }
+
     pub(crate) fn build(self) -> OpLog {
         let map: FxHashMap<Peer, BTreeMap<Lamport, Op>> = self
             .ops
@@ -81,65 +125,208 @@ impl OpLogBuilder {
 
         OpLog {
             str_pool: self.str_pool,
-            max_lamport: vv.iter().map(|(_, v)| *v).max().unwrap_or(0),
+            max_lamport: vv.values().copied().max().unwrap_or(0),
+            applied: vv.clone(),
             vector_clock: vv,
+            missing: Default::default(),
             map,
+            txns: self.txns,
+            shallow_since: None,
         }
     }
 }
 
 impl OpLog {
-    pub fn record_update(&mut self, id: OpId, table: SmolStr, row: SmolStr) {
+    /// Set the version, e.g. the version of a snapshot, which doesn't include
+    /// the ops that were received without their history. The log can have
+    /// ops that are not included in the version, which are not exported
+    /// until the version includes them.
+    pub(crate) fn set_version(&mut self, version: VectorClock) {
+        let max = version.values().copied().max().unwrap_or(0);
+        self.max_lamport = self.max_lamport.max(max);
+        let peers: Vec<Peer> = self.missing.keys().copied().collect();
+        self.vector_clock = version.clone();
+        self.applied = version;
+        for peer in peers {
+            self.update_version(peer);
+        }
+    }
+
+    /// Start the log from the version of a shallow snapshot. The ops
+    /// included in it are not in the log.
+    pub(crate) fn set_shallow(&mut self, frontier: VectorClock) {
+        self.set_version(frontier.clone());
+        self.shallow_since = Some(frontier);
+    }
+
+    pub(crate) fn shallow_since(&self) -> Option<&VectorClock> {
+        self.shallow_since.as_ref()
+    }
+
+    pub fn record_update(&mut self, id: OpId, table: SmolStr, row: SmolStr, col: SmolStr) {
         let table = get_or_intern(&mut self.str_pool, &table);
         let row = get_or_intern(&mut self.str_pool, &row);
-        let peer = id.peer;
-        let lamport = id.lamport;
-        self.max_lamport = self.max_lamport.max(lamport);
-        let map = self.map.entry(peer).or_default();
-        map.insert(lamport, Op::Update { table, row });
-        self.vector_clock.extend_to_include(id);
+        let col = get_or_intern(&mut self.str_pool, &col);
+        let map = self.map.entry(id.peer).or_default();
+        map.insert(id.lamport, Op::Update { table, row, col });
+        self.include(id);
     }
 
     pub(crate) fn record_delete_row(&mut self, id: OpId, table: SmolStr, row: SmolStr) {
         let table = get_or_intern(&mut self.str_pool, &table);
         let row = get_or_intern(&mut self.str_pool, &row);
-        let peer = id.peer;
-        let lamport = id.lamport;
-        self.max_lamport = self.max_lamport.max(lamport);
-        let map = self.map.entry(peer).or_default();
-        map.insert(lamport, Op::DeleteRow { table, row });
-        self.vector_clock.extend_to_include(id);
+        let map = self.map.entry(id.peer).or_default();
+        map.insert(id.lamport, Op::DeleteRow { table, row });
+        self.include(id);
     }
 
     pub(crate) fn record_delete_table(&mut self, id: OpId, table: SmolStr) {
         let table = get_or_intern(&mut self.str_pool, &table);
-        let peer = id.peer;
-        let lamport = id.lamport;
-        self.max_lamport = self.max_lamport.max(lamport);
-        let map = self.map.entry(peer).or_default();
-        map.insert(lamport, Op::DeleteTable { table });
-        self.vector_clock.extend_to_include(id);
+        let map = self.map.entry(id.peer).or_default();
+        map.insert(id.lamport, Op::DeleteTable { table });
+        self.include(id);
     }
 
-    pub(crate) fn next_lamport(&self) -> u32 {
-        self.max_lamport + 1
+    pub(crate) fn record_define(&mut self, id: OpId, table: SmolStr, col: Option<SmolStr>) {
+        let table = get_or_intern(&mut self.str_pool, &table);
+        let col = col.map(|c| get_or_intern(&mut self.str_pool, &c));
+        let map = self.map.entry(id.peer).or_default();
+        map.insert(id.lamport, Op::Define { table, col });
+        self.include(id);
     }
 
-    pub(crate) fn iter_from(
-        &self,
-        from: crate::clock::VectorClock,
-    ) -> impl Iterator<Item = (OpId, &Op)> + '_ {
-        self.map.iter().flat_map(move |(peer, map)| {
+    /// Include the received op in the version, even if it doesn't change the
+    /// db because it's overwritten or already received
+    pub(crate) fn include(&mut self, id: OpId) {
+        self.max_lamport = self.max_lamport.max(id.lamport);
+        self.applied.extend_to_include(id);
+        if let Some(missing) = self.missing.get_mut(&id.peer) {
+            missing.remove(&id.lamport);
+            if missing.is_empty() {
+                self.missing.remove(&id.peer);
+            }
+        }
+
+        self.update_version(id.peer);
+    }
+
+    /// Keep the received op that was not applied out of the version, so the
+    /// later ops of its peer don't include it. It's received again in the
+    /// next sync.
+    pub(crate) fn exclude(&mut self, id: OpId) {
+        if self.vector_clock.includes(id) {
+            return;
+        }
+
+        self.missing.entry(id.peer).or_default().insert(id.lamport);
+        self.update_version(id.peer);
+    }
+
+    fn update_version(&mut self, peer: Peer) {
+        let mut end = *self.applied.get(&peer).unwrap_or(&0);
+        if let Some(first) = self.missing.get(&peer).and_then(|m| m.first()) {
+            end = end.min(first - 1);
+        }
+
+        if end == 0 {
+            self.vector_clock.remove(&peer);
+        } else {
+            self.vector_clock.insert(peer, end);
+        }
+    }
+
+    /// Record that the ops of `peer` in `start..=end` belong to the same transaction
+    pub(crate) fn record_txn(&mut self, peer: Peer, start: Lamport, end: Lamport) {
+        self.txns.entry(peer).or_default().insert(start, end);
+    }
+
+    /// Iterate over the transactions that contain ops not included in `from`
+    pub(crate) fn iter_txns_from<'a>(
+        &'a self,
+        from: &'a VectorClock,
+    ) -> impl Iterator<Item = (Peer, Lamport, Lamport)> + 'a {
+        self.txns.iter().flat_map(move |(peer, txns)| {
             let start = *from.get(peer).unwrap_or(&0);
-            map.range((Bound::Excluded(start), Bound::Unbounded))
-                .map(move |(lamport, op)| {
-                    let id = OpId {
-                        peer: *peer,
-                        lamport: *lamport,
-                    };
-
-                    (id, op)
+            txns.iter()
+                .filter(move |(_, end)| **end > start)
+                .map(move |(s, e)| (*peer, *s, *e))
+        })
+    }
+
+    /// The transaction that contains the op, (start lamport, end lamport)
+    pub(crate) fn txn_of(&self, id: OpId) -> Option<(Lamport, Lamport)> {
+        txn_of(self.txns.get(&id.peer)?, id.lamport)
+    }
+
+    /// Remove the deletions and the transactions included in `stable`, and
+    /// the updates included in it whose rows don't exist anymore. Returns the
+    /// number of removed ops. The version is not changed.
+    pub(crate) fn gc(
+        &mut self,
+        stable: &VectorClock,
+        row_exists: impl Fn(&str, &str) -> bool,
+    ) -> usize {
+        let mut removed = 0;
+        for (peer, map) in self.map.iter_mut() {
+            let Some(end) = stable.get(peer) else {
+                continue;
+            };
+
+            let len = map.len();
+            let stale: Vec<Lamport> = map
+                .range(..=*end)
+                .filter(|(_, op)| match op {
+                    Op::DeleteRow { .. } | Op::DeleteTable { .. } => true,
+                    Op::Update { table, row, .. } => !row_exists(table, row),
+                    Op::Define { .. } => false,
                 })
+                .map(|(lamport, _)| *lamport)
+                .collect();
+            for lamport in stale {
+                map.remove(&lamport);
+            }
+
+            removed += len - map.len();
+        }
+
+        self.map.retain(|_, map| !map.is_empty());
+        for (peer, txns) in self.txns.iter_mut() {
+            if let Some(end) = stable.get(peer) {
+                txns.retain(|_, e| *e > *end);
+            }
+        }
+
+        self.txns.retain(|_, txns| !txns.is_empty());
+        removed
+    }
+
+    pub(crate) fn next_lamport(&self) -> u32 {
+        self.max_lamport + 1
+    }
+
+    /// Iterate over the ops that are not included in `from`, ordered by
+    /// lamport, so the ops of every peer are visited in order
+    pub(crate) fn iter_from(&self, from: &VectorClock) -> impl Iterator<Item = (OpId, &Op)> + '_ {
+        // the ops that are not included in the version are not exported
+        let mut peers: Vec<_> = self
+            .map
+            .iter()
+            .filter_map(|(peer, map)| {
+                let start = *from.get(peer).unwrap_or(&0);
+                let end = *self.vector_clock.get(peer).unwrap_or(&0);
+                let ops = (start < end).then(|| map.range(start + 1..=end))?;
+                Some((*peer, ops.peekable()))
+            })
+            .collect();
+        std::iter::from_fn(move || {
+            let (_, i) = peers
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, (peer, ops))| Some((OpId::new(*ops.peek()?.0, *peer), i)))
+                .min()?;
+            let (peer, ops) = &mut peers[i];
+            let (lamport, op) = ops.next()?;
+            Some((OpId::new(*lamport, *peer), op))
         })
     }
 
@@ -147,3 +334,12 @@ impl OpLog {
         &self.vector_clock
     }
 }
+
+/// Get the lamport range of the transaction that contains `lamport`
+pub(crate) fn txn_of(
+    txns: &BTreeMap<Lamport, Lamport>,
+    lamport: Lamport,
+) -> Option<(Lamport, Lamport)> {
+    let (start, end) = txns.range(..=lamport).next_back()?;
+    (*end >= lamport).then_some((*start, *end))
+}
@@ -0,0 +1,433 @@
+use std::{cmp::Ordering, ops::Bound};
+
+use smol_str::SmolStr;
+
+use crate::{
+    table::{compare_values, total_cmp, Index, LwwTable},
+    value::Value,
+    LwwDb,
+};
+
+mod aggregate;
+mod view;
+pub use aggregate::{Aggregate, AggregateRow};
+pub use view::{View, ViewDef};
+
+static NULL: Value = Value::Null;
+
+/// A condition on the value of a column
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Eq(SmolStr, Value),
+    Ne(SmolStr, Value),
+    Lt(SmolStr, Value),
+    Le(SmolStr, Value),
+    Gt(SmolStr, Value),
+    Ge(SmolStr, Value),
+    In(SmolStr, Vec<Value>),
+    IsNull(SmolStr),
+    /// The value is a string that starts with the prefix
+    Prefix(SmolStr, SmolStr),
+}
+
+impl Predicate {
+    pub fn eq(col: &str, value: impl Into<Value>) -> Self {
+        Predicate::Eq(col.into(), value.into())
+    }
+
+    pub fn ne(col: &str, value: impl Into<Value>) -> Self {
+        Predicate::Ne(col.into(), value.into())
+    }
+
+    pub fn lt(col: &str, value: impl Into<Value>) -> Self {
+        Predicate::Lt(col.into(), value.into())
+    }
+
+    pub fn le(col: &str, value: impl Into<Value>) -> Self {
+        Predicate::Le(col.into(), value.into())
+    }
+
+    pub fn gt(col: &str, value: impl Into<Value>) -> Self {
+        Predicate::Gt(col.into(), value.into())
+    }
+
+    pub fn ge(col: &str, value: impl Into<Value>) -> Self {
+        Predicate::Ge(col.into(), value.into())
+    }
+
+    pub fn is_in<V: Into<Value>>(col: &str, values: impl IntoIterator<Item = V>) -> Self {
+        Predicate::In(col.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn is_null(col: &str) -> Self {
+        Predicate::IsNull(col.into())
+    }
+
+    pub fn prefix(col: &str, prefix: &str) -> Self {
+        Predicate::Prefix(col.into(), prefix.into())
+    }
+
+    pub fn col(&self) -> &SmolStr {
+        match self {
+            Predicate::Eq(col, _)
+            | Predicate::Ne(col, _)
+            | Predicate::Lt(col, _)
+            | Predicate::Le(col, _)
+            | Predicate::Gt(col, _)
+            | Predicate::Ge(col, _)
+            | Predicate::In(col, _)
+            | Predicate::IsNull(col)
+            | Predicate::Prefix(col, _) => col,
+        }
+    }
+
+    /// The comparisons only match the values of the same type, so `lt(col, 1)`
+    /// doesn't match a string or a null. The numbers are compared by value,
+    /// but an [Value::I64] never equals a [Value::Double], like [Value::eq].
+    pub fn matches(&self, value: &Value) -> bool {
+        let cmp = |other: &Value| compare_values(value, other);
+        match self {
+            Predicate::Eq(_, v) => cmp(v) == Some(Ordering::Equal),
+            Predicate::Ne(_, v) => cmp(v) != Some(Ordering::Equal),
+            Predicate::Lt(_, v) => cmp(v) == Some(Ordering::Less),
+            Predicate::Le(_, v) => matches!(cmp(v), Some(Ordering::Less | Ordering::Equal)),
+            Predicate::Gt(_, v) => cmp(v) == Some(Ordering::Greater),
+            Predicate::Ge(_, v) => matches!(cmp(v), Some(Ordering::Greater | Ordering::Equal)),
+            Predicate::In(_, values) => values.iter().any(|v| cmp(v) == Some(Ordering::Equal)),
+            Predicate::IsNull(_) => matches!(value, Value::Null | Value::Deleted),
+            Predicate::Prefix(_, p) => matches!(value, Value::Str(s) if s.starts_with(p.as_str())),
+        }
+    }
+
+    /// The rows that may match the predicate, found by the index. Returns
+    /// None if the index can't be used for the predicate.
+    fn candidates<'a>(&'a self, index: &'a Index) -> Option<Vec<&'a SmolStr>> {
+        Some(match self {
+            Predicate::Eq(_, v) => index.get(v).collect(),
+            Predicate::In(_, values) => values.iter().flat_map(|v| index.get(v)).collect(),
+            Predicate::Lt(_, v) => index.range(..v.clone())?.collect(),
+            Predicate::Le(_, v) => index.range(..=v.clone())?.collect(),
+            Predicate::Gt(_, v) => index
+                .range((Bound::Excluded(v.clone()), Bound::Unbounded))?
+                .collect(),
+            Predicate::Ge(_, v) => index.range(v.clone()..)?.collect(),
+            Predicate::Prefix(_, p) => index.prefix(p)?.collect(),
+            Predicate::Ne(..) | Predicate::IsNull(_) => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// A query over the rows of a table, created by [LwwDb::query]
+///
+/// ```
+/// use lww_table::{LwwDb, Order, Predicate};
+///
+/// let mut db = LwwDb::new();
+/// db.set("people", "a", "name", "Alice");
+/// db.set("people", "a", "age", 30);
+/// db.set("people", "b", "name", "Bob");
+/// db.set("people", "b", "age", 20);
+/// let rows = db
+///     .query("people")
+///     .filter(Predicate::gt("age", 10))
+///     .select(&["name"])
+///     .order_by("age", Order::Asc)
+///     .limit(1)
+///     .run();
+/// assert_eq!(rows.len(), 1);
+/// assert_eq!(rows[0].get("name"), Some(&"Bob".into()));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Query<'a> {
+    table: Option<&'a LwwTable>,
+    filters: Vec<Predicate>,
+    select: Option<Vec<SmolStr>>,
+    order_by: Vec<(SmolStr, Order)>,
+    offset: usize,
+    limit: Option<usize>,
+    include_deleted: bool,
+}
+
+/// A row in the result of a [Query]
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryRow<'a> {
+    pub row_id: &'a SmolStr,
+    /// The selected columns in the order they are selected, or all the
+    /// non-null values ordered by column name if there is no projection
+    pub values: Vec<(SmolStr, &'a Value)>,
+}
+
+impl<'a> QueryRow<'a> {
+    pub fn get(&self, col: &str) -> Option<&'a Value> {
+        self.values.iter().find(|(c, _)| c == col).map(|(_, v)| *v)
+    }
+}
+
+impl<'a> Query<'a> {
+    /// Only keep the rows that match the predicate. The predicates are
+    /// combined with AND.
+    pub fn filter(mut self, predicate: Predicate) -> Self {
+        self.filters.push(predicate);
+        self
+    }
+
+    /// Only return the values of these columns
+    pub fn select(mut self, cols: &[&str]) -> Self {
+        self.select = Some(cols.iter().map(SmolStr::new).collect());
+        self
+    }
+
+    /// Order the rows by the column. It can be called multiple times, the
+    /// later columns break the ties of the earlier ones. In the ascending
+    /// order, null < bool < number < string.
+    pub fn order_by(mut self, col: &str, order: Order) -> Self {
+        self.order_by.push((col.into(), order));
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Include the rows deleted by [LwwDb::delete_row]. A deleted row that is
+    /// written after the deletion is never excluded.
+    pub fn include_deleted(mut self, include: bool) -> Self {
+        self.include_deleted = include;
+        self
+    }
+
+    /// The matching rows. Without [Query::order_by], they are in the order
+    /// of the table.
+    pub fn run(&self) -> Vec<QueryRow<'a>> {
+        let Some(table) = self.table else {
+            return Vec::new();
+        };
+
+        let value = |row: usize, col: &str| cell(table, row, col);
+        let mut rows = self.matching_rows(table);
+        if !self.order_by.is_empty() {
+            rows.sort_by(|&a, &b| {
+                self.order_by
+                    .iter()
+                    .map(|(col, order)| {
+                        let ord = total_cmp(value(a, col), value(b, col));
+                        match order {
+                            Order::Asc => ord,
+                            Order::Desc => ord.reverse(),
+                        }
+                    })
+                    .find(|ord| ord.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+
+        rows.into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|row| QueryRow {
+                row_id: &table.rows[row].row_id,
+                values: match &self.select {
+                    Some(cols) => cols
+                        .iter()
+                        .map(|col| (col.clone(), value(row, col)))
+                        .collect(),
+                    None => {
+                        let mut values: Vec<_> = table
+                            .cols
+                            .iter()
+                            .filter(|(_, c)| *c.value(row) != Value::Null)
+                            .map(|(col, c)| (col.clone(), c.value(row)))
+                            .collect();
+                        values.sort_by(|a, b| a.0.cmp(&b.0));
+                        values
+                    }
+                },
+            })
+            .collect()
+    }
+
+    /// The indexes of the rows that pass the filters, in the order of the table
+    fn matching_rows(&self, table: &LwwTable) -> Vec<usize> {
+        self.candidates(table)
+            .unwrap_or_else(|| (0..table.rows.len()).collect())
+            .into_iter()
+            .filter(|&row| self.include_deleted || !table.is_deleted(row))
+            .filter(|&row| {
+                self.filters
+                    .iter()
+                    .all(|p| p.matches(cell(table, row, p.col())))
+            })
+            .collect()
+    }
+
+    /// The row indexes found by the first predicate that can use an index,
+    /// in the order of the table
+    fn candidates(&self, table: &LwwTable) -> Option<Vec<usize>> {
+        let rows = self.filters.iter().find_map(|p| {
+            let index = table.indexes.get(p.col())?;
+            p.candidates(index)
+        })?;
+        let mut rows: Vec<usize> = rows
+            .into_iter()
+            .map(|row_id| table.row_id_to_idx[row_id])
+            .collect();
+        rows.sort_unstable();
+        rows.dedup();
+        Some(rows)
+    }
+}
+
+fn cell<'a>(table: &'a LwwTable, row: usize, col: &str) -> &'a Value {
+    table.cols.get(col).map_or(&NULL, |c| c.value(row))
+}
+
+impl LwwDb {
+    /// Start a query over the rows of the table. The query of a missing table
+    /// returns no rows.
+    pub fn query(&self, table_str: &str) -> Query<'_> {
+        Query {
+            table: self.tables.get(table_str),
+            filters: Vec::new(),
+            select: None,
+            order_by: Vec::new(),
+            offset: 0,
+            limit: None,
+            include_deleted: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::IndexKind;
+
+    use super::*;
+
+    fn ids(rows: Vec<QueryRow>) -> Vec<String> {
+        rows.into_iter().map(|r| r.row_id.to_string()).collect()
+    }
+
+    fn people() -> LwwDb {
+        let mut db = LwwDb::new();
+        for (row, name, age) in [
+            ("a", "Alice", 30),
+            ("b", "Bob", 20),
+            ("c", "Carol", 40),
+            ("d", "Alan", 20),
+        ] {
+            db.set("people", row, "name", name);
+            db.set("people", row, "age", age);
+        }
+        db.set("people", "e", "name", "Eve");
+        db
+    }
+
+    #[test]
+    fn filter_sort_and_page() {
+        let db = people();
+        let q = db.query("people");
+        assert_eq!(
+            ids(q.clone().filter(Predicate::eq("age", 20)).run()),
+            ["b", "d"]
+        );
+        assert_eq!(
+            ids(q.clone().filter(Predicate::ne("age", 20)).run()),
+            ["a", "c", "e"]
+        );
+        assert_eq!(
+            ids(q.clone().filter(Predicate::is_null("age")).run()),
+            ["e"]
+        );
+        assert_eq!(
+            ids(q.clone().filter(Predicate::prefix("name", "Al")).run()),
+            ["a", "d"]
+        );
+        assert_eq!(
+            ids(q
+                .clone()
+                .filter(Predicate::is_in("name", ["Bob", "Eve"]))
+                .run()),
+            ["b", "e"]
+        );
+        assert_eq!(
+            ids(q
+                .clone()
+                .filter(Predicate::gt("age", 10))
+                .filter(Predicate::lt("age", 40))
+                .order_by("age", Order::Desc)
+                .order_by("name", Order::Asc)
+                .run()),
+            ["a", "d", "b"]
+        );
+        assert_eq!(
+            ids(q
+                .clone()
+                .order_by("age", Order::Asc)
+                .offset(1)
+                .limit(2)
+                .run()),
+            ["b", "d"]
+        );
+
+        let rows = q
+            .select(&["age", "email"])
+            .filter(Predicate::eq("name", "Carol"))
+            .run();
+        assert_eq!(
+            rows[0].values,
+            vec![
+                ("age".into(), &Value::I64(40)),
+                ("email".into(), &Value::Null)
+            ]
+        );
+        assert!(db.query("missing").run().is_empty());
+    }
+
+    #[test]
+    fn use_index() {
+        let mut db = people();
+        let all = |db: &LwwDb, p: Predicate| ids(db.query("people").filter(p).run());
+        let preds = [
+            Predicate::eq("age", 20),
+            Predicate::is_in("age", [20, 40]),
+            Predicate::le("age", 30),
+            Predicate::gt("age", 20),
+            Predicate::ge("name", "Bob"),
+            Predicate::prefix("name", "Al"),
+        ];
+        let expected: Vec<_> = preds.iter().map(|p| all(&db, p.clone())).collect();
+        db.create_index("people", "age", IndexKind::Ordered);
+        db.create_index("people", "name", IndexKind::Ordered);
+        for (p, expected) in preds.iter().zip(&expected) {
+            assert_eq!(&all(&db, p.clone()), expected, "{:?}", p);
+        }
+
+        db.create_index("people", "name", IndexKind::Hash);
+        assert_eq!(all(&db, Predicate::prefix("name", "Al")), ["a", "d"]);
+    }
+
+    #[test]
+    fn exclude_deleted_rows() {
+        let mut db = people();
+        db.delete_row("people", "a");
+        db.delete_row("people", "b");
+        db.set("people", "b", "age", 21);
+        assert_eq!(ids(db.query("people").run()), ["b", "c", "d", "e"]);
+        assert_eq!(
+            ids(db.query("people").include_deleted(true).run()),
+            ["a", "b", "c", "d", "e"]
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use smol_str::SmolStr;
+
+use crate::{
+    table::{total_cmp, IndexKey, LwwTable},
+    value::Value,
+    LwwDb,
+};
+
+use super::Query;
+
+/// An aggregate function over the rows of a group
+///
+/// The null values are skipped. The numeric functions only use the
+/// [Value::I64] and [Value::Double] values:
+///
+/// - [Aggregate::Sum] is an [Value::I64] if all the values are [Value::I64]
+///   and the sum doesn't overflow, otherwise it's a [Value::Double]. The
+///   integers are summed exactly before the doubles are added.
+/// - [Aggregate::Avg] is always a [Value::Double]
+///
+/// They are [Value::Null] if there is no value to aggregate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Aggregate {
+    /// The number of rows
+    Count,
+    /// The number of non-null values of the column
+    CountValues(SmolStr),
+    Sum(SmolStr),
+    /// The smallest value by the order of [Query::order_by]
+    Min(SmolStr),
+    /// The largest value by the order of [Query::order_by]
+    Max(SmolStr),
+    Avg(SmolStr),
+}
+
+impl Aggregate {
+    pub fn count_values(col: &str) -> Self {
+        Aggregate::CountValues(col.into())
+    }
+
+    pub fn sum(col: &str) -> Self {
+        Aggregate::Sum(col.into())
+    }
+
+    pub fn min(col: &str) -> Self {
+        Aggregate::Min(col.into())
+    }
+
+    pub fn max(col: &str) -> Self {
+        Aggregate::Max(col.into())
+    }
+
+    pub fn avg(col: &str) -> Self {
+        Aggregate::Avg(col.into())
+    }
+
+    pub fn col(&self) -> Option<&str> {
+        match self {
+            Aggregate::Count => None,
+            Aggregate::CountValues(col)
+            | Aggregate::Sum(col)
+            | Aggregate::Min(col)
+            | Aggregate::Max(col)
+            | Aggregate::Avg(col) => Some(col),
+        }
+    }
+}
+
+/// A group in the result of an aggregation
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateRow {
+    /// The values of the group-by columns
+    pub group: Vec<Value>,
+    /// The results of the aggregates, in the order they are given
+    pub values: Vec<Value>,
+}
+
+/// The state of an aggregate. The counts and the sums can be updated in both
+/// directions, so [crate::ViewDef] can maintain them incrementally.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Acc<'a> {
+    /// The number of rows, or values that are aggregated
+    count: i64,
+    int_sum: i128,
+    double_sum: f64,
+    doubles: i64,
+    /// Only used by [LwwTable::aggregate], the views keep all the values
+    extreme: Option<&'a Value>,
+}
+
+impl<'a> Acc<'a> {
+    /// Add the value to the count and the sum
+    pub fn add(&mut self, agg: &Aggregate, value: &Value) {
+        self.update(agg, value, 1);
+    }
+
+    /// Remove the value from the count and the sum
+    pub fn remove(&mut self, agg: &Aggregate, value: &Value) {
+        self.update(agg, value, -1);
+    }
+
+    fn update(&mut self, agg: &Aggregate, value: &Value, sign: i64) {
+        match agg {
+            Aggregate::Count => self.count += sign,
+            Aggregate::CountValues(_) => {
+                if !matches!(value, Value::Null | Value::Deleted) {
+                    self.count += sign;
+                }
+            }
+            Aggregate::Sum(_) | Aggregate::Avg(_) => match value {
+                Value::I64(v) => {
+                    self.count += sign;
+                    self.int_sum += *v as i128 * sign as i128;
+                }
+                Value::Double(v) => {
+                    self.count += sign;
+                    self.doubles += sign;
+                    self.double_sum += v * sign as f64;
+                    if self.doubles == 0 {
+                        // drop the rounding errors
+                        self.double_sum = 0.0;
+                    }
+                }
+                _ => {}
+            },
+            Aggregate::Min(_) | Aggregate::Max(_) => {}
+        }
+    }
+
+    fn add_extreme(&mut self, agg: &Aggregate, value: &'a Value) {
+        if matches!(value, Value::Null | Value::Deleted) {
+            return;
+        }
+
+        let keep = if matches!(agg, Aggregate::Min(_)) {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        if self.extreme.is_none_or(|e| total_cmp(value, e) == keep) {
+            self.extreme = Some(value);
+        }
+    }
+
+    /// The result of the aggregate, `extreme` is the result of
+    /// [Aggregate::Min] or [Aggregate::Max]
+    pub fn finish(&self, agg: &Aggregate, extreme: Option<&Value>) -> Value {
+        match agg {
+            Aggregate::Count | Aggregate::CountValues(_) => Value::I64(self.count),
+            Aggregate::Min(_) | Aggregate::Max(_) => extreme.cloned().unwrap_or(Value::Null),
+            _ if self.count == 0 => Value::Null,
+            Aggregate::Sum(_) => match i64::try_from(self.int_sum) {
+                Ok(sum) if self.doubles == 0 => Value::I64(sum),
+                _ => Value::Double(self.int_sum as f64 + self.double_sum),
+            },
+            Aggregate::Avg(_) => {
+                Value::Double((self.int_sum as f64 + self.double_sum) / self.count as f64)
+            }
+        }
+    }
+}
+
+/// Aggregate the rows by the groups of the values of `group_by`. The groups
+/// are ordered by their values. Without `group_by`, there is always a single
+/// group, even if there is no row.
+fn aggregate_rows(
+    table: &LwwTable,
+    rows: impl Iterator<Item = usize>,
+    group_by: &[&str],
+    aggregates: &[Aggregate],
+) -> Vec<AggregateRow> {
+    let group_cols: Vec<_> = group_by.iter().map(|c| table.cols.get(*c)).collect();
+    let agg_cols: Vec<_> = aggregates
+        .iter()
+        .map(|a| a.col().and_then(|c| table.cols.get(c)))
+        .collect();
+    // keyed like the groups of the views, so every NaN and each zero sign
+    // is a single group
+    let mut groups: BTreeMap<Vec<IndexKey>, Vec<Acc>> = BTreeMap::new();
+    for row in rows {
+        let key: Vec<IndexKey> = group_cols
+            .iter()
+            .map(|c| IndexKey(c.map_or(&Value::Null, |c| c.value(row)).clone()))
+            .collect();
+        let accs = groups
+            .entry(key)
+            .or_insert_with(|| vec![Acc::default(); aggregates.len()]);
+        for ((acc, agg), col) in accs.iter_mut().zip(aggregates).zip(&agg_cols) {
+            let value = col.map_or(&Value::Null, |c| c.value(row));
+            acc.add(agg, value);
+            acc.add_extreme(agg, value);
+        }
+    }
+
+    if group_by.is_empty() && groups.is_empty() {
+        groups.insert(Vec::new(), vec![Acc::default(); aggregates.len()]);
+    }
+
+    groups
+        .into_iter()
+        .map(|(group, accs)| AggregateRow {
+            group: group.into_iter().map(|k| k.0).collect(),
+            values: accs
+                .iter()
+                .zip(aggregates)
+                .map(|(acc, agg)| acc.finish(agg, acc.extreme))
+                .collect(),
+        })
+        .collect()
+}
+
+impl LwwTable {
+    /// Aggregate the rows that are not deleted, grouped by the values of the
+    /// `group_by` columns. Without `group_by`, there is a single group of all
+    /// the rows.
+    ///
+    /// It reads the cells from the columns directly, without copying the rows.
+    pub fn aggregate(&self, group_by: &[&str], aggregates: &[Aggregate]) -> Vec<AggregateRow> {
+        let rows = (0..self.rows.len()).filter(|&row| !self.is_deleted(row));
+        aggregate_rows(self, rows, group_by, aggregates)
+    }
+}
+
+impl LwwDb {
+    /// See [LwwTable::aggregate]. It's empty if the table doesn't exist.
+    pub fn aggregate(
+        &self,
+        table_str: &str,
+        group_by: &[&str],
+        aggregates: &[Aggregate],
+    ) -> Vec<AggregateRow> {
+        self.tables
+            .get(table_str)
+            .map(|t| t.aggregate(group_by, aggregates))
+            .unwrap_or_default()
+    }
+}
+
+impl Query<'_> {
+    /// Aggregate the rows that pass the filters. The projection, the order
+    /// and the paging of the query are ignored.
+    pub fn aggregate(&self, group_by: &[&str], aggregates: &[Aggregate]) -> Vec<AggregateRow> {
+        let Some(table) = self.table else {
+            return Vec::new();
+        };
+
+        let rows = self.matching_rows(table);
+        aggregate_rows(table, rows.into_iter(), group_by, aggregates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Predicate, ViewDef};
+
+    #[test]
+    fn group_and_promote() {
+        let mut db = LwwDb::new();
+        for (row, user, amount) in [
+            ("1", "a", Value::I64(3)),
+            ("2", "a", Value::Double(1.5)),
+            ("3", "b", Value::I64(i64::MAX)),
+            ("4", "b", Value::I64(1)),
+            ("5", "c", Value::I64(2)),
+            ("6", "c", Value::I64(4)),
+            ("7", "c", Value::from("n/a")),
+        ] {
+            db.set("orders", row, "user", user);
+            db.set("orders", row, "amount", amount);
+        }
+        db.set("orders", "8", "amount", 10);
+        db.set("orders", "9", "user", "c");
+        db.delete_row("orders", "6");
+
+        let aggs = [
+            Aggregate::Count,
+            Aggregate::count_values("amount"),
+            Aggregate::sum("amount"),
+            Aggregate::min("amount"),
+            Aggregate::max("amount"),
+            Aggregate::avg("amount"),
+        ];
+        let rows = db.aggregate("orders", &["user"], &aggs);
+        let group = |g: &str| -> Vec<Value> {
+            rows.iter()
+                .find(|r| r.group == [g.into()])
+                .unwrap()
+                .values
+                .clone()
+        };
+        assert_eq!(rows[0].group, vec![Value::Null]);
+        assert_eq!(rows[0].values[2], Value::I64(10));
+        assert_eq!(
+            group("a"),
+            vec![
+                2.into(),
+                2.into(),
+                4.5.into(),
+                1.5.into(),
+                3.into(),
+                2.25.into()
+            ]
+        );
+        assert_eq!(group("b")[2], Value::Double(i64::MAX as f64 + 1.0));
+        // the deleted row is skipped, and the string is only used by min/max
+        assert_eq!(
+            group("c"),
+            vec![
+                3.into(),
+                2.into(),
+                2.into(),
+                2.into(),
+                "n/a".into(),
+                2.0.into()
+            ]
+        );
+
+        assert_eq!(
+            db.aggregate("orders", &[], &[Aggregate::Count])[0].values,
+            vec![Value::I64(8)]
+        );
+        let rows = db
+            .query("orders")
+            .filter(Predicate::eq("user", "a"))
+            .aggregate(&[], &[Aggregate::sum("amount"), Aggregate::avg("user")]);
+        assert_eq!(
+            rows,
+            vec![AggregateRow {
+                group: vec![],
+                values: vec![4.5.into(), Value::Null]
+            }]
+        );
+        assert_eq!(
+            db.query("orders")
+                .filter(Predicate::eq("user", "x"))
+                .aggregate(&[], &[Aggregate::Count, Aggregate::sum("amount")])[0]
+                .values,
+            vec![0.into(), Value::Null]
+        );
+    }
+
+    #[test]
+    fn group_nan_and_zeros() {
+        let mut db = LwwDb::new();
+        for (row, k) in [
+            ("1", f64::NAN),
+            ("2", 0.0),
+            ("3", f64::NAN),
+            ("4", -0.0),
+            ("5", 0.0),
+        ] {
+            db.set("t", row, "k", k);
+        }
+
+        db.create_view(
+            "v",
+            "t",
+            ViewDef::new().group_by(&["k"]).aggregate(Aggregate::Count),
+        );
+        let counts = |rows: Vec<AggregateRow>| -> Vec<(u64, Value)> {
+            rows.into_iter()
+                .map(|r| match r.group[..] {
+                    [Value::Double(k)] => (k.to_bits(), r.values[0].clone()),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        let expected = vec![
+            ((-0.0f64).to_bits(), 1.into()),
+            (0.0f64.to_bits(), 2.into()),
+            (f64::NAN.to_bits(), 2.into()),
+        ];
+        assert_eq!(
+            counts(db.aggregate("t", &["k"], &[Aggregate::Count])),
+            expected
+        );
+        assert_eq!(counts(db.view("v").unwrap().groups()), expected);
+    }
+}
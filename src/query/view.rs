@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use smol_str::SmolStr;
+
+use crate::{table::IndexKey, value::Value, LwwDb};
+
+use super::{aggregate::Acc, Aggregate, AggregateRow, Predicate};
+
+/// The definition of a materialized view over a table
+///
+/// The view keeps the ids of the rows that pass the filters. If it has
+/// group-by columns or aggregates, it also keeps the aggregates of every
+/// group, like [crate::Query::aggregate].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ViewDef {
+    filters: Vec<Predicate>,
+    group_by: Vec<SmolStr>,
+    aggregates: Vec<Aggregate>,
+}
+
+impl ViewDef {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the rows that match the predicate. The predicates are
+    /// combined with AND.
+    pub fn filter(mut self, predicate: Predicate) -> Self {
+        self.filters.push(predicate);
+        self
+    }
+
+    pub fn group_by(mut self, cols: &[&str]) -> Self {
+        self.group_by = cols.iter().map(SmolStr::new).collect();
+        self
+    }
+
+    pub fn aggregate(mut self, aggregate: Aggregate) -> Self {
+        self.aggregates.push(aggregate);
+        self
+    }
+}
+
+/// A view that is updated by every change to its table, see
+/// [LwwDb::create_view]
+#[derive(Debug, Clone)]
+pub struct View {
+    table: SmolStr,
+    def: ViewDef,
+    rows: BTreeSet<SmolStr>,
+    groups: BTreeMap<Vec<IndexKey>, Group>,
+}
+
+#[derive(Debug, Clone)]
+struct Group {
+    rows: usize,
+    accs: Vec<Acc<'static>>,
+    /// The values of the [Aggregate::Min] and [Aggregate::Max] columns, with
+    /// their numbers of occurrences
+    values: Vec<BTreeMap<IndexKey, usize>>,
+}
+
+impl View {
+    pub fn table(&self) -> &SmolStr {
+        &self.table
+    }
+
+    pub fn def(&self) -> &ViewDef {
+        &self.def
+    }
+
+    /// The ids of the rows that pass the filters, ordered by id
+    pub fn rows(&self) -> impl Iterator<Item = &SmolStr> {
+        self.rows.iter()
+    }
+
+    pub fn contains(&self, row: &str) -> bool {
+        self.rows.contains(row)
+    }
+
+    /// The aggregates of the groups, ordered by the group values. It's the
+    /// same as the result of [crate::Query::aggregate] with the filters,
+    /// except that the sums and the averages of [Value::Double] values are
+    /// updated incrementally, so they can differ by rounding errors.
+    pub fn groups(&self) -> Vec<AggregateRow> {
+        let aggregates = &self.def.aggregates;
+        let mut ans: Vec<_> = self
+            .groups
+            .iter()
+            .map(|(key, group)| AggregateRow {
+                group: key.iter().map(|k| k.0.clone()).collect(),
+                values: aggregates
+                    .iter()
+                    .zip(&group.accs)
+                    .zip(&group.values)
+                    .map(|((agg, acc), values)| {
+                        let extreme = match agg {
+                            Aggregate::Min(_) => values.keys().next(),
+                            _ => values.keys().next_back(),
+                        };
+                        acc.finish(agg, extreme.map(|k| &k.0))
+                    })
+                    .collect(),
+            })
+            .collect();
+        // the groups are already ordered, like the result of the query
+        if ans.is_empty() && self.def.group_by.is_empty() {
+            ans.push(AggregateRow {
+                group: Vec::new(),
+                values: aggregates
+                    .iter()
+                    .map(|agg| Acc::default().finish(agg, None))
+                    .collect(),
+            });
+        }
+
+        ans
+    }
+
+    /// Move the row from its old values to its new values. The values are
+    /// None if the row doesn't exist or is deleted, like in a [crate::Query].
+    pub(crate) fn apply_row_change(
+        &mut self,
+        row: &str,
+        before: Option<&[(SmolStr, Value)]>,
+        after: Option<&[(SmolStr, Value)]>,
+    ) {
+        if let Some(before) = before.filter(|v| self.matches(v)) {
+            self.rows.remove(row);
+            self.update_group(before, false);
+        }
+
+        if let Some(after) = after.filter(|v| self.matches(v)) {
+            self.rows.insert(row.into());
+            self.update_group(after, true);
+        }
+    }
+
+    fn matches(&self, values: &[(SmolStr, Value)]) -> bool {
+        self.def
+            .filters
+            .iter()
+            .all(|p| p.matches(get(values, p.col())))
+    }
+
+    fn update_group(&mut self, values: &[(SmolStr, Value)], add: bool) {
+        if self.def.group_by.is_empty() && self.def.aggregates.is_empty() {
+            return;
+        }
+
+        let key: Vec<_> = self
+            .def
+            .group_by
+            .iter()
+            .map(|c| IndexKey(get(values, c).clone()))
+            .collect();
+        let aggregates = &self.def.aggregates;
+        let group = self.groups.entry(key.clone()).or_insert_with(|| Group {
+            rows: 0,
+            accs: vec![Acc::default(); aggregates.len()],
+            values: vec![BTreeMap::new(); aggregates.len()],
+        });
+        for ((agg, acc), extremes) in aggregates
+            .iter()
+            .zip(&mut group.accs)
+            .zip(&mut group.values)
+        {
+            let value = agg.col().map_or(&Value::Null, |c| get(values, c));
+            if add {
+                acc.add(agg, value);
+            } else {
+                acc.remove(agg, value);
+            }
+
+            if matches!(agg, Aggregate::Min(_) | Aggregate::Max(_))
+                && !matches!(value, Value::Null | Value::Deleted)
+            {
+                let key = IndexKey(value.clone());
+                if add {
+                    *extremes.entry(key).or_default() += 1;
+                } else if let Some(n) = extremes.get_mut(&key) {
+                    *n -= 1;
+                    if *n == 0 {
+                        extremes.remove(&key);
+                    }
+                }
+            }
+        }
+
+        if add {
+            group.rows += 1;
+        } else {
+            group.rows -= 1;
+            if group.rows == 0 {
+                self.groups.remove(&key);
+            }
+        }
+    }
+}
+
+fn get<'a>(values: &'a [(SmolStr, Value)], col: &str) -> &'a Value {
+    values
+        .iter()
+        .find(|(c, _)| c == col)
+        .map_or(&Value::Null, |(_, v)| v)
+}
+
+impl LwwDb {
+    /// Create a view over the table. It's computed from the current rows and
+    /// then updated incrementally by every change to the table, including the
+    /// imported ones. It replaces the existing view with the same name.
+    ///
+    /// The views are local, they are not synced or saved in the snapshot.
+    pub fn create_view(&mut self, name: &str, table_str: &str, def: ViewDef) {
+        let mut view = View {
+            table: table_str.into(),
+            def,
+            rows: BTreeSet::new(),
+            groups: BTreeMap::new(),
+        };
+        if let Some(table) = self.tables.get(table_str) {
+            for row in table.rows.iter() {
+                let values = table.live_row_values(&row.row_id);
+                view.apply_row_change(&row.row_id, None, values.as_deref());
+            }
+        }
+
+        self.views.insert(name.into(), view);
+    }
+
+    pub fn drop_view(&mut self, name: &str) -> bool {
+        self.views.remove(name).is_some()
+    }
+
+    pub fn view(&self, name: &str) -> Option<&View> {
+        self.views.get(name)
+    }
+
+    /// Whether the changes to the table need to be diffed, for the listeners
+    /// or the views
+    pub(crate) fn tracks_changes(&self, table_str: &str) -> bool {
+        !self.observer.is_empty() || self.views.values().any(|v| v.table == table_str)
+    }
+
+    pub(crate) fn update_views(
+        &mut self,
+        table_str: &str,
+        row: &str,
+        before: Option<&[(SmolStr, Value)]>,
+        after: Option<&[(SmolStr, Value)]>,
+    ) {
+        for view in self.views.values_mut() {
+            if view.table == table_str {
+                view.apply_row_change(row, before, after);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(db: &LwwDb) {
+        let view = db.view("totals").unwrap();
+        let expected = db
+            .query("orders")
+            .filter(Predicate::ne("status", "cancelled"))
+            .aggregate(
+                &["user"],
+                &[
+                    Aggregate::Count,
+                    Aggregate::sum("amount"),
+                    Aggregate::max("amount"),
+                ],
+            );
+        assert_eq!(view.groups(), expected);
+    }
+
+    #[test]
+    fn incremental_view() {
+        let mut db = LwwDb::new();
+        db.set("orders", "1", "user", "a");
+        db.set("orders", "1", "amount", 10);
+        db.create_view(
+            "totals",
+            "orders",
+            ViewDef::new()
+                .filter(Predicate::ne("status", "cancelled"))
+                .group_by(&["user"])
+                .aggregate(Aggregate::Count)
+                .aggregate(Aggregate::sum("amount"))
+                .aggregate(Aggregate::max("amount")),
+        );
+        check(&db);
+        db.set("orders", "2", "user", "a");
+        db.set("orders", "2", "amount", 2.5);
+        db.set("orders", "3", "user", "b");
+        db.set("orders", "3", "amount", 7);
+        check(&db);
+        db.set("orders", "1", "status", "cancelled");
+        check(&db);
+        assert!(!db.view("totals").unwrap().contains("1"));
+        db.set("orders", "3", "user", "a");
+        db.delete_row("orders", "2");
+        check(&db);
+        assert_eq!(
+            db.view("totals").unwrap().groups(),
+            vec![AggregateRow {
+                group: vec!["a".into()],
+                values: vec![1.into(), 7.into(), 7.into()]
+            }]
+        );
+
+        let mut other = LwwDb::new();
+        other.set_peer(2);
+        other.set("orders", "4", "user", "b");
+        other.set("orders", "4", "amount", 1);
+        db.import_updates(&other.export_updates(Default::default()));
+        check(&db);
+        // a row whose values are deleted is still in the query
+        db.set("orders", "5", "user", "c");
+        db.delete("orders", "5", "user");
+        check(&db);
+        assert!(db.view("totals").unwrap().contains("5"));
+        db.create_view("all", "orders", ViewDef::new());
+        assert!(db.view("all").unwrap().contains("5"));
+        db.delete_row("orders", "5");
+        check(&db);
+        assert!(!db.view("all").unwrap().contains("5"));
+
+        db.delete_table("orders");
+        check(&db);
+        assert!(db.view("totals").unwrap().rows().next().is_none());
+    }
+}
@@ -0,0 +1,413 @@
+use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    clock::OpId,
+    error::{LwwError, LwwResult},
+    LwwDb,
+};
+
+/// The number of sub-ranges a range that differs is split into
+const BRANCH: usize = 16;
+/// The ranges with at most this many items are listed instead of split
+const MAX_ITEMS: usize = 16;
+
+/// The key of an item. The row and the column are None for the tombstones.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub(crate) struct ItemKey {
+    pub table: SmolStr,
+    pub row: Option<SmolStr>,
+    pub col: Option<SmolStr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Fingerprint {
+    /// The wrapping sum of the hashes of the items
+    sum: u128,
+    count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum RangeMode {
+    /// The range is already reconciled
+    Skip,
+    Fingerprint(Fingerprint),
+    /// All the items of the sender in the range, with their hashes
+    Items(Vec<(ItemKey, u128)>),
+}
+
+/// The keys from the upper bound of the previous range, or the start, to
+/// `upper` (exclusive). None is the end.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Range {
+    upper: Option<ItemKey>,
+    mode: RangeMode,
+}
+
+/// A message of the reconciliation, see [LwwDb::reconcile]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReconcileMessage {
+    /// The contiguous ranges from the start. The keys after the last range
+    /// are already reconciled.
+    ranges: Vec<Range>,
+    /// The items the receiver should send
+    want: Vec<ItemKey>,
+    /// The items that the receiver doesn't have, exported as updates
+    updates: Option<Vec<u8>>,
+}
+
+impl ReconcileMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).unwrap()
+    }
+
+    pub fn try_decode(encoded: &[u8]) -> LwwResult<Self> {
+        Ok(postcard::from_bytes(encoded)?)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.want.is_empty() && self.updates.is_none()
+    }
+
+    /// Add the range `lower..upper`, after skipping the keys since the last
+    /// range
+    fn push_range(&mut self, lower: Option<&ItemKey>, upper: Option<&ItemKey>, mode: RangeMode) {
+        let end = self.ranges.last().and_then(|r| r.upper.as_ref());
+        if lower != end {
+            self.ranges.push(Range {
+                upper: lower.cloned(),
+                mode: RangeMode::Skip,
+            });
+        }
+
+        self.ranges.push(Range {
+            upper: upper.cloned(),
+            mode,
+        });
+    }
+}
+
+fn hash_item(key: &ItemKey, ids: impl IntoIterator<Item = (OpId, i64)>) -> u128 {
+    let mut hasher = Xxh3::new();
+    for s in [Some(&key.table), key.row.as_ref(), key.col.as_ref()] {
+        match s {
+            Some(s) => {
+                hasher.update(&(s.len() as u64).to_le_bytes());
+                hasher.update(s.as_bytes());
+            }
+            None => hasher.update(&u64::MAX.to_le_bytes()),
+        }
+    }
+
+    for (id, total) in ids {
+        hasher.update(&id.peer.to_le_bytes());
+        hasher.update(&id.lamport.to_le_bytes());
+        hasher.update(&total.to_le_bytes());
+    }
+
+    hasher.digest128()
+}
+
+fn fingerprint(items: &[(ItemKey, u128)]) -> Fingerprint {
+    Fingerprint {
+        sum: items.iter().fold(0, |sum, (_, h)| sum.wrapping_add(*h)),
+        count: items.len() as u64,
+    }
+}
+
+impl LwwDb {
+    /// The items of the db with their hashes, ordered by key
+    fn reconcile_items(&self) -> Vec<(ItemKey, u128)> {
+        let mut items = Vec::new();
+        for (name, table) in self.tables.iter() {
+            let key = |row: Option<&SmolStr>, col: Option<&SmolStr>| ItemKey {
+                table: name.clone(),
+                row: row.cloned(),
+                col: col.cloned(),
+            };
+            if let Some(id) = table.removed {
+                let key = key(None, None);
+                let hash = hash_item(&key, [(id, 0)]);
+                items.push((key, hash));
+            }
+
+            for row in table.rows.iter() {
+                if let Some(id) = row.deleted {
+                    let key = key(Some(&row.row_id), None);
+                    let hash = hash_item(&key, [(id, 0)]);
+                    items.push((key, hash));
+                }
+            }
+
+            for (col_name, col) in table.cols.iter() {
+                for (i, _, id) in col.iter() {
+                    let row = &table.rows[i].row_id;
+                    let key = key(Some(row), Some(col_name));
+                    let hash = if let Some(reg) = table.mv_register(row, col_name) {
+                        let ids = reg.values().iter().map(|v| v.id);
+                        let ids = ids.chain(reg.superseded().map(|(id, _)| id));
+                        hash_item(&key, ids.map(|id| (id, 0)))
+                    } else {
+                        let counter = table.counter(row, col_name);
+                        let entries = counter.into_iter().flat_map(|c| c.iter());
+                        hash_item(&key, [(id, 0)].into_iter().chain(entries))
+                    };
+                    items.push((key, hash));
+                }
+            }
+        }
+
+        items.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        items
+    }
+
+    /// The first message of a reconciliation with a peer. Send it to the
+    /// peer, which replies with [LwwDb::reconcile].
+    pub fn start_reconcile(&self) -> ReconcileMessage {
+        let items = self.reconcile_items();
+        ReconcileMessage {
+            ranges: vec![Range {
+                upper: None,
+                mode: RangeMode::Fingerprint(fingerprint(&items)),
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Handle a message of the reconciliation started by
+    /// [LwwDb::start_reconcile], and return the reply to the peer. The two
+    /// dbs have the same items when there is no reply.
+    ///
+    /// The peers compare the fingerprints of the key ranges of their cells,
+    /// row tombstones and table tombstones, and split the ranges that differ
+    /// until they are small enough to list. The differences are found in
+    /// about log16(n) round trips, and only the latest ops of the items that
+    /// differ are sent, so the cost doesn't depend on the history. The
+    /// schemas are not reconciled, they are synced by [LwwDb::export_updates].
+    ///
+    /// The received ops don't change the version, because their history is
+    /// not received, so they are sent again by a later
+    /// [LwwDb::export_updates] from the version. They are not exported by
+    /// this db until its version includes them.
+    pub fn reconcile(&mut self, msg: &ReconcileMessage) -> LwwResult<Option<ReconcileMessage>> {
+        if let Some(updates) = &msg.updates {
+            // The ops before the received ones may still be missing, so the
+            // version is not changed. They are included in the version when
+            // they are received by LwwDb::import_updates.
+            let version = self.version().clone();
+            self.try_import_updates(updates)?;
+            self.oplog.set_version(version);
+        }
+
+        if msg.ranges.is_empty() && msg.want.is_empty() {
+            return Ok(None);
+        }
+
+        let items = self.reconcile_items();
+        let mut reply = ReconcileMessage::default();
+        let mut to_send = msg.want.clone();
+        let mut next_lower: Option<&ItemKey> = None;
+        for (i, range) in msg.ranges.iter().enumerate() {
+            let upper = range.upper.as_ref();
+            let lower = std::mem::replace(&mut next_lower, upper);
+            let start = lower.map_or(0, |l| items.partition_point(|(k, _)| k < l));
+            let end = upper.map_or(items.len(), |u| items.partition_point(|(k, _)| k < u));
+            if start > end || (upper.is_none() && i + 1 < msg.ranges.len()) {
+                return Err(LwwError::InvalidOp("invalid reconciliation range"));
+            }
+
+            let mine = &items[start..end];
+            match &range.mode {
+                RangeMode::Skip => {}
+                RangeMode::Fingerprint(theirs) => {
+                    if fingerprint(mine) == *theirs {
+                        continue;
+                    }
+
+                    if mine.len() <= MAX_ITEMS {
+                        reply.push_range(lower, upper, RangeMode::Items(mine.to_vec()));
+                    } else {
+                        let chunks: Vec<_> = mine.chunks(mine.len().div_ceil(BRANCH)).collect();
+                        for (i, chunk) in chunks.iter().enumerate() {
+                            let chunk_lower = if i == 0 { lower } else { Some(&chunk[0].0) };
+                            let chunk_upper = chunks.get(i + 1).map_or(upper, |c| Some(&c[0].0));
+                            let fingerprint = RangeMode::Fingerprint(fingerprint(chunk));
+                            reply.push_range(chunk_lower, chunk_upper, fingerprint);
+                        }
+                    }
+                }
+                RangeMode::Items(theirs) => {
+                    let theirs_map: FxHashMap<&ItemKey, u128> =
+                        theirs.iter().map(|(k, h)| (k, *h)).collect();
+                    let mine_map: FxHashMap<&ItemKey, u128> =
+                        mine.iter().map(|(k, h)| (k, *h)).collect();
+                    for (key, hash) in mine {
+                        if theirs_map.get(key) != Some(hash) {
+                            to_send.push(key.clone());
+                        }
+                    }
+
+                    for (key, hash) in theirs {
+                        if mine_map.get(key) != Some(hash) {
+                            reply.want.push(key.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        if !to_send.is_empty() {
+            reply.updates = Some(self.export_items(&to_send));
+        }
+
+        Ok((!reply.is_empty()).then_some(reply))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::value::Value;
+
+    use super::*;
+
+    /// Reconcile the two dbs, and return the number of messages and the
+    /// bytes sent
+    fn run(a: &mut LwwDb, b: &mut LwwDb) -> (usize, usize) {
+        run_until(a, b, usize::MAX)
+    }
+
+    /// Reconcile the two dbs, stopping after `max_messages` messages
+    fn run_until(a: &mut LwwDb, b: &mut LwwDb, max_messages: usize) -> (usize, usize) {
+        let mut msg = a.start_reconcile().encode();
+        let (mut messages, mut bytes) = (0, 0);
+        let mut to_b = true;
+        loop {
+            if messages == max_messages {
+                return (messages, bytes);
+            }
+
+            messages += 1;
+            bytes += msg.len();
+            let db = if to_b { &mut *b } else { &mut *a };
+            let decoded = ReconcileMessage::try_decode(&msg).unwrap();
+            match db.reconcile(&decoded).unwrap() {
+                Some(reply) => msg = reply.encode(),
+                None => return (messages, bytes),
+            }
+            to_b = !to_b;
+        }
+    }
+
+    #[test]
+    fn reconcile_small_difference() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        for i in 0..10000 {
+            a.set("table", &i.to_string(), "x", i);
+            a.set("table", &i.to_string(), "name", format!("row {}", i));
+        }
+        a.increment("table", "1", "n", 1);
+        let mut b = LwwDb::from_snapshot(&a.export_snapshot());
+        b.set_peer(2);
+        let full = a.export_updates(Default::default()).len();
+
+        a.set("table", "10", "x", "a");
+        a.delete_row("table", "20");
+        a.set("other", "1", "x", 1);
+        b.set("table", "30", "x", "b");
+        b.delete("table", "40", "name");
+        b.increment("table", "1", "n", 2);
+        b.delete_table("gone");
+
+        let (messages, bytes) = run(&mut a, &mut b);
+        assert!(a.check_eq(&mut b));
+        assert_eq!(a.get_cell("table", "1", "n"), Some(&Value::I64(3)));
+        assert!(messages <= 8, "{} messages", messages);
+        assert!(
+            bytes * 4 < full,
+            "{} bytes, full export {} bytes",
+            bytes,
+            full
+        );
+
+        // nothing is sent after they converge
+        let (messages, _) = run(&mut a, &mut b);
+        assert_eq!(messages, 1);
+    }
+
+    #[test]
+    fn sync_after_interrupted_reconcile() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        for i in 0..5000 {
+            a.set("m", &format!("{:05}", i), "x", i);
+        }
+
+        let mut c = LwwDb::from_snapshot(&a.export_snapshot());
+        c.set_peer(2);
+        a.set("m", "00100", "x", "changed");
+        for i in 0..400 {
+            a.set("n", &format!("{:05}", i), "x", i);
+        }
+
+        let version = c.version().clone();
+        run_until(&mut a, &mut c, 5);
+        assert_eq!(c.version(), &version);
+        assert!((0..400).any(|i| c.get_cell("n", &format!("{:05}", i), "x").is_some()));
+        assert_ne!(c.get_cell("m", "00100", "x"), a.get_cell("m", "00100", "x"));
+
+        // c doesn't relay the ops whose history it doesn't have
+        let mut d = LwwDb::new();
+        d.import_updates(&c.export_updates(Default::default()));
+        assert_eq!(d.version(), &version);
+        let restored = LwwDb::from_snapshot(&c.export_snapshot());
+        assert_eq!(restored.version(), &version);
+        let restored = LwwDb::from_snapshot(&c.export_shallow_snapshot());
+        assert_eq!(restored.version(), &version);
+
+        c.import_updates(&a.export_updates(c.version().clone()));
+        assert!(a.check_eq(&mut c));
+        assert_eq!(c.version(), a.version());
+        d.import_updates(&c.export_updates(d.version().clone()));
+        assert!(a.check_eq(&mut d));
+    }
+
+    #[test]
+    fn reconcile_with_empty() {
+        let mut a = LwwDb::new();
+        for i in 0..100 {
+            a.set("table", &i.to_string(), "x", i);
+        }
+
+        a.delete_row("table", "3");
+        let mut b = LwwDb::new();
+        run(&mut b, &mut a);
+        assert!(a.check_eq(&mut b));
+        assert!(matches!(
+            a.reconcile(&ReconcileMessage {
+                ranges: vec![
+                    Range {
+                        upper: Some(ItemKey {
+                            table: "z".into(),
+                            row: None,
+                            col: None
+                        }),
+                        mode: RangeMode::Skip,
+                    },
+                    Range {
+                        upper: Some(ItemKey {
+                            table: "a".into(),
+                            row: None,
+                            col: None
+                        }),
+                        mode: RangeMode::Items(Vec::new()),
+                    }
+                ],
+                ..Default::default()
+            }),
+            Err(LwwError::InvalidOp(_))
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::{
+    error::{LwwError, LwwResult},
+    value::Value,
+    LwwDb,
+};
+
+mod meta;
+pub use meta::{ColumnDef, TableDef};
+pub(crate) use meta::{SchemaMeta, SchemaOp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ValueType {
+    Double,
+    I64,
+    Str,
+    Bool,
+}
+
+impl ValueType {
+    pub fn of(value: &Value) -> Option<ValueType> {
+        match value {
+            Value::Double(_) => Some(ValueType::Double),
+            Value::I64(_) => Some(ValueType::I64),
+            Value::Str(_) => Some(ValueType::Str),
+            Value::True | Value::False => Some(ValueType::Bool),
+            Value::Null | Value::Deleted => None,
+        }
+    }
+
+    /// Convert the value to this type if it can be done without losing information
+    pub fn coerce(&self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (ValueType::Double, Value::I64(i)) => Some(Value::Double(*i as f64)),
+            (ValueType::I64, Value::Double(d))
+                if d.fract() == 0.0 && *d >= i64::MIN as f64 && *d <= i64::MAX as f64 =>
+            {
+                Some(Value::I64(*d as i64))
+            }
+            (ValueType::Double, Value::Str(s)) => s.trim().parse().ok().map(Value::Double),
+            (ValueType::I64, Value::Str(s)) => s.trim().parse().ok().map(Value::I64),
+            (ValueType::Bool, Value::Str(s)) => match s.trim() {
+                "true" => Some(Value::True),
+                "false" => Some(Value::False),
+                _ => None,
+            },
+            (ValueType::Str, Value::Double(_) | Value::I64(_) | Value::True | Value::False) => {
+                Some(Value::Str(value.to_string().into()))
+            }
+            (t, v) if ValueType::of(v) == Some(*t) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueType::Double => write!(f, "double"),
+            ValueType::I64 => write!(f, "i64"),
+            ValueType::Str => write!(f, "str"),
+            ValueType::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSchema {
+    pub name: SmolStr,
+    pub value_type: ValueType,
+    pub nullable: bool,
+    /// Used when an invalid remote value is coerced, see [InvalidOpPolicy::Coerce]
+    pub default: Option<Value>,
+}
+
+impl ColumnSchema {
+    /// A non-nullable column without default value
+    pub fn new(name: &str, value_type: ValueType) -> Self {
+        Self {
+            name: name.into(),
+            value_type,
+            nullable: false,
+            default: None,
+        }
+    }
+
+    pub fn nullable(mut self, nullable: bool) -> Self {
+        self.nullable = nullable;
+        self
+    }
+
+    pub fn default_value(mut self, value: impl Into<Value>) -> Self {
+        self.default = Some(value.into());
+        self
+    }
+
+    pub fn check(&self, table: &str, value: &Value) -> Result<(), SchemaViolation> {
+        match ValueType::of(value) {
+            None if self.nullable => Ok(()),
+            None => Err(SchemaViolation::NotNullable {
+                table: table.into(),
+                col: self.name.clone(),
+            }),
+            Some(t) if t == self.value_type => Ok(()),
+            Some(_) => Err(SchemaViolation::TypeMismatch {
+                table: table.into(),
+                col: self.name.clone(),
+                expected: self.value_type,
+                found: value.clone(),
+            }),
+        }
+    }
+
+    /// Convert the value to a valid one, falling back to the default value
+    pub fn coerce(&self, value: &Value) -> Option<Value> {
+        if ValueType::of(value).is_none() {
+            return if self.nullable {
+                Some(Value::Null)
+            } else {
+                self.default.clone()
+            };
+        }
+
+        self.value_type
+            .coerce(value)
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// The columns of a table. The columns not in the schema are rejected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableSchema {
+    columns: Vec<ColumnSchema>,
+}
+
+impl TableSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn column(mut self, column: ColumnSchema) -> Self {
+        self.add_column(column);
+        self
+    }
+
+    /// Add the column, replacing the column with the same name
+    pub fn add_column(&mut self, column: ColumnSchema) {
+        match self.columns.iter_mut().find(|c| c.name == column.name) {
+            Some(c) => *c = column,
+            None => self.columns.push(column),
+        }
+    }
+
+    pub fn get_column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    pub fn columns(&self) -> &[ColumnSchema] {
+        &self.columns
+    }
+
+    pub fn check(&self, table: &str, col: &str, value: &Value) -> Result<(), SchemaViolation> {
+        match self.get_column(col) {
+            Some(c) => c.check(table, value),
+            None => Err(SchemaViolation::UnknownColumn {
+                table: table.into(),
+                col: col.into(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaViolation {
+    UnknownColumn {
+        table: SmolStr,
+        col: SmolStr,
+    },
+    TypeMismatch {
+        table: SmolStr,
+        col: SmolStr,
+        expected: ValueType,
+        found: Value,
+    },
+    NotNullable {
+        table: SmolStr,
+        col: SmolStr,
+    },
+}
+
+impl Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaViolation::UnknownColumn { table, col } => {
+                write!(f, "column {}.{} is not in the schema", table, col)
+            }
+            SchemaViolation::TypeMismatch {
+                table,
+                col,
+                expected,
+                found,
+            } => write!(f, "{}.{} expects {}, found {}", table, col, expected, found),
+            SchemaViolation::NotNullable { table, col } => {
+                write!(f, "{}.{} is not nullable", table, col)
+            }
+        }
+    }
+}
+
+/// What to do with the imported ops that violate the schema, or are denied by
+/// the access policy, see [LwwDb::set_access_policy]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidOpPolicy {
+    /// Skip the op and report it in [crate::ImportStatus]
+    #[default]
+    Reject,
+    /// Convert the value to the column type, or use the column default. The
+    /// op is rejected if neither works.
+    ///
+    /// The coerced value only exists on this replica. The op is still
+    /// exported with its original value, so it stays the op its peer signed.
+    Coerce,
+    /// Keep the op aside without applying it. See [LwwDb::quarantined_ops]
+    Quarantine,
+}
+
+impl LwwDb {
+    /// Validate the writes to the table with the schema. It replaces the
+    /// previous schema of the table.
+    ///
+    /// The existing values are not checked.
+    pub fn register_schema(&mut self, table: &str, schema: TableSchema) {
+        self.schemas.insert(table.into(), schema);
+    }
+
+    pub fn unregister_schema(&mut self, table: &str) -> Option<TableSchema> {
+        self.schemas.remove(table)
+    }
+
+    /// The schema that validates the local writes to the table. It's the
+    /// registered schema if any, otherwise the one built from the column
+    /// definitions, see [LwwDb::define_column]. The imported ops are only
+    /// validated by the registered schema.
+    pub fn schema(&self, table: &str) -> Option<&TableSchema> {
+        self.schemas
+            .get(table)
+            .or_else(|| self.schema_meta.schema(table))
+    }
+
+    pub fn set_invalid_op_policy(&mut self, policy: InvalidOpPolicy) {
+        self.invalid_op_policy = policy;
+    }
+
+    pub(crate) fn check_schema(&self, table: &str, col: &str, value: &Value) -> LwwResult<()> {
+        match self.schema(table) {
+            Some(schema) => schema
+                .check(table, col, value)
+                .map_err(LwwError::SchemaViolation),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the remote write with the registered schema. It returns the
+    /// value to write, which may be coerced.
+    ///
+    /// The column definitions are not used, because whether a replicated
+    /// definition is known when an op arrives depends on the order the ops
+    /// are received, and the replicas would accept different ops.
+    pub(crate) fn check_remote_write(
+        &self,
+        table: &str,
+        col: &str,
+        value: &Value,
+    ) -> Result<Option<Value>, SchemaViolation> {
+        let Some(schema) = self.schemas.get(table) else {
+            return Ok(None);
+        };
+
+        match schema.check(table, col, value) {
+            Ok(()) => Ok(None),
+            Err(e) => match self.invalid_op_policy {
+                InvalidOpPolicy::Coerce => schema
+                    .get_column(col)
+                    .and_then(|c| c.coerce(value))
+                    .map(Some)
+                    .ok_or(e),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{PeerKey, RejectReason, RejectedOp};
+
+    use super::*;
+
+    fn schema() -> TableSchema {
+        TableSchema::new()
+            .column(ColumnSchema::new("name", ValueType::Str))
+            .column(ColumnSchema::new("age", ValueType::I64).default_value(0))
+            .column(ColumnSchema::new("note", ValueType::Str).nullable(true))
+    }
+
+    #[test]
+    fn local_set() {
+        let mut db = LwwDb::new();
+        db.register_schema("people", schema());
+        db.try_set("people", "a", "name", "Alice").unwrap();
+        db.try_set("people", "a", "note", Value::Null).unwrap();
+        db.try_delete("people", "a", "note").unwrap();
+        assert!(matches!(
+            db.try_set("people", "a", "age", "12"),
+            Err(LwwError::SchemaViolation(
+                SchemaViolation::TypeMismatch { .. }
+            ))
+        ));
+        assert!(matches!(
+            db.try_set("people", "a", "email", "a@b.c"),
+            Err(LwwError::SchemaViolation(
+                SchemaViolation::UnknownColumn { .. }
+            ))
+        ));
+        assert!(matches!(
+            db.try_delete("people", "a", "name"),
+            Err(LwwError::SchemaViolation(
+                SchemaViolation::NotNullable { .. }
+            ))
+        ));
+        db.set("other", "a", "age", "12");
+        assert_eq!(db.get_cell("people", "a", "age"), None);
+    }
+
+    fn remote_updates() -> Vec<u8> {
+        let mut db = LwwDb::new();
+        db.set("people", "a", "name", "Alice");
+        db.set("people", "a", "age", "12");
+        db.set("people", "b", "age", "twelve");
+        db.export_updates(Default::default())
+    }
+
+    #[test]
+    fn reject_remote_ops() {
+        let mut db = LwwDb::new();
+        db.register_schema("people", schema());
+        let status = db.try_import_updates(&remote_updates()).unwrap();
+        assert_eq!(status.rejected.len(), 2);
+        assert!(status
+            .rejected
+            .iter()
+            .all(|r| matches!(r.reason, RejectReason::Schema(_))));
+        assert_eq!(db.get_cell("people", "a", "name"), Some(&"Alice".into()));
+        assert_eq!(db.get_cell("people", "a", "age"), None);
+        assert_eq!(db.quarantined_ops().count(), 0);
+    }
+
+    #[test]
+    fn coerce_remote_ops() {
+        let mut db = LwwDb::new();
+        db.register_schema("people", schema());
+        db.set_invalid_op_policy(InvalidOpPolicy::Coerce);
+        let status = db.try_import_updates(&remote_updates()).unwrap();
+        assert!(status.rejected.is_empty());
+        assert_eq!(db.get_cell("people", "a", "age"), Some(&Value::I64(12)));
+        assert_eq!(db.get_cell("people", "b", "age"), Some(&Value::I64(0)));
+    }
+
+    #[test]
+    fn relay_coerced_ops_as_written() {
+        let key = PeerKey::generate();
+        let mut remote = LwwDb::new();
+        remote.set_signing_key(key.clone());
+        remote.set("people", "a", "age", "12");
+        let mut db = LwwDb::new();
+        db.trust_peer(key.public_key());
+        db.register_schema("people", schema());
+        db.set_invalid_op_policy(InvalidOpPolicy::Coerce);
+        db.import_updates(&remote.export_updates(Default::default()));
+        assert_eq!(db.get_cell("people", "a", "age"), Some(&Value::I64(12)));
+
+        // the other replicas receive the op that the remote peer signed
+        let mut other = LwwDb::new();
+        other.trust_peer(key.public_key());
+        let status = other
+            .try_import_updates(&db.export_updates(Default::default()))
+            .unwrap();
+        assert!(status.forged.is_empty());
+        assert_eq!(other.get_cell("people", "a", "age"), Some(&"12".into()));
+
+        // the snapshots keep the original value too
+        let restored = LwwDb::from_snapshot(&db.export_snapshot());
+        let mut other = LwwDb::new();
+        other.import_updates(&restored.export_updates(Default::default()));
+        assert_eq!(other.get_cell("people", "a", "age"), Some(&"12".into()));
+        let shallow = LwwDb::from_snapshot(&db.export_shallow_snapshot());
+        assert_eq!(shallow.coerced, db.coerced);
+    }
+
+    #[test]
+    fn reject_remote_increments() {
+        let mut remote = LwwDb::new();
+        remote.increment("people", "a", "age", 1);
+        remote.increment("people", "a", "name", 1);
+        let mut db = LwwDb::new();
+        db.register_schema("people", schema());
+        // the total is not coerced either
+        db.set_invalid_op_policy(InvalidOpPolicy::Coerce);
+        let status = db
+            .try_import_updates(&remote.export_updates(Default::default()))
+            .unwrap();
+        assert_eq!(status.applied, 1);
+        assert!(matches!(
+            status.rejected[..],
+            [RejectedOp {
+                reason: RejectReason::Schema(SchemaViolation::TypeMismatch { .. }),
+                ..
+            }]
+        ));
+        assert_eq!(db.get_cell("people", "a", "age"), Some(&Value::I64(1)));
+        assert_eq!(db.get_cell("people", "a", "name"), None);
+    }
+
+    #[test]
+    fn quarantine_remote_ops() {
+        let mut db = LwwDb::new();
+        db.register_schema("people", schema());
+        db.set_invalid_op_policy(InvalidOpPolicy::Quarantine);
+        let status = db.try_import_updates(&remote_updates()).unwrap();
+        assert!(status.rejected.is_empty());
+        assert_eq!(status.quarantined, 2);
+        assert_eq!(db.quarantined_ops().count(), 2);
+        assert_eq!(db.get_cell("people", "a", "age"), None);
+
+        // accept any age after the schema changes
+        db.register_schema(
+            "people",
+            schema().column(ColumnSchema::new("age", ValueType::Str)),
+        );
+        let status = db.retry_quarantined_ops();
+        assert_eq!(status.applied, 2);
+        assert_eq!(db.quarantined_ops().count(), 0);
+        assert_eq!(db.get_cell("people", "b", "age"), Some(&"twelve".into()));
+    }
+}
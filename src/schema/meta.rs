@@ -0,0 +1,367 @@
+use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::{clock::OpId, value::Value, LwwDb};
+
+use super::{ColumnSchema, TableSchema, ValueType};
+
+/// The definition of a table that is synced with the data
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableDef {
+    pub display_name: Option<SmolStr>,
+}
+
+impl TableDef {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn display_name(mut self, name: &str) -> Self {
+        self.display_name = Some(name.into());
+        self
+    }
+}
+
+/// The definition of a column that is synced with the data. The defined
+/// columns are the schema of the table unless a local schema is registered
+/// with [LwwDb::register_schema].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnDef {
+    pub value_type: ValueType,
+    pub nullable: bool,
+    pub display_name: Option<SmolStr>,
+    pub default: Option<Value>,
+    /// The columns are listed in ascending order
+    pub order: i64,
+}
+
+impl ColumnDef {
+    /// A non-nullable column without default value
+    pub fn new(value_type: ValueType) -> Self {
+        Self {
+            value_type,
+            nullable: false,
+            display_name: None,
+            default: None,
+            order: 0,
+        }
+    }
+
+    pub fn nullable(mut self, nullable: bool) -> Self {
+        self.nullable = nullable;
+        self
+    }
+
+    pub fn display_name(mut self, name: &str) -> Self {
+        self.display_name = Some(name.into());
+        self
+    }
+
+    pub fn default_value(mut self, value: impl Into<Value>) -> Self {
+        self.default = Some(value.into());
+        self
+    }
+
+    pub fn order(mut self, order: i64) -> Self {
+        self.order = order;
+        self
+    }
+}
+
+/// A write to the schema metadata
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum SchemaOp {
+    Table(TableDef),
+    /// None removes the column definition
+    Column {
+        col: SmolStr,
+        def: Option<ColumnDef>,
+    },
+}
+
+impl SchemaOp {
+    pub fn col(&self) -> Option<&SmolStr> {
+        match self {
+            SchemaOp::Table(_) => None,
+            SchemaOp::Column { col, .. } => Some(col),
+        }
+    }
+}
+
+/// The LWW registers of the table and column definitions
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SchemaMeta {
+    tables: FxHashMap<SmolStr, TableMeta>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TableMeta {
+    def: Option<(TableDef, OpId)>,
+    columns: FxHashMap<SmolStr, (Option<ColumnDef>, OpId)>,
+    /// Built from the column definitions
+    schema: Option<TableSchema>,
+}
+
+impl TableMeta {
+    fn sorted_columns(&self) -> Vec<(&SmolStr, &ColumnDef)> {
+        let mut ans: Vec<_> = self
+            .columns
+            .iter()
+            .filter_map(|(name, (def, _))| def.as_ref().map(|d| (name, d)))
+            .collect();
+        ans.sort_by(|a, b| a.1.order.cmp(&b.1.order).then_with(|| a.0.cmp(b.0)));
+        ans
+    }
+
+    fn rebuild_schema(&mut self) {
+        let columns = self.sorted_columns();
+        if columns.is_empty() {
+            self.schema = None;
+            return;
+        }
+
+        let mut schema = TableSchema::new();
+        for (name, def) in columns {
+            schema.add_column(ColumnSchema {
+                name: name.clone(),
+                value_type: def.value_type,
+                nullable: def.nullable,
+                default: def.default.clone(),
+            });
+        }
+
+        self.schema = Some(schema);
+    }
+}
+
+impl SchemaMeta {
+    /// Apply the op if it's newer than the current definition
+    pub fn apply(&mut self, id: OpId, table: &str, op: SchemaOp) -> bool {
+        let meta = self.tables.entry(table.into()).or_default();
+        match op {
+            SchemaOp::Table(def) => {
+                if meta.def.as_ref().is_some_and(|(_, old)| *old >= id) {
+                    return false;
+                }
+
+                meta.def = Some((def, id));
+            }
+            SchemaOp::Column { col, def } => {
+                if meta.columns.get(&col).is_some_and(|(_, old)| *old >= id) {
+                    return false;
+                }
+
+                meta.columns.insert(col, (def, id));
+                meta.rebuild_schema();
+            }
+        }
+
+        true
+    }
+
+    /// The current op of the table definition, or of the column definition if
+    /// `col` is given
+    pub fn get(&self, table: &str, col: Option<&str>) -> Option<(OpId, SchemaOp)> {
+        let meta = self.tables.get(table)?;
+        match col {
+            None => meta
+                .def
+                .as_ref()
+                .map(|(def, id)| (*id, SchemaOp::Table(def.clone()))),
+            Some(col) => meta.columns.get(col).map(|(def, id)| {
+                (
+                    *id,
+                    SchemaOp::Column {
+                        col: col.into(),
+                        def: def.clone(),
+                    },
+                )
+            }),
+        }
+    }
+
+    /// All the current ops, (table, id, op)
+    pub fn iter(&self) -> impl Iterator<Item = (&SmolStr, OpId, SchemaOp)> + '_ {
+        self.tables.iter().flat_map(|(name, meta)| {
+            let table = meta
+                .def
+                .iter()
+                .map(move |(def, id)| (name, *id, SchemaOp::Table(def.clone())));
+            let columns = meta.columns.iter().map(move |(col, (def, id))| {
+                (
+                    name,
+                    *id,
+                    SchemaOp::Column {
+                        col: col.clone(),
+                        def: def.clone(),
+                    },
+                )
+            });
+            table.chain(columns)
+        })
+    }
+
+    pub fn schema(&self, table: &str) -> Option<&TableSchema> {
+        self.tables.get(table).and_then(|m| m.schema.as_ref())
+    }
+}
+
+impl LwwDb {
+    /// Set the definition of the table. It's synced to the other peers.
+    pub fn define_table(&mut self, table: &str, def: TableDef) {
+        let id = self.next_id();
+        self.apply_schema_op(id, table, SchemaOp::Table(def));
+    }
+
+    /// Set the definition of the column. It's synced to the other peers, and
+    /// the local writes to the column are validated by it on every peer. The
+    /// imported writes are not, so the replicas that receive the definition
+    /// and the writes in different orders accept the same writes.
+    pub fn define_column(&mut self, table: &str, col: &str, def: ColumnDef) {
+        let id = self.next_id();
+        self.apply_schema_op(
+            id,
+            table,
+            SchemaOp::Column {
+                col: col.into(),
+                def: Some(def),
+            },
+        );
+    }
+
+    /// Remove the definition of the column. The values of the column are kept.
+    pub fn remove_column_def(&mut self, table: &str, col: &str) {
+        let id = self.next_id();
+        self.apply_schema_op(
+            id,
+            table,
+            SchemaOp::Column {
+                col: col.into(),
+                def: None,
+            },
+        );
+    }
+
+    pub fn table_def(&self, table: &str) -> Option<&TableDef> {
+        self.schema_meta
+            .tables
+            .get(table)
+            .and_then(|m| m.def.as_ref().map(|(def, _)| def))
+    }
+
+    /// The column definitions of the table, ordered by [ColumnDef::order]
+    pub fn column_defs(&self, table: &str) -> Vec<(&SmolStr, &ColumnDef)> {
+        self.schema_meta
+            .tables
+            .get(table)
+            .map(|m| m.sorted_columns())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn apply_schema_op(&mut self, id: OpId, table: &str, op: SchemaOp) {
+        let col = op.col().cloned();
+        if self.schema_meta.apply(id, table, op) {
+            self.oplog.record_define(id, table.into(), col);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{LwwError, SchemaViolation};
+
+    use super::*;
+
+    #[test]
+    fn schema_syncs_with_data() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.define_table("people", TableDef::new().display_name("People"));
+        a.define_column(
+            "people",
+            "age",
+            ColumnDef::new(ValueType::I64).display_name("Age").order(1),
+        );
+        a.define_column("people", "name", ColumnDef::new(ValueType::Str));
+        a.set("people", "a", "name", "Alice");
+        assert!(matches!(
+            a.try_set("people", "a", "age", "12"),
+            Err(LwwError::SchemaViolation(
+                SchemaViolation::TypeMismatch { .. }
+            ))
+        ));
+
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        b.import_updates(&a.export_updates(Default::default()));
+        assert_eq!(
+            b.table_def("people").unwrap().display_name.as_deref(),
+            Some("People")
+        );
+        let names: Vec<_> = b
+            .column_defs("people")
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, vec!["name", "age"]);
+        assert!(b.try_set("people", "a", "email", "a@b.c").is_err());
+
+        let c = LwwDb::from_snapshot(&b.export_snapshot());
+        assert_eq!(c.column_defs("people"), b.column_defs("people"));
+        assert!(c.version().includes(OpId::new(3, 1)));
+
+        // a local schema takes precedence
+        b.register_schema("people", TableSchema::new());
+        assert!(b.try_set("people", "a", "name", "Bob").is_err());
+    }
+
+    #[test]
+    fn concurrent_definitions_converge() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        a.define_column("t", "x", ColumnDef::new(ValueType::I64));
+        b.define_column("t", "x", ColumnDef::new(ValueType::Str));
+        b.define_column("t", "y", ColumnDef::new(ValueType::Str));
+        b.remove_column_def("t", "y");
+        a.import_updates(&b.export_updates(Default::default()));
+        b.import_updates(&a.export_updates(Default::default()));
+        assert_eq!(a.column_defs("t"), b.column_defs("t"));
+        assert_eq!(a.column_defs("t").len(), 1);
+        assert_eq!(a.column_defs("t")[0].1.value_type, ValueType::Str);
+
+        // the data written under the new schema is accepted by the peers that
+        // learn the schema from the same payload
+        b.set("t", "r", "x", "hello");
+        let mut c = LwwDb::new();
+        let status = c
+            .try_import_updates(&b.export_updates(Default::default()))
+            .unwrap();
+        assert!(status.rejected.is_empty());
+        assert_eq!(c.get_cell("t", "r", "x"), Some(&"hello".into()));
+    }
+
+    #[test]
+    fn imports_dont_depend_on_definition_order() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.define_column("t", "x", ColumnDef::new(ValueType::I64));
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        b.set("t", "r", "x", "not a number");
+        let def = a.export_updates(Default::default());
+        let write = b.export_updates(Default::default());
+
+        let mut c = LwwDb::new();
+        c.import_updates(&def);
+        assert!(c.try_import_updates(&write).unwrap().rejected.is_empty());
+        let mut d = LwwDb::new();
+        d.import_updates(&write);
+        d.import_updates(&def);
+        assert!(c.check_eq(&mut d));
+        assert_eq!(c.get_cell("t", "r", "x"), Some(&"not a number".into()));
+        assert!(c.try_set("t", "r", "x", "local").is_err());
+    }
+}
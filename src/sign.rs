@@ -0,0 +1,363 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use fxhash::FxHashMap;
+use serde::Serialize;
+
+use crate::{
+    clock::{Lamport, OpId, Peer, VectorClock},
+    encode::DecodedOp,
+    error::{LwwError, LwwResult},
+    import::ImportStatus,
+    schema::SchemaOp,
+    value::Value,
+    LwwDb,
+};
+
+/// The Ed25519 key pair of a peer. Its peer id is derived from the public
+/// key, see [LwwDb::set_signing_key].
+#[derive(Clone)]
+pub struct PeerKey(SigningKey);
+
+/// The Ed25519 public key of a peer, see [LwwDb::trust_peer]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerPublicKey(VerifyingKey);
+
+impl PeerKey {
+    /// A random key pair
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        getrandom::getrandom(&mut bytes).unwrap();
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(secret: &[u8; 32]) -> Self {
+        Self(SigningKey::from_bytes(secret))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn public_key(&self) -> PeerPublicKey {
+        PeerPublicKey(self.0.verifying_key())
+    }
+
+    pub fn peer(&self) -> Peer {
+        self.public_key().peer()
+    }
+}
+
+impl std::fmt::Debug for PeerKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PeerKey({})", self.peer())
+    }
+}
+
+impl PeerPublicKey {
+    /// It fails if the bytes are not a valid Ed25519 public key
+    pub fn from_bytes(bytes: &[u8; 32]) -> LwwResult<Self> {
+        VerifyingKey::from_bytes(bytes)
+            .map(Self)
+            .map_err(|_| LwwError::InvalidPublicKey)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    /// The peer id bound to the key, the first 8 bytes of the key
+    pub fn peer(&self) -> Peer {
+        Peer::from_le_bytes(self.0.as_bytes()[..8].try_into().unwrap())
+    }
+}
+
+/// The content of an op that is signed by its peer
+#[derive(Serialize)]
+pub(crate) enum SignedOp<'a> {
+    /// A write to a cell, or the deletion of a row or a table
+    Op {
+        id: OpId,
+        table: &'a str,
+        row: Option<&'a str>,
+        col: Option<&'a str>,
+        value: &'a Value,
+        /// The context of the write, ordered by peer
+        context: Option<Vec<(Peer, Lamport)>>,
+    },
+    Counter {
+        id: OpId,
+        table: &'a str,
+        row: &'a str,
+        col: &'a str,
+        total: i64,
+    },
+    Schema {
+        id: OpId,
+        table: &'a str,
+        op: &'a SchemaOp,
+    },
+}
+
+impl<'a> SignedOp<'a> {
+    pub fn op(
+        id: OpId,
+        table: &'a str,
+        row: Option<&'a str>,
+        col: Option<&'a str>,
+        value: &'a Value,
+        context: Option<&VectorClock>,
+    ) -> Self {
+        let context = context.map(|c| {
+            let mut c: Vec<_> = c.iter().map(|(p, l)| (*p, *l)).collect();
+            c.sort_unstable();
+            c
+        });
+        SignedOp::Op {
+            id,
+            table,
+            row,
+            col,
+            value,
+            context,
+        }
+    }
+
+    pub fn id(&self) -> OpId {
+        match self {
+            SignedOp::Op { id, .. }
+            | SignedOp::Counter { id, .. }
+            | SignedOp::Schema { id, .. } => *id,
+        }
+    }
+
+    fn message(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Signatures {
+    key: Option<SigningKey>,
+    /// None if the imported ops are not verified
+    trusted: Option<FxHashMap<Peer, VerifyingKey>>,
+    /// The signatures of the ops of the other peers, which are exported with
+    /// the ops
+    received: FxHashMap<OpId, Signature>,
+}
+
+impl Signatures {
+    /// Whether the exported ops can have signatures
+    pub fn is_active(&self) -> bool {
+        self.key.is_some() || !self.received.is_empty()
+    }
+}
+
+impl LwwDb {
+    /// Sign the ops of this db with the key. The peer id of the db is set to
+    /// the one bound to the key, see [PeerPublicKey::peer].
+    ///
+    /// The ops are signed when they are exported by [LwwDb::export_updates],
+    /// and the signatures of the ops of the other peers are exported with
+    /// them, so the ops can be verified after they are relayed by any peer.
+    /// The snapshots don't have the signatures.
+    pub fn set_signing_key(&mut self, key: PeerKey) {
+        self.set_peer(key.peer());
+        self.signatures.key = Some(key.0);
+    }
+
+    /// Verify the signatures of the imported ops, and trust the ops of the
+    /// peer signed by the key.
+    ///
+    /// Once a peer is trusted, the imported ops that are not signed by the
+    /// trusted key of their peer are dropped and reported in
+    /// [ImportStatus::forged], so no peer can write under the id of another
+    /// peer. A forged op drops all the ops of its transaction. The version
+    /// stops before the forged ops, so the genuine ones are received again.
+    /// The ops of this db are checked with its signing key.
+    pub fn trust_peer(&mut self, key: PeerPublicKey) {
+        self.signatures
+            .trusted
+            .get_or_insert_with(Default::default)
+            .insert(key.peer(), key.0);
+    }
+
+    /// The signature of the op to export with it
+    pub(crate) fn signature_of(&self, op: &SignedOp) -> Option<Signature> {
+        let id = op.id();
+        match &self.signatures.key {
+            Some(key) if id.peer == self.peer => Some(key.sign(&op.message())),
+            _ => self.signatures.received.get(&id).copied(),
+        }
+    }
+
+    /// Whether the imported op is signed by its peer. It's always true if the
+    /// ops are not verified. Otherwise the op is reported as forged.
+    pub(crate) fn check_signature<'a>(
+        &mut self,
+        id: OpId,
+        op: impl FnOnce() -> SignedOp<'a>,
+        signatures: &FxHashMap<OpId, Signature>,
+        status: &mut ImportStatus,
+    ) -> bool {
+        if !self.verify_signature(id, op, signatures) {
+            self.reject_forged(id, status);
+            return false;
+        }
+
+        self.keep_signature(id, signatures);
+        true
+    }
+
+    /// Whether all the ops of the transaction are signed by their peers.
+    /// Otherwise they are all reported as forged, so none of them is applied.
+    pub(crate) fn check_txn_signatures(
+        &mut self,
+        ops: &[DecodedOp],
+        signatures: &FxHashMap<OpId, Signature>,
+        status: &mut ImportStatus,
+    ) -> bool {
+        let verified = ops.iter().all(|op| {
+            let signed = || SignedOp::op(op.id, op.table, op.row, op.col, op.value, op.context);
+            self.verify_signature(op.id, signed, signatures)
+        });
+        for op in ops {
+            if verified {
+                self.keep_signature(op.id, signatures);
+            } else {
+                self.reject_forged(op.id, status);
+            }
+        }
+
+        verified
+    }
+
+    fn verify_signature<'a>(
+        &self,
+        id: OpId,
+        op: impl FnOnce() -> SignedOp<'a>,
+        signatures: &FxHashMap<OpId, Signature>,
+    ) -> bool {
+        let Some(trusted) = &self.signatures.trusted else {
+            return true;
+        };
+
+        let key = match (trusted.get(&id.peer), &self.signatures.key) {
+            (Some(key), _) => Some(*key),
+            (None, Some(key)) if id.peer == self.peer => Some(key.verifying_key()),
+            _ => None,
+        };
+        match (key, signatures.get(&id)) {
+            (Some(key), Some(signature)) => key.verify_strict(&op().message(), signature).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Keep the signature of the imported op to export it with the op
+    fn keep_signature(&mut self, id: OpId, signatures: &FxHashMap<OpId, Signature>) {
+        if let Some(signature) = signatures.get(&id) {
+            self.signatures.received.insert(id, *signature);
+        }
+    }
+
+    /// The version stops before the forged op, so the genuine op is received
+    /// again from another peer
+    fn reject_forged(&mut self, id: OpId, status: &mut ImportStatus) {
+        self.oplog.exclude(id);
+        status.forged.push(id);
+    }
+
+    /// Drop the signatures of the ops that are overwritten
+    pub(crate) fn prune_signatures(&mut self) {
+        if self.signatures.received.is_empty() {
+            return;
+        }
+
+        let live = self.live_ops();
+        self.signatures.received.retain(|id, _| live.contains(id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::TableDef;
+
+    use super::*;
+
+    fn signed_db() -> (LwwDb, PeerKey) {
+        let key = PeerKey::generate();
+        let mut db = LwwDb::new();
+        db.set_signing_key(key.clone());
+        (db, key)
+    }
+
+    #[test]
+    fn verify_relayed_ops() {
+        let (mut a, key_a) = signed_db();
+        let (mut b, key_b) = signed_db();
+        assert_eq!(a.peer, key_a.peer());
+        a.define_table("table", TableDef::new().display_name("Table"));
+        a.set("table", "a", "x", "from a");
+        a.increment("table", "a", "n", 2);
+        a.delete_row("table", "gone");
+        b.set("table", "b", "x", "from b");
+        b.import_updates(&a.export_updates(Default::default()));
+
+        // b relays the ops of a
+        let mut c = LwwDb::new();
+        c.trust_peer(key_a.public_key());
+        c.trust_peer(key_b.public_key());
+        let status = c
+            .try_import_updates(&b.export_updates(Default::default()))
+            .unwrap();
+        assert!(status.forged.is_empty());
+        assert!(c.check_eq(&mut b));
+        assert_eq!(c.version(), b.version());
+        assert_eq!(c.get_cell("table", "a", "n"), Some(&Value::I64(2)));
+    }
+
+    #[test]
+    fn reject_forged_ops() {
+        let (mut a, key_a) = signed_db();
+        a.set("table", "a", "x", 1);
+        let mut c = LwwDb::new();
+        c.trust_peer(key_a.public_key());
+        c.import_updates(&a.export_updates(Default::default()));
+
+        // mallory writes under the id of a
+        let (mut mallory, _) = signed_db();
+        mallory.import_updates(&a.export_updates(Default::default()));
+        mallory.set_peer(key_a.peer());
+        mallory.set("table", "a", "x", "forged");
+        mallory.set("table", "b", "x", "forged");
+        let version = c.version().clone();
+        let status = c
+            .try_import_updates(&mallory.export_updates(version.clone()))
+            .unwrap();
+        assert_eq!(status.forged.len(), 2);
+        assert_eq!(status.applied, 0);
+        assert_eq!(c.version(), &version);
+        assert_eq!(c.get_cell("table", "a", "x"), Some(&Value::I64(1)));
+
+        // the unsigned ops of an unknown peer are dropped too
+        let mut unsigned = LwwDb::new();
+        unsigned.set("table", "c", "x", 1);
+        let status = c
+            .try_import_updates(&unsigned.export_updates(Default::default()))
+            .unwrap();
+        assert_eq!(status.forged.len(), 1);
+        assert_eq!(c.get_cell("table", "c", "x"), None);
+    }
+
+    #[test]
+    fn prune_overwritten_signatures() {
+        let (mut a, _) = signed_db();
+        let mut b = LwwDb::new();
+        for i in 0..10 {
+            a.set("table", "a", "x", i);
+            b.import_updates(&a.export_updates(b.version().clone()));
+        }
+
+        assert_eq!(b.signatures.received.len(), 10);
+        b.prune_signatures();
+        assert_eq!(b.signatures.received.len(), 1);
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    clock::VectorClock,
+    error::{LwwError, LwwResult},
+    LwwDb,
+};
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const LOG_FILE: &str = "log";
+/// The length and the checksum of a record
+const RECORD_HEADER_LEN: usize = 8;
+const DEFAULT_CHECKPOINT_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// The on-disk storage of a [LwwDb] in a directory.
+///
+/// The changes are appended to a log file as records of
+/// [LwwDb::export_updates], so saving costs O(changes). When the log grows
+/// over the checkpoint threshold, the db is written as a snapshot and the log
+/// is cleared.
+///
+/// ```no_run
+/// use lww_table::Storage;
+///
+/// let (mut storage, mut db) = Storage::open("./data").unwrap();
+/// db.set("table", "row", "col", 1);
+/// storage.append(&db).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Storage {
+    dir: PathBuf,
+    log: File,
+    log_len: u64,
+    /// The version of the db that is written to the disk
+    saved: VectorClock,
+    checkpoint_threshold: u64,
+}
+
+impl Storage {
+    /// Open the storage in the directory, creating it if it doesn't exist, and
+    /// recover the db from the latest snapshot and the log after it.
+    ///
+    /// A torn record at the end of the log, which is left by a crash during a
+    /// write, is truncated.
+    pub fn open(dir: impl AsRef<Path>) -> LwwResult<(Self, LwwDb)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(LwwError::Io)?;
+        let mut db = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => LwwDb::try_from_snapshot(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => LwwDb::new(),
+            Err(e) => return Err(LwwError::Io(e)),
+        };
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))
+            .map_err(LwwError::Io)?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes).map_err(LwwError::Io)?;
+        let mut log_len = 0;
+        while let Some(record) = read_record(&bytes[log_len..]) {
+            db.try_import_updates(record)?;
+            log_len += RECORD_HEADER_LEN + record.len();
+        }
+
+        if log_len < bytes.len() {
+            tracing::warn!(
+                "truncate {} bytes of the torn record at the end of the log",
+                bytes.len() - log_len
+            );
+            log.set_len(log_len as u64).map_err(LwwError::Io)?;
+            log.sync_all().map_err(LwwError::Io)?;
+        }
+
+        let storage = Storage {
+            dir,
+            log,
+            log_len: log_len as u64,
+            saved: db.version().clone(),
+            checkpoint_threshold: DEFAULT_CHECKPOINT_THRESHOLD,
+        };
+        Ok((storage, db))
+    }
+
+    /// Write a checkpoint when the log is larger than `bytes` after an append
+    pub fn set_checkpoint_threshold(&mut self, bytes: u64) {
+        self.checkpoint_threshold = bytes;
+    }
+
+    /// The size of the log in bytes
+    pub fn log_len(&self) -> u64 {
+        self.log_len
+    }
+
+    /// Append the changes of the db since the last save to the log, and flush
+    /// them to the disk.
+    pub fn append(&mut self, db: &LwwDb) -> LwwResult<()> {
+        if db.version() == &self.saved {
+            return Ok(());
+        }
+
+        let payload = db.export_updates(self.saved.clone());
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        let written = self
+            .log
+            .write_all(&record)
+            .and_then(|_| self.log.sync_data());
+        if let Err(e) = written {
+            // drop the partial record, so the next records are not appended
+            // after it
+            let _ = self.log.set_len(self.log_len);
+            return Err(LwwError::Io(e));
+        }
+
+        self.log_len += record.len() as u64;
+        self.saved = db.version().clone();
+        if self.log_len > self.checkpoint_threshold {
+            self.checkpoint(db)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the db as the new snapshot and clear the log.
+    ///
+    /// The snapshot replaces the old one atomically. If it crashes before the
+    /// log is cleared, the log is replayed on the new snapshot, which is
+    /// harmless because importing the same ops again has no effect.
+    pub fn checkpoint(&mut self, db: &LwwDb) -> LwwResult<()> {
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut file = File::create(&tmp).map_err(LwwError::Io)?;
+        file.write_all(&db.export_snapshot())
+            .map_err(LwwError::Io)?;
+        file.sync_all().map_err(LwwError::Io)?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE)).map_err(LwwError::Io)?;
+        // the rename must be on the disk before the log is cleared
+        sync_dir(&self.dir)?;
+        self.log.set_len(0).map_err(LwwError::Io)?;
+        self.log.sync_all().map_err(LwwError::Io)?;
+        self.log_len = 0;
+        self.saved = db.version().clone();
+        Ok(())
+    }
+}
+
+/// Flush the entries of the directory to the disk
+fn sync_dir(dir: &Path) -> LwwResult<()> {
+    // directories can't be opened as files on windows
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(LwwError::Io)?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Returns the payload of the record at the start of `bytes`, or None if the
+/// record is incomplete or corrupted
+fn read_record(bytes: &[u8]) -> Option<&[u8]> {
+    let header = bytes.get(..RECORD_HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    (crc32fast::hash(payload) == crc).then_some(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::value::Value;
+
+    use super::*;
+
+    #[test]
+    fn recover_from_log_and_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut db) = Storage::open(dir.path()).unwrap();
+        db.set("table", "a", "x", 1);
+        storage.append(&db).unwrap();
+        db.set("table", "b", "x", 2);
+        storage.append(&db).unwrap();
+        let len = storage.log_len();
+        storage.append(&db).unwrap();
+        assert_eq!(storage.log_len(), len);
+        drop(storage);
+
+        let (mut storage, mut db) = Storage::open(dir.path()).unwrap();
+        assert_eq!(db.get_cell("table", "b", "x"), Some(&Value::I64(2)));
+        storage.checkpoint(&db).unwrap();
+        assert_eq!(storage.log_len(), 0);
+        db.delete_row("table", "a");
+        storage.append(&db).unwrap();
+        drop(storage);
+
+        let (storage, db) = Storage::open(dir.path()).unwrap();
+        assert_eq!(db.get_cell("table", "a", "x"), Some(&Value::Null));
+        assert_eq!(db.get_cell("table", "b", "x"), Some(&Value::I64(2)));
+        assert!(storage.log_len() > 0);
+    }
+
+    #[test]
+    fn truncate_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut db) = Storage::open(dir.path()).unwrap();
+        db.set("table", "a", "x", 1);
+        storage.append(&db).unwrap();
+        let len = storage.log_len();
+        db.set("table", "a", "x", 2);
+        storage.append(&db).unwrap();
+        drop(storage);
+
+        // a crash in the middle of the second record
+        let log = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap();
+        log.set_len(len + 5).unwrap();
+        drop(log);
+
+        let (mut storage, mut db) = Storage::open(dir.path()).unwrap();
+        assert_eq!(storage.log_len(), len);
+        assert_eq!(db.get_cell("table", "a", "x"), Some(&Value::I64(1)));
+        db.set("table", "a", "y", 3);
+        storage.append(&db).unwrap();
+        drop(storage);
+
+        let (_, db) = Storage::open(dir.path()).unwrap();
+        assert_eq!(db.get_cell("table", "a", "y"), Some(&Value::I64(3)));
+    }
+
+    #[test]
+    fn checkpoint_when_log_is_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut db) = Storage::open(dir.path()).unwrap();
+        storage.set_checkpoint_threshold(64);
+        for i in 0..20 {
+            db.set("table", &i.to_string(), "x", i);
+            storage.append(&db).unwrap();
+        }
+
+        assert!(storage.log_len() <= 64);
+        drop(storage);
+        let (_, db) = Storage::open(dir.path()).unwrap();
+        assert_eq!(db.get_cell("table", "19", "x"), Some(&Value::I64(19)));
+    }
+}
@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::VectorClock,
+    error::{LwwError, LwwResult},
+    LwwDb,
+};
+
+/// A message of [SyncSession]. Use [SyncMessage::encode] to send it over any
+/// transport.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SyncMessage {
+    /// The version of the sender, which asks for the updates it doesn't have
+    Hello { version: VectorClock },
+    /// The updates since `from` exported by [LwwDb::export_updates], and the
+    /// version of the sender
+    Updates {
+        from: VectorClock,
+        version: VectorClock,
+        payload: Vec<u8>,
+    },
+    /// The version of the sender after it imported the updates
+    Ack { version: VectorClock },
+    /// The sender is restored from a shallow snapshot and doesn't have the
+    /// updates since the version of the receiver
+    NeedSnapshot { frontier: VectorClock },
+}
+
+impl SyncMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).unwrap()
+    }
+
+    pub fn try_decode(encoded: &[u8]) -> LwwResult<Self> {
+        Ok(postcard::from_bytes(encoded)?)
+    }
+}
+
+/// The state of the sync with a remote peer. It doesn't do any I/O, it only
+/// produces the messages to send and consumes the received ones, so it works
+/// over any transport.
+///
+/// It tracks the version the remote peer has acknowledged, so only the new
+/// ops are sent in the later rounds. The messages can be dropped or
+/// duplicated:
+///
+/// - The updates are only imported if the receiver has all the ops before
+///   them, otherwise it replies with a [SyncMessage::Hello] and the sender
+///   sends them again from the acknowledged version
+/// - Importing the same updates again has no effect
+/// - If the last message may be lost, e.g. on a timeout, call
+///   [SyncSession::reset] and [SyncSession::poll] to send the
+///   unacknowledged ops again
+///
+/// ```
+/// use lww_table::{LwwDb, SyncSession};
+///
+/// let mut a = LwwDb::new();
+/// let mut b = LwwDb::new();
+/// a.set("table", "row", "col", 1);
+/// b.set("table", "row", "other", 2);
+/// let (mut session_a, mut session_b) = (SyncSession::new(), SyncSession::new());
+///
+/// let mut to_b = vec![session_a.hello(&a)];
+/// while !to_b.is_empty() {
+///     let mut to_a = Vec::new();
+///     for msg in to_b {
+///         to_a.extend(session_b.handle(&mut b, msg).unwrap());
+///     }
+///     to_b = Vec::new();
+///     for msg in to_a {
+///         to_b.extend(session_a.handle(&mut a, msg).unwrap());
+///     }
+/// }
+///
+/// assert!(a.check_eq(&mut b));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SyncSession {
+    /// The ops the remote peer is known to have
+    acked: VectorClock,
+    /// The ops that are sent to the remote peer, or known to be there
+    sent: VectorClock,
+    /// Whether the version of the remote peer is known. Nothing is sent
+    /// before it.
+    remote_known: bool,
+}
+
+impl SyncSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message that starts the sync. The remote peer replies with the
+    /// updates this db doesn't have, or its version.
+    pub fn hello(&self, db: &LwwDb) -> SyncMessage {
+        SyncMessage::Hello {
+            version: db.version().clone(),
+        }
+    }
+
+    /// The version the remote peer has acknowledged
+    pub fn acked(&self) -> &VectorClock {
+        &self.acked
+    }
+
+    /// Send the ops that are not acknowledged again in the next
+    /// [SyncSession::poll]
+    pub fn reset(&mut self) {
+        self.sent = self.acked.clone();
+    }
+
+    /// The updates of the db that are not sent to the remote peer yet. Call
+    /// it after the local changes.
+    pub fn poll(&mut self, db: &LwwDb) -> Option<SyncMessage> {
+        if !self.remote_known || self.sent.includes_clock(db.version()) {
+            return None;
+        }
+
+        let from = self.sent.clone();
+        self.sent.merge(db.version());
+        match db.try_export_updates(from.clone()) {
+            Ok(payload) => Some(SyncMessage::Updates {
+                from,
+                version: db.version().clone(),
+                payload,
+            }),
+            // it only fails when the history before `from` is not kept
+            Err(_) => Some(SyncMessage::NeedSnapshot {
+                frontier: db.shallow_frontier().cloned().unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Handle a message from the remote peer, and return the replies.
+    ///
+    /// It fails with [LwwError::NeedFullSnapshot] if the remote peer can't
+    /// send the updates this db doesn't have. The db needs to be restored
+    /// from a snapshot of a peer that has them.
+    pub fn handle(&mut self, db: &mut LwwDb, msg: SyncMessage) -> LwwResult<Vec<SyncMessage>> {
+        let mut replies = Vec::new();
+        match msg {
+            SyncMessage::Hello { version } => {
+                self.learn(&version);
+                self.reset();
+                if self.sent.includes_clock(db.version()) {
+                    replies.push(SyncMessage::Ack {
+                        version: db.version().clone(),
+                    });
+                }
+            }
+            SyncMessage::Updates {
+                from,
+                version,
+                payload,
+            } => {
+                if !db.version().includes_clock(&from) {
+                    // the updates before them are lost
+                    return Ok(vec![self.hello(db)]);
+                }
+
+                db.try_import_updates(&payload)?;
+                self.learn(&version);
+                replies.push(SyncMessage::Ack {
+                    version: db.version().clone(),
+                });
+            }
+            SyncMessage::Ack { version } => self.learn(&version),
+            SyncMessage::NeedSnapshot { .. } => return Err(LwwError::NeedFullSnapshot),
+        }
+
+        replies.extend(self.poll(db));
+        Ok(replies)
+    }
+
+    /// The remote peer has the ops in `version`
+    fn learn(&mut self, version: &VectorClock) {
+        self.remote_known = true;
+        self.acked.merge(version);
+        self.sent.merge(version);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    struct Node {
+        db: LwwDb,
+        session: SyncSession,
+    }
+
+    impl Node {
+        fn new(id: u64) -> Self {
+            let mut db = LwwDb::new();
+            db.set_peer(id);
+            Node {
+                db,
+                session: SyncSession::new(),
+            }
+        }
+    }
+
+    /// Deliver `first` to `b`, then the replies in both directions until
+    /// there is none left. `fault` is called with the index of every reply,
+    /// and returns how many times it's delivered.
+    fn run(a: &mut Node, b: &mut Node, first: SyncMessage, mut fault: impl FnMut(usize) -> usize) {
+        let mut queue = VecDeque::from([(true, first.encode())]);
+        let mut sent = 0;
+        while let Some((to_b, bytes)) = queue.pop_front() {
+            let (node, back) = if to_b {
+                (&mut *b, false)
+            } else {
+                (&mut *a, true)
+            };
+            let msg = SyncMessage::try_decode(&bytes).unwrap();
+            for reply in node.session.handle(&mut node.db, msg).unwrap() {
+                for _ in 0..fault(sent) {
+                    queue.push_back((back, reply.encode()));
+                }
+                sent += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn sync_two_peers() {
+        let mut a = Node::new(1);
+        let mut b = Node::new(2);
+        a.db.set("table", "a", "x", 1);
+        b.db.set("table", "b", "x", 2);
+        let hello = a.session.hello(&a.db);
+        run(&mut a, &mut b, hello, |_| 1);
+        assert!(a.db.check_eq(&mut b.db));
+        assert_eq!(a.session.acked(), b.db.version());
+
+        // only the new ops are sent in the next round
+        let acked = a.session.acked().clone();
+        a.db.set("table", "c", "x", 3);
+        let msg = a.session.poll(&a.db).unwrap();
+        let SyncMessage::Updates { from, .. } = &msg else {
+            panic!("expected updates");
+        };
+        assert_eq!(from, &acked);
+        assert!(a.session.poll(&a.db).is_none());
+        run(&mut a, &mut b, msg, |_| 1);
+        assert!(a.db.check_eq(&mut b.db));
+    }
+
+    #[test]
+    fn dropped_messages() {
+        let mut a = Node::new(1);
+        let mut b = Node::new(2);
+        a.db.set("table", "a", "x", 1);
+        b.db.set("table", "b", "x", 2);
+        let hello = a.session.hello(&a.db);
+        run(&mut a, &mut b, hello, |_| 1);
+
+        // the updates are lost
+        a.db.set("table", "a", "x", 10);
+        a.session.poll(&a.db).unwrap();
+        // the receiver finds the gap in the next updates and asks again
+        a.db.set("table", "c", "x", 3);
+        let msg = a.session.poll(&a.db).unwrap();
+        run(&mut a, &mut b, msg, |_| 1);
+        assert!(a.db.check_eq(&mut b.db));
+        assert_eq!(b.db.get_cell("table", "a", "x"), Some(&10.into()));
+
+        // the last updates and the replies are lost
+        b.db.set("table", "b", "x", 20);
+        b.session.poll(&b.db).unwrap();
+        b.session.reset();
+        let msg = b.session.poll(&b.db).unwrap();
+        run(&mut b, &mut a, msg, |i| if i == 0 { 0 } else { 1 });
+        assert!(!b.session.acked().includes_clock(b.db.version()));
+        b.session.reset();
+        let msg = b.session.poll(&b.db).unwrap();
+        run(&mut b, &mut a, msg, |_| 1);
+        assert!(a.db.check_eq(&mut b.db));
+        assert_eq!(b.session.acked(), a.db.version());
+    }
+
+    #[test]
+    fn duplicated_messages() {
+        let mut a = Node::new(1);
+        let mut b = Node::new(2);
+        for i in 0..10 {
+            a.db.set("table", &i.to_string(), "x", i);
+            b.db.increment("table", &i.to_string(), "n", 1);
+        }
+
+        let hello = a.session.hello(&a.db);
+        run(&mut a, &mut b, hello, |i| if i < 8 { 2 } else { 1 });
+        assert!(a.db.check_eq(&mut b.db));
+        assert_eq!(a.db.get_cell("table", "3", "n"), Some(&1.into()));
+    }
+
+    #[test]
+    fn need_snapshot() {
+        let mut a = Node::new(1);
+        a.db.set("table", "a", "x", 1);
+        let mut b = Node::new(2);
+        b.db = LwwDb::from_snapshot(&a.db.export_shallow_snapshot());
+        let mut c = Node::new(3);
+        let reply = b.session.handle(&mut b.db, c.session.hello(&c.db)).unwrap();
+        assert!(matches!(reply[..], [SyncMessage::NeedSnapshot { .. }]));
+        assert!(matches!(
+            c.session.handle(&mut c.db, reply[0].clone()),
+            Err(LwwError::NeedFullSnapshot)
+        ));
+
+        // a peer at the frontier can sync with it
+        b.session = SyncSession::new();
+        let hello = a.session.hello(&a.db);
+        b.db.set("table", "b", "x", 2);
+        run(&mut a, &mut b, hello, |_| 1);
+        assert!(a.db.check_eq(&mut b.db));
+    }
+}
@@ -1,19 +1,39 @@
-use std::{fmt::Display, iter::once};
+use std::{fmt::Display, iter::once, ops::RangeBounds};
 
 use fxhash::FxHashMap;
 use smol_str::SmolStr;
 
 use crate::{
-    clock::{Lamport, OpId, Peer},
+    clock::{OpId, Peer, VectorClock},
+    error::{LwwError, LwwResult},
     value::Value,
 };
 
+mod column;
+mod counter;
+mod index;
+mod mv_register;
+use column::reorder_vec_by_indexes;
+pub use column::Column;
+pub(crate) use counter::Counter;
+pub use index::IndexKind;
+pub(crate) use index::{compare_values, total_cmp, Index, IndexKey};
+pub(crate) use mv_register::MvRegister;
+
 #[derive(Debug, Clone, Default)]
 pub struct LwwTable {
     pub(crate) row_id_to_idx: FxHashMap<SmolStr, usize>,
     pub(crate) rows: Vec<Row>,
     pub(crate) cols: FxHashMap<SmolStr, Column>,
     pub(crate) removed: Option<OpId>,
+    /// The counter cells, col -> row -> counter. The sum of a counter is
+    /// written to the column with the id of its latest entry.
+    pub(crate) counters: FxHashMap<SmolStr, FxHashMap<SmolStr, Counter>>,
+    /// The multi-value cells, col -> row -> register. The latest value of a
+    /// register is also written to the column.
+    pub(crate) mv: FxHashMap<SmolStr, FxHashMap<SmolStr, MvRegister>>,
+    /// The secondary indexes, col -> index
+    pub(crate) indexes: FxHashMap<SmolStr, Index>,
 }
 
 impl Display for LwwTable {
@@ -29,25 +49,6 @@ pub struct Row {
     pub(crate) deleted: Option<OpId>,
 }
 
-#[derive(Debug, Clone, PartialEq, Default)]
-pub struct Column {
-    pub(crate) value: Vec<Value>,
-    pub(crate) lamport: Vec<Lamport>,
-    pub(crate) peer: Vec<Peer>,
-    pub(crate) num: usize,
-}
-
-impl Column {
-    pub(crate) fn with_len(len: usize) -> Column {
-        Column {
-            value: vec![Value::Null; len],
-            lamport: vec![0; len],
-            peer: vec![0; len],
-            num: 0,
-        }
-    }
-}
-
 impl LwwTable {
     pub fn new() -> Self {
         Self::default()
@@ -62,7 +63,7 @@ impl LwwTable {
 
         for (col_name, col) in &self.cols {
             table.push_column(
-                once(col_name.to_string()).chain(col.value.iter().map(|v| v.to_string())),
+                once(col_name.to_string()).chain((0..col.len()).map(|i| col.value(i).to_string())),
             );
         }
         table.build()
@@ -81,27 +82,34 @@ impl LwwTable {
             deleted: None,
         });
         for (_, col) in self.cols.iter_mut() {
-            col.value.push(Value::Null);
-            col.lamport.push(0);
-            col.peer.push(0);
+            col.push_empty();
         }
 
         idx
     }
 
     fn ensure_col(&mut self, col_name: &str) -> &mut Column {
-        self.cols.entry(col_name.into()).or_insert_with(|| {
-            let len = self.rows.len();
-            Column {
-                value: vec![Value::Null; len],
-                lamport: vec![0; len],
-                peer: vec![0; len],
-                num: 0,
-            }
-        })
+        let len = self.rows.len();
+        self.cols
+            .entry(col_name.into())
+            .or_insert_with(|| Column::with_len(len))
     }
 
     pub fn set(&mut self, row: &str, col: &str, v: Value, id: OpId) -> bool {
+        self.set_with_context(row, col, v, id, None)
+    }
+
+    /// Write the cell. If the write has a context, which is the version of the
+    /// writer, or the cell is already a multi-value cell, the write is kept
+    /// with the concurrent writes until a write that has seen it supersedes it.
+    pub fn set_with_context(
+        &mut self,
+        row: &str,
+        col: &str,
+        v: Value,
+        id: OpId,
+        context: Option<VectorClock>,
+    ) -> bool {
         if id.lamport == 0 {
             assert!(id.peer == 0, "lamport is 0, peer should be 0");
             assert!(v == Value::Null, "lamport is 0, value should be null");
@@ -121,21 +129,251 @@ impl LwwTable {
             }
         }
 
-        let col = self.ensure_col(col);
-        if id < OpId::new(col.lamport[row_idx], col.peer[row_idx]) {
+        let kept_by_counter = self.reset_counter(row_idx, col, &v, id);
+        let has_register = self.mv_register(row, col).is_some();
+        if context.is_some() || has_register {
+            // The existing value becomes a value of the new register
+            let seed = self
+                .cols
+                .get(col)
+                .filter(|c| !has_register && c.id(row_idx).lamport != 0)
+                .map(|c| (c.id(row_idx), c.value(row_idx).clone()));
+            let rows = self.mv.entry(col.into()).or_default();
+            let reg = rows.entry(row.into()).or_default();
+            if let Some((seed_id, seed_value)) = seed {
+                reg.merge(seed_id, seed_value, None);
+            }
+
+            if !reg.merge(id, v.clone(), context) {
+                if !has_register {
+                    rows.remove(row);
+                }
+
+                return false;
+            }
+
+            // a superseded write without context only changes the register
+            if reg.contains(id) && id > self.ensure_col(col).id(row_idx) {
+                self.write_cell(row_idx, col, v, id);
+            }
+
+            return true;
+        }
+
+        if id < self.ensure_col(col).id(row_idx) {
+            // the sum of the newer entries overwrites the set, but the set
+            // still drops the older entries
+            return kept_by_counter;
+        }
+
+        self.write_cell(row_idx, col, v, id);
+        true
+    }
+
+    /// Write the cell and keep the index of the column up to date
+    fn write_cell(&mut self, row_idx: usize, col_name: &str, v: Value, id: OpId) {
+        let col = self
+            .cols
+            .entry(col_name.into())
+            .or_insert_with(|| Column::with_len(self.rows.len()));
+        if let Some(index) = self.indexes.get_mut(col_name) {
+            index.update(&self.rows[row_idx].row_id, col.value(row_idx), &v);
+        }
+
+        col.set(row_idx, v, id);
+    }
+
+    /// Index the values of the column. It replaces the existing index of the
+    /// column.
+    pub fn create_index(&mut self, col_name: &str, kind: IndexKind) {
+        let mut index = Index::new(kind);
+        if let Some(col) = self.cols.get(col_name) {
+            for (i, value, _) in col.iter() {
+                index.update(&self.rows[i].row_id, &Value::Null, value);
+            }
+        }
+
+        self.indexes.insert(col_name.into(), index);
+    }
+
+    pub fn drop_index(&mut self, col_name: &str) -> bool {
+        self.indexes.remove(col_name).is_some()
+    }
+
+    pub fn index_kind(&self, col_name: &str) -> Option<IndexKind> {
+        self.indexes.get(col_name).map(|i| i.kind())
+    }
+
+    /// The ids of the rows whose value in the column equals `value`, in no
+    /// particular order
+    pub fn find_rows(&self, col_name: &str, value: &Value) -> LwwResult<Vec<&SmolStr>> {
+        let index = self
+            .indexes
+            .get(col_name)
+            .ok_or(LwwError::IndexUnavailable)?;
+        Ok(index.get(value).collect())
+    }
+
+    /// The ids of the rows whose value in the column is in the range, ordered
+    /// by value. The column needs an [IndexKind::Ordered] index.
+    pub fn find_rows_in_range(
+        &self,
+        col_name: &str,
+        range: impl RangeBounds<Value>,
+    ) -> LwwResult<Vec<&SmolStr>> {
+        let index = self
+            .indexes
+            .get(col_name)
+            .ok_or(LwwError::IndexUnavailable)?;
+        Ok(index
+            .range(range)
+            .ok_or(LwwError::IndexUnavailable)?
+            .collect())
+    }
+
+    pub(crate) fn mv_register(&self, row: &str, col: &str) -> Option<&MvRegister> {
+        self.mv.get(col).and_then(|c| c.get(row))
+    }
+
+    /// Remove the multi-value register values older than `id` in the row, or
+    /// in the whole table if `row` is None
+    fn remove_mv_values(&mut self, row: Option<&str>, id: OpId) {
+        for rows in self.mv.values_mut() {
+            for (row_id, reg) in rows.iter_mut() {
+                if row.is_none_or(|r| r == row_id) {
+                    reg.retain_newer(id);
+                }
+            }
+
+            rows.retain(|_, reg| !reg.is_empty());
+        }
+
+        self.mv.retain(|_, rows| !rows.is_empty());
+    }
+
+    /// The total of the increments made by the peer to the counter cell
+    pub(crate) fn counter_total(&self, row: &str, col: &str, peer: Peer) -> i64 {
+        self.counter(row, col).map(|c| c.total(peer)).unwrap_or(0)
+    }
+
+    pub(crate) fn counter(&self, row: &str, col: &str) -> Option<&Counter> {
+        self.counters.get(col).and_then(|c| c.get(row))
+    }
+
+    /// Merge the entry of `id.peer` into the counter cell. Returns false if the
+    /// entry is outdated.
+    pub fn merge_counter(&mut self, row: &str, col: &str, id: OpId, total: i64) -> bool {
+        if id.lamport == 0 || self.removed.is_some_and(|r| id < r) {
+            return false;
+        }
+
+        let row_idx = self.ensure_row(row);
+        if self.rows[row_idx].deleted.is_some_and(|d| id < d) {
+            return false;
+        }
+
+        // Without a counter, the cell is owned by a set, which drops the
+        // older entries
+        let set = match self.counter(row, col) {
+            Some(_) => None,
+            None => self
+                .cols
+                .get(col)
+                .map(|c| (c.id(row_idx), c.value(row_idx)))
+                .filter(|(set, _)| set.lamport != 0),
+        };
+        if set.is_some_and(|(set, _)| id < set) {
             return false;
         }
 
-        if col.lamport[row_idx] == 0 {
-            col.num += 1;
+        let set = set.map(|(set, v)| (set, v.clone()));
+        let counter = self
+            .counters
+            .entry(col.into())
+            .or_default()
+            .entry(row.into())
+            .or_default();
+        if let Some((set, v)) = set {
+            counter.reset(set, v);
         }
 
-        col.value[row_idx] = v;
-        col.lamport[row_idx] = id.lamport;
-        col.peer[row_idx] = id.peer;
+        if !counter.merge(id, total) {
+            return false;
+        }
+
+        self.materialize_counter(row_idx, col);
         true
     }
 
+    /// Drop the counter entries older than the set of the cell, like a row
+    /// deletion drops the entries older than it. Returns true if the counter
+    /// keeps the set as its latest one.
+    fn reset_counter(&mut self, row_idx: usize, col_name: &str, v: &Value, id: OpId) -> bool {
+        let row = &self.rows[row_idx].row_id;
+        let Some(rows) = self.counters.get_mut(col_name) else {
+            return false;
+        };
+        let Some(counter) = rows.get_mut(row) else {
+            return false;
+        };
+
+        counter.reset(id, v.clone());
+        if counter.is_empty() {
+            rows.remove(row);
+            if rows.is_empty() {
+                self.counters.remove(col_name);
+            }
+
+            return false;
+        }
+
+        let kept = counter.last_set().is_some_and(|(set, _)| set == id);
+        self.materialize_counter(row_idx, col_name);
+        kept
+    }
+
+    /// Write the sum of the counter to the column, unless the cell was
+    /// overwritten by a newer `set`
+    fn materialize_counter(&mut self, row_idx: usize, col_name: &str) {
+        let row = &self.rows[row_idx].row_id;
+        let Some(counter) = self.counters.get(col_name).and_then(|c| c.get(row)) else {
+            return;
+        };
+        let Some(id) = counter.max_id() else {
+            return;
+        };
+
+        let sum = counter.sum();
+        if id >= self.ensure_col(col_name).id(row_idx) {
+            self.write_cell(row_idx, col_name, Value::I64(sum), id);
+        }
+    }
+
+    /// Remove the counter entries older than `id` in the row, or in the whole
+    /// table if `row` is None, and update the sums
+    fn remove_counter_entries(&mut self, row: Option<&str>, id: OpId) {
+        let mut changed = Vec::new();
+        for (col, rows) in self.counters.iter_mut() {
+            for (row_id, counter) in rows.iter_mut() {
+                if row.is_some_and(|r| r != row_id) {
+                    continue;
+                }
+
+                counter.retain_newer(id);
+                changed.push((col.clone(), row_id.clone()));
+            }
+
+            rows.retain(|_, c| !c.is_empty());
+        }
+
+        self.counters.retain(|_, rows| !rows.is_empty());
+        for (col, row) in changed {
+            if let Some(idx) = self.row_id_to_idx.get(&row) {
+                self.materialize_counter(*idx, &col);
+            }
+        }
+    }
+
     pub fn check_eq(&mut self, other: &mut Self) -> bool {
         if self.rows.len() != other.rows.len() {
             eprintln!("row number not equal");
@@ -166,6 +404,16 @@ impl LwwTable {
             return false;
         }
 
+        if self.counters != other.counters {
+            eprintln!("counters not equal");
+            return false;
+        }
+
+        if self.mv != other.mv {
+            eprintln!("multi-value cells not equal");
+            return false;
+        }
+
         true
     }
 
@@ -174,6 +422,12 @@ impl LwwTable {
     }
 
     pub fn delete_row(&mut self, row: &str, id: OpId) -> bool {
+        if let Some(removed) = self.removed {
+            if id < removed {
+                return false;
+            }
+        }
+
         let idx = self.ensure_row(row);
         let row = &mut self.rows[idx];
         if let Some(removed) = &row.deleted {
@@ -184,17 +438,15 @@ impl LwwTable {
 
         let mut to_remove = vec![];
         for (c, col) in self.cols.iter_mut() {
-            if id < OpId::new(col.lamport[idx], col.peer[idx]) {
+            if id < col.id(idx) {
                 continue;
             }
 
-            if col.lamport[idx] != 0 {
-                col.num -= 1;
+            if let Some(index) = self.indexes.get_mut(c) {
+                index.update(&row.row_id, col.value(idx), &Value::Null);
             }
 
-            col.value[idx] = Value::Null;
-            col.lamport[idx] = 0;
-            col.peer[idx] = 0;
+            col.clear(idx);
             if col.num == 0 {
                 to_remove.push(c.clone());
             }
@@ -205,6 +457,9 @@ impl LwwTable {
         }
 
         row.deleted = Some(id);
+        let row_id = row.row_id.clone();
+        self.remove_counter_entries(Some(&row_id), id);
+        self.remove_mv_values(Some(&row_id), id);
         true
     }
 
@@ -215,19 +470,125 @@ impl LwwTable {
             }
         }
 
-        // FIXME: should not clear the rows with clock > id
-        self.cols.clear();
-        self.rows.clear();
+        // Only the cells and row tombstones older than the deletion are removed,
+        // so the concurrent writes with greater ids survive no matter which
+        // one arrives first
+        self.cols.retain(|name, col| {
+            let to_clear: Vec<usize> = col
+                .iter()
+                .filter(|(_, _, cell_id)| *cell_id < id)
+                .map(|(i, _, _)| i)
+                .collect();
+            let mut index = self.indexes.get_mut(name);
+            for i in to_clear {
+                if let Some(index) = &mut index {
+                    index.update(&self.rows[i].row_id, col.value(i), &Value::Null);
+                }
+
+                col.clear(i);
+            }
+
+            col.num > 0
+        });
+
+        for row in self.rows.iter_mut() {
+            if matches!(row.deleted, Some(d) if d < id) {
+                row.deleted = None;
+            }
+        }
+
+        let mut keep: Vec<bool> = self.rows.iter().map(|r| r.deleted.is_some()).collect();
+        for col in self.cols.values() {
+            for (i, _, _) in col.iter() {
+                keep[i] = true;
+            }
+        }
+
+        self.retain_rows(&keep);
         self.removed = Some(id);
+        self.remove_counter_entries(None, id);
+        self.remove_mv_values(None, id);
         true
     }
 
+    /// Drop the row tombstones, the table tombstone and the deleted cells
+    /// whose ids are included in `stable`, then remove the rows that have
+    /// nothing left. Returns the number of dropped tombstones and the number
+    /// of removed rows.
+    pub(crate) fn gc(&mut self, stable: &VectorClock) -> (usize, usize) {
+        let mut tombstones = 0;
+        if self.removed.is_some_and(|id| stable.includes(id)) {
+            self.removed = None;
+            tombstones += 1;
+        }
+
+        for row in self.rows.iter_mut() {
+            if row.deleted.is_some_and(|id| stable.includes(id)) {
+                row.deleted = None;
+                tombstones += 1;
+            }
+        }
+
+        // The null values of the multi-value cells are kept, because they
+        // are the latest values of their registers
+        let (rows, mv) = (&self.rows, &self.mv);
+        self.cols.retain(|name, col| {
+            let to_clear: Vec<usize> = col
+                .iter()
+                .filter(|(i, v, id)| {
+                    matches!(v, Value::Null)
+                        && stable.includes(*id)
+                        && !mv
+                            .get(name)
+                            .is_some_and(|m| m.contains_key(&rows[*i].row_id))
+                })
+                .map(|(i, _, _)| i)
+                .collect();
+            tombstones += to_clear.len();
+            for i in to_clear {
+                col.clear(i);
+            }
+
+            col.num > 0
+        });
+
+        let mut keep: Vec<bool> = self.rows.iter().map(|r| r.deleted.is_some()).collect();
+        for col in self.cols.values() {
+            for (i, _, _) in col.iter() {
+                keep[i] = true;
+            }
+        }
+
+        let removed_rows = keep.iter().filter(|keep| !**keep).count();
+        self.retain_rows(&keep);
+        (tombstones, removed_rows)
+    }
+
+    /// Remove the rows whose `keep` flag is false
+    fn retain_rows(&mut self, keep: &[bool]) {
+        debug_assert_eq!(keep.len(), self.rows.len());
+        if keep.iter().all(|x| *x) {
+            return;
+        }
+
+        let mut flags = keep.iter();
+        self.rows.retain(|_| *flags.next().unwrap());
+        for col in self.cols.values_mut() {
+            col.retain_rows(keep);
+        }
+
+        self.row_id_to_idx = self
+            .rows
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.row_id.clone(), i))
+            .collect();
+    }
+
     pub fn sort(&mut self) {
         let indexes = sort_vecs_based_on_first(&mut self.rows, |r| r.row_id.as_str());
         for col in self.cols.values_mut() {
-            reorder_vec_by_indexes(&mut col.value, &indexes);
-            reorder_vec_by_indexes(&mut col.lamport, &indexes);
-            reorder_vec_by_indexes(&mut col.peer, &indexes);
+            col.reorder(&indexes);
         }
 
         self.row_id_to_idx = self
@@ -243,19 +604,45 @@ impl LwwTable {
         idx.map(|idx| {
             self.cols
                 .iter()
-                .map(move |(col_name, col)| (col_name.as_str(), &col.value[*idx]))
+                .map(move |(col_name, col)| (col_name.as_str(), col.value(*idx)))
         })
         .into_iter()
         .flatten()
     }
 
-    pub(crate) fn iter_row_with_id(&self, row: &str) -> impl Iterator<Item = RowValue> + '_ {
+    /// A row is deleted if it has a tombstone and no value written after it
+    pub(crate) fn is_deleted(&self, row_idx: usize) -> bool {
+        self.rows[row_idx].deleted.is_some()
+            && self.cols.values().all(|c| *c.value(row_idx) == Value::Null)
+    }
+
+    /// The non-null values of the row, or None if the row doesn't exist or
+    /// is deleted. It's what a [crate::Query] sees of the row.
+    pub(crate) fn live_row_values(&self, row: &str) -> Option<Vec<(SmolStr, Value)>> {
+        let idx = *self.row_id_to_idx.get(row)?;
+        (!self.is_deleted(idx)).then(|| self.row_values(row))
+    }
+
+    /// The non-null values of the row
+    pub(crate) fn row_values(&self, row: &str) -> Vec<(SmolStr, Value)> {
+        let Some(idx) = self.row_id_to_idx.get(row) else {
+            return Vec::new();
+        };
+
+        self.cols
+            .iter()
+            .filter(|(_, col)| *col.value(*idx) != Value::Null)
+            .map(|(name, col)| (name.clone(), col.value(*idx).clone()))
+            .collect()
+    }
+
+    pub(crate) fn iter_row_with_id(&self, row: &str) -> impl Iterator<Item = RowValue<'_>> + '_ {
         let idx = self.row_id_to_idx.get(row);
         idx.map(|idx| {
             self.cols.iter().map(move |(col_name, col)| RowValue {
                 col_name,
-                id: OpId::new(col.lamport[*idx], col.peer[*idx]),
-                value: &col.value[*idx],
+                id: col.id(*idx),
+                value: col.value(*idx),
             })
         })
         .into_iter()
@@ -265,7 +652,7 @@ impl LwwTable {
     pub(crate) fn get_cell(&self, row: &str, col: &str) -> Option<&Value> {
         let row_idx = self.row_id_to_idx.get(row)?;
         let col = self.cols.get(col)?;
-        col.value.get(*row_idx)
+        Some(col.value(*row_idx))
     }
 }
 
@@ -286,13 +673,10 @@ fn sort_vecs_based_on_first<T: Clone, U: Ord + ?Sized>(
     indexes
 }
 
-fn reorder_vec_by_indexes<T: Clone>(a: &mut Vec<T>, indexes: &[usize]) {
-    let new_a: Vec<T> = indexes.iter().map(|x| a[*x].clone()).collect();
-    *a = new_a;
-}
-
 #[cfg(test)]
 mod test {
+    use itertools::Itertools;
+
     use super::*;
 
     #[test]
@@ -301,4 +685,158 @@ mod test {
         sort_vecs_based_on_first(&mut v, |x| x);
         assert_eq!(v, vec![0, 1, 2, 3, 4, 5, 6, 7])
     }
+
+    #[derive(Debug, Clone)]
+    enum TestOp {
+        Set(&'static str, &'static str, i64),
+        DeleteRow(&'static str),
+        DeleteTable,
+        /// Merge a counter entry with the total of the peer
+        Counter(&'static str, &'static str, i64),
+        /// A write to a multi-value cell with the version of the writer
+        MvSet(&'static str, &'static str, i64, &'static [(Peer, u32)]),
+    }
+
+    fn apply(table: &mut LwwTable, id: OpId, op: &TestOp) {
+        match op {
+            TestOp::Set(row, col, v) => table.set(row, col, (*v).into(), id),
+            TestOp::DeleteRow(row) => table.delete_row(row, id),
+            TestOp::DeleteTable => table.delete_table(id),
+            TestOp::Counter(row, col, total) => table.merge_counter(row, col, id, *total),
+            TestOp::MvSet(row, col, v, context) => {
+                let mut clock = VectorClock::new();
+                for (peer, lamport) in context.iter() {
+                    clock.extend_to_include(OpId::new(*lamport, *peer));
+                }
+                table.set_with_context(row, col, (*v).into(), id, Some(clock))
+            }
+        };
+    }
+
+    /// Apply the ops in every order and check all the tables are equal
+    fn check_convergence(ops: &[(OpId, TestOp)]) -> LwwTable {
+        let mut expected: Option<LwwTable> = None;
+        for perm in ops.iter().permutations(ops.len()) {
+            let mut table = LwwTable::new();
+            for (id, op) in perm {
+                apply(&mut table, *id, op);
+            }
+
+            match &mut expected {
+                Some(expected) => assert!(expected.check_eq(&mut table)),
+                None => expected = Some(table),
+            }
+        }
+
+        expected.unwrap()
+    }
+
+    #[test]
+    fn delete_table_keeps_later_writes() {
+        let mut table = check_convergence(&[
+            (OpId::new(1, 1), TestOp::Set("a", "x", 1)),
+            (OpId::new(2, 1), TestOp::Set("b", "y", 2)),
+            (OpId::new(3, 2), TestOp::DeleteTable),
+            (OpId::new(4, 1), TestOp::Set("a", "y", 3)),
+            (OpId::new(2, 3), TestOp::DeleteRow("c")),
+            (OpId::new(5, 3), TestOp::DeleteRow("d")),
+        ]);
+        table.sort();
+        assert_eq!(table.get_cell("a", "y"), Some(&Value::I64(3)));
+        assert_eq!(table.get_cell("a", "x"), None);
+        assert_eq!(table.get_cell("b", "y"), None);
+        assert!(!table.row_id_to_idx.contains_key("b"));
+        assert!(!table.row_id_to_idx.contains_key("c"));
+        assert!(table.rows[table.row_id_to_idx["d"]].deleted.is_some());
+    }
+
+    #[test]
+    fn concurrent_delete_table_and_row() {
+        check_convergence(&[
+            (OpId::new(1, 1), TestOp::Set("a", "x", 1)),
+            (OpId::new(2, 2), TestOp::DeleteRow("a")),
+            (OpId::new(3, 1), TestOp::DeleteTable),
+            (OpId::new(3, 3), TestOp::Set("a", "x", 2)),
+            (OpId::new(2, 3), TestOp::DeleteTable),
+            (OpId::new(1, 2), TestOp::Set("b", "x", 2)),
+        ]);
+    }
+
+    #[test]
+    fn concurrent_counter_increments() {
+        let table = check_convergence(&[
+            (OpId::new(1, 1), TestOp::Counter("a", "n", 1)),
+            (OpId::new(2, 1), TestOp::Counter("a", "n", 3)),
+            (OpId::new(1, 2), TestOp::Counter("a", "n", 10)),
+            (OpId::new(2, 3), TestOp::Counter("b", "n", 5)),
+            (OpId::new(3, 3), TestOp::DeleteRow("b")),
+            (OpId::new(4, 3), TestOp::Counter("b", "n", 1)),
+        ]);
+        assert_eq!(table.get_cell("a", "n"), Some(&Value::I64(13)));
+        assert_eq!(table.get_cell("b", "n"), Some(&Value::I64(1)));
+
+        // a newer set drops the older entries, like a row deletion
+        let table = check_convergence(&[
+            (OpId::new(1, 1), TestOp::Counter("a", "n", 1)),
+            (OpId::new(2, 2), TestOp::Set("a", "n", 100)),
+            (OpId::new(1, 2), TestOp::Counter("a", "n", 2)),
+        ]);
+        assert_eq!(table.get_cell("a", "n"), Some(&Value::I64(100)));
+        assert!(table.counter("a", "n").is_none());
+        let table = check_convergence(&[
+            (OpId::new(1, 1), TestOp::Counter("a", "n", 1)),
+            (OpId::new(2, 2), TestOp::Set("a", "n", 100)),
+            (OpId::new(3, 2), TestOp::Counter("a", "n", 2)),
+            (OpId::new(4, 3), TestOp::Counter("a", "n", 5)),
+            (OpId::new(3, 3), TestOp::Set("a", "n", 7)),
+        ]);
+        assert_eq!(table.get_cell("a", "n"), Some(&Value::I64(5)));
+    }
+
+    #[test]
+    fn concurrent_multi_value_writes() {
+        let table = check_convergence(&[
+            (OpId::new(1, 1), TestOp::MvSet("a", "x", 1, &[])),
+            (OpId::new(1, 2), TestOp::MvSet("a", "x", 2, &[])),
+            (OpId::new(2, 3), TestOp::MvSet("a", "x", 3, &[(1, 1)])),
+            (OpId::new(3, 3), TestOp::Set("a", "x", 4)),
+            (OpId::new(1, 4), TestOp::MvSet("b", "x", 1, &[])),
+            (OpId::new(2, 5), TestOp::DeleteRow("b")),
+        ]);
+        let values: Vec<_> = table
+            .mv_register("a", "x")
+            .unwrap()
+            .values()
+            .iter()
+            .map(|v| v.value.clone())
+            .collect();
+        assert_eq!(values, vec![2.into(), 3.into(), 4.into()]);
+        assert_eq!(table.get_cell("a", "x"), Some(&Value::I64(4)));
+        assert!(table.mv_register("b", "x").is_none());
+    }
+
+    #[test]
+    fn superseded_writes_without_context() {
+        let values = |table: &LwwTable| -> Vec<Value> {
+            let reg = table.mv_register("a", "x").unwrap();
+            reg.values().iter().map(|v| v.value.clone()).collect()
+        };
+
+        // the write of 3 has seen 1, which replaced the older 2
+        let ops = [
+            (OpId::new(5, 1), TestOp::Set("a", "x", 1)),
+            (OpId::new(3, 2), TestOp::Set("a", "x", 2)),
+            (OpId::new(6, 3), TestOp::MvSet("a", "x", 3, &[(1, 5)])),
+        ];
+        let table = check_convergence(&ops);
+        assert_eq!(values(&table), vec![3.into()]);
+        assert_eq!(table.get_cell("a", "x"), Some(&Value::I64(3)));
+
+        let mut ops = ops.to_vec();
+        ops.push((OpId::new(7, 2), TestOp::Set("a", "x", 4)));
+        ops.push((OpId::new(4, 4), TestOp::Set("a", "x", 5)));
+        let table = check_convergence(&ops);
+        assert_eq!(values(&table), vec![3.into(), 4.into()]);
+        assert_eq!(table.get_cell("a", "x"), Some(&Value::I64(4)));
+    }
 }
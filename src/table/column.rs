@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    clock::{Lamport, OpId, Peer},
+    value::Value,
+};
+
+static NULL: Value = Value::Null;
+
+/// A column switches to the sparse storage when less than 1/SPARSE_RATIO of its cells have value
+const SPARSE_RATIO: usize = 16;
+/// A column switches to the dense storage when more than 1/DENSE_RATIO of its cells have value
+const DENSE_RATIO: usize = 4;
+
+/// The cells of a column.
+///
+/// It switches between a dense and a sparse representation based on how many
+/// cells have value, so adding a column to a large table doesn't cost O(rows)
+/// memory.
+#[derive(Debug, Clone)]
+pub struct Column {
+    storage: Storage,
+    /// The number of cells that have value
+    pub(crate) num: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Storage {
+    Dense {
+        value: Vec<Value>,
+        lamport: Vec<Lamport>,
+        peer: Vec<Peer>,
+    },
+    Sparse {
+        len: usize,
+        cells: BTreeMap<usize, (Value, OpId)>,
+    },
+}
+
+impl PartialEq for Column {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.num == other.num && self.iter().eq(other.iter())
+    }
+}
+
+impl Default for Column {
+    fn default() -> Self {
+        Self::with_len(0)
+    }
+}
+
+impl Column {
+    /// Create an empty column with `len` rows
+    pub(crate) fn with_len(len: usize) -> Column {
+        Column {
+            storage: Storage::Sparse {
+                len,
+                cells: BTreeMap::new(),
+            },
+            num: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Dense { value, .. } => value.len(),
+            Storage::Sparse { len, .. } => *len,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_sparse(&self) -> bool {
+        matches!(self.storage, Storage::Sparse { .. })
+    }
+
+    /// Get the value of the cell, it's [Value::Null] if the cell is empty
+    pub(crate) fn value(&self, row: usize) -> &Value {
+        match &self.storage {
+            Storage::Dense { value, .. } => &value[row],
+            Storage::Sparse { cells, .. } => cells.get(&row).map(|x| &x.0).unwrap_or(&NULL),
+        }
+    }
+
+    /// Get the id of the op that set the cell, it's `OpId(0, 0)` if the cell is empty
+    pub(crate) fn id(&self, row: usize) -> OpId {
+        match &self.storage {
+            Storage::Dense { lamport, peer, .. } => OpId::new(lamport[row], peer[row]),
+            Storage::Sparse { cells, .. } => {
+                cells.get(&row).map(|x| x.1).unwrap_or(OpId::new(0, 0))
+            }
+        }
+    }
+
+    pub(crate) fn has_value(&self, row: usize) -> bool {
+        self.id(row).lamport != 0
+    }
+
+    /// Iterate over the cells that have value in the order of rows
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = (usize, &Value, OpId)> + '_> {
+        match &self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } => Box::new(
+                value
+                    .iter()
+                    .zip(lamport.iter().zip(peer.iter()))
+                    .enumerate()
+                    .filter(|(_, (_, (l, _)))| **l != 0)
+                    .map(|(i, (v, (l, p)))| (i, v, OpId::new(*l, *p))),
+            ),
+            Storage::Sparse { cells, .. } => {
+                Box::new(cells.iter().map(|(i, (v, id))| (*i, v, *id)))
+            }
+        }
+    }
+
+    pub(crate) fn push_empty(&mut self) {
+        match &mut self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } => {
+                value.push(Value::Null);
+                lamport.push(0);
+                peer.push(0);
+            }
+            Storage::Sparse { len, .. } => *len += 1,
+        }
+
+        self.adjust_storage();
+    }
+
+    /// Set the cell. `id.lamport` should not be 0
+    pub(crate) fn set(&mut self, row: usize, v: Value, id: OpId) {
+        debug_assert!(id.lamport != 0);
+        let is_new = !self.has_value(row);
+        if is_new {
+            self.num += 1;
+        }
+
+        match &mut self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } => {
+                value[row] = v;
+                lamport[row] = id.lamport;
+                peer[row] = id.peer;
+            }
+            Storage::Sparse { cells, .. } => {
+                cells.insert(row, (v, id));
+            }
+        }
+
+        if is_new {
+            self.adjust_storage();
+        }
+    }
+
+    /// Make the cell empty
+    pub(crate) fn clear(&mut self, row: usize) {
+        if !self.has_value(row) {
+            return;
+        }
+
+        self.num -= 1;
+        match &mut self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } => {
+                value[row] = Value::Null;
+                lamport[row] = 0;
+                peer[row] = 0;
+            }
+            Storage::Sparse { cells, .. } => {
+                cells.remove(&row);
+            }
+        }
+
+        self.adjust_storage();
+    }
+
+    /// Remove the rows whose `keep` flag is false
+    pub(crate) fn retain_rows(&mut self, keep: &[bool]) {
+        debug_assert_eq!(keep.len(), self.len());
+        match &mut self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } => {
+                retain_by_flags(value, keep);
+                retain_by_flags(lamport, keep);
+                retain_by_flags(peer, keep);
+            }
+            Storage::Sparse { len, cells } => {
+                let new_idx = new_indexes(keep);
+                *len = keep.iter().filter(|x| **x).count();
+                *cells = std::mem::take(cells)
+                    .into_iter()
+                    .filter_map(|(i, cell)| new_idx[i].map(|i| (i, cell)))
+                    .collect();
+            }
+        }
+
+        self.num = self.iter().count();
+        self.adjust_storage();
+    }
+
+    /// Reorder the rows, the new i-th row is the old `indexes[i]`-th row
+    pub(crate) fn reorder(&mut self, indexes: &[usize]) {
+        match &mut self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } => {
+                reorder_vec_by_indexes(value, indexes);
+                reorder_vec_by_indexes(lamport, indexes);
+                reorder_vec_by_indexes(peer, indexes);
+            }
+            Storage::Sparse { cells, .. } => {
+                let mut new_idx = vec![0; indexes.len()];
+                for (new, old) in indexes.iter().enumerate() {
+                    new_idx[*old] = new;
+                }
+
+                *cells = std::mem::take(cells)
+                    .into_iter()
+                    .map(|(i, cell)| (new_idx[i], cell))
+                    .collect();
+            }
+        }
+    }
+
+    fn adjust_storage(&mut self) {
+        let len = self.len();
+        match &mut self.storage {
+            Storage::Dense {
+                value,
+                lamport,
+                peer,
+            } if self.num * SPARSE_RATIO < len => {
+                let cells = std::mem::take(value)
+                    .into_iter()
+                    .zip(lamport.iter().zip(peer.iter()))
+                    .enumerate()
+                    .filter(|(_, (_, (l, _)))| **l != 0)
+                    .map(|(i, (v, (l, p)))| (i, (v, OpId::new(*l, *p))))
+                    .collect();
+                self.storage = Storage::Sparse { len, cells };
+            }
+            Storage::Sparse { cells, .. } if self.num * DENSE_RATIO > len => {
+                let mut value = vec![Value::Null; len];
+                let mut lamport = vec![0; len];
+                let mut peer = vec![0; len];
+                for (i, (v, id)) in std::mem::take(cells) {
+                    value[i] = v;
+                    lamport[i] = id.lamport;
+                    peer[i] = id.peer;
+                }
+
+                self.storage = Storage::Dense {
+                    value,
+                    lamport,
+                    peer,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+fn new_indexes(keep: &[bool]) -> Vec<Option<usize>> {
+    let mut next = 0;
+    keep.iter()
+        .map(|k| {
+            k.then(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect()
+}
+
+pub(super) fn retain_by_flags<T>(a: &mut Vec<T>, keep: &[bool]) {
+    let mut flags = keep.iter();
+    a.retain(|_| *flags.next().unwrap());
+}
+
+pub(super) fn reorder_vec_by_indexes<T: Clone>(a: &mut Vec<T>, indexes: &[usize]) {
+    let new_a: Vec<T> = indexes.iter().map(|x| a[*x].clone()).collect();
+    *a = new_a;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn switch_storage() {
+        let mut col = Column::with_len(100);
+        assert!(col.is_sparse());
+        for i in 0..30 {
+            col.set(i, Value::I64(i as i64), OpId::new(1, 1));
+        }
+
+        assert!(!col.is_sparse());
+        assert_eq!(col.num, 30);
+        for i in 0..28 {
+            col.clear(i);
+        }
+
+        assert!(col.is_sparse());
+        assert_eq!(col.value(29), &Value::I64(29));
+        assert_eq!(col.value(0), &Value::Null);
+        assert_eq!(col.id(29), OpId::new(1, 1));
+        assert_eq!(col.iter().count(), 2);
+    }
+
+    #[test]
+    fn sparse_eq_dense() {
+        let mut a = Column::with_len(8);
+        let mut b = Column::with_len(8);
+        for i in 0..8 {
+            a.set(i, Value::I64(i as i64), OpId::new(i as Lamport + 1, 1));
+        }
+
+        b.set(7, Value::I64(7), OpId::new(8, 1));
+        for i in 0..7 {
+            a.clear(i);
+        }
+
+        assert_ne!(a.is_sparse(), b.is_sparse());
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn reorder_and_retain() {
+        let mut col = Column::with_len(64);
+        col.set(1, Value::I64(1), OpId::new(1, 1));
+        col.set(3, Value::I64(3), OpId::new(1, 1));
+        let mut indexes: Vec<usize> = (0..64).collect();
+        indexes.swap(0, 3);
+        col.reorder(&indexes);
+        assert_eq!(col.value(0), &Value::I64(3));
+        let mut keep = vec![true; 64];
+        keep[0] = false;
+        col.retain_rows(&keep);
+        assert_eq!(col.len(), 63);
+        assert_eq!(col.num, 1);
+        assert_eq!(col.value(0), &Value::I64(1));
+    }
+}
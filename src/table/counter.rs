@@ -0,0 +1,109 @@
+use fxhash::FxHashMap;
+
+use crate::{
+    clock::{Lamport, OpId, Peer},
+    value::Value,
+};
+
+/// A PN-counter cell. Every peer owns an entry with the total of its own
+/// increments, and the value of the cell is the sum of the entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Counter {
+    entries: FxHashMap<Peer, (Lamport, i64)>,
+    /// The latest set of the cell and its value. The entries older than it
+    /// are dropped, and it's kept so it can be exported after the sum of
+    /// the newer entries overwrites it.
+    set: Option<(OpId, Value)>,
+}
+
+impl Counter {
+    /// The total of the increments made by the peer
+    pub fn total(&self, peer: Peer) -> i64 {
+        self.entries.get(&peer).map(|(_, t)| *t).unwrap_or(0)
+    }
+
+    /// Keep the newer one of the entry and the existing entry of the peer.
+    /// Returns false if the entry is not newer.
+    pub fn merge(&mut self, id: OpId, total: i64) -> bool {
+        if self.set.as_ref().is_some_and(|(set, _)| id < *set) {
+            return false;
+        }
+
+        match self.entries.get(&id.peer) {
+            Some((lamport, _)) if *lamport >= id.lamport => false,
+            _ => {
+                self.entries.insert(id.peer, (id.lamport, total));
+                true
+            }
+        }
+    }
+
+    pub fn sum(&self) -> i64 {
+        self.entries
+            .values()
+            .fold(0i64, |acc, (_, t)| acc.wrapping_add(*t))
+    }
+
+    /// The id of the latest entry, which owns the value of the cell
+    pub fn max_id(&self) -> Option<OpId> {
+        self.iter().map(|(id, _)| id).max()
+    }
+
+    /// Remove the entries older than `id`
+    pub fn retain_newer(&mut self, id: OpId) {
+        self.entries
+            .retain(|peer, (lamport, _)| OpId::new(*lamport, *peer) > id);
+        if self.set.as_ref().is_some_and(|(set, _)| *set < id) {
+            self.set = None;
+        }
+    }
+
+    /// Remove the entries older than the set of the cell, and keep the set
+    /// if it's the latest one
+    pub fn reset(&mut self, id: OpId, value: Value) {
+        self.retain_newer(id);
+        if self.set.as_ref().is_none_or(|(set, _)| *set < id) {
+            self.set = Some((id, value));
+        }
+    }
+
+    /// The latest set of the cell, see [Counter::reset]
+    pub fn last_set(&self) -> Option<(OpId, &Value)> {
+        self.set.as_ref().map(|(id, v)| (*id, v))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (OpId, i64)> + '_ {
+        self.entries
+            .iter()
+            .map(|(peer, (lamport, total))| (OpId::new(*lamport, *peer), *total))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge_keeps_latest_entry_per_peer() {
+        let mut counter = Counter::default();
+        assert!(counter.merge(OpId::new(1, 1), 1));
+        assert!(counter.merge(OpId::new(3, 1), 3));
+        assert!(!counter.merge(OpId::new(2, 1), 2));
+        assert!(counter.merge(OpId::new(2, 2), -1));
+        assert_eq!(counter.sum(), 2);
+        assert_eq!(counter.max_id(), Some(OpId::new(3, 1)));
+        counter.retain_newer(OpId::new(2, 3));
+        assert_eq!(counter.sum(), 3);
+
+        // the entries older than a set are dropped, even if they arrive later
+        counter.reset(OpId::new(4, 2), Value::I64(10));
+        assert!(counter.is_empty());
+        assert!(!counter.merge(OpId::new(3, 2), 5));
+        assert!(counter.merge(OpId::new(5, 1), 1));
+        assert_eq!(counter.sum(), 1);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    ops::{Bound, RangeBounds},
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexKind {
+    /// Equality lookups only
+    Hash,
+    /// Equality and range lookups
+    Ordered,
+}
+
+/// A secondary index from the values of a column to the ids of the rows
+#[derive(Debug, Clone)]
+pub(crate) enum Index {
+    Hash(FxHashMap<IndexKey, FxHashSet<SmolStr>>),
+    Ordered(BTreeMap<IndexKey, FxHashSet<SmolStr>>),
+}
+
+/// A [Value] with a total order. Values of different types are ordered by
+/// type, see [total_cmp]. The numbers are ordered by value, but a
+/// [Value::I64] never equals a [Value::Double]: `I64(2)` is ordered right
+/// before `Double(2.0)`, and they are distinct keys.
+#[derive(Debug, Clone)]
+pub(crate) struct IndexKey(pub Value);
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        total_cmp(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl Hash for IndexKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl Index {
+    pub fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hash => Index::Hash(Default::default()),
+            IndexKind::Ordered => Index::Ordered(Default::default()),
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            Index::Hash(_) => IndexKind::Hash,
+            Index::Ordered(_) => IndexKind::Ordered,
+        }
+    }
+
+    /// Move the row from the entry of `old` to the entry of `new`
+    pub fn update(&mut self, row: &SmolStr, old: &Value, new: &Value) {
+        if old == new {
+            return;
+        }
+
+        if is_indexed(old) {
+            let key = IndexKey(old.clone());
+            let empty = match self {
+                Index::Hash(map) => map.get_mut(&key).is_some_and(|rows| {
+                    rows.remove(row);
+                    rows.is_empty()
+                }),
+                Index::Ordered(map) => map.get_mut(&key).is_some_and(|rows| {
+                    rows.remove(row);
+                    rows.is_empty()
+                }),
+            };
+            if empty {
+                match self {
+                    Index::Hash(map) => map.remove(&key),
+                    Index::Ordered(map) => map.remove(&key),
+                };
+            }
+        }
+
+        if is_indexed(new) {
+            let key = IndexKey(new.clone());
+            match self {
+                Index::Hash(map) => map.entry(key).or_default().insert(row.clone()),
+                Index::Ordered(map) => map.entry(key).or_default().insert(row.clone()),
+            };
+        }
+    }
+
+    pub fn get(&self, value: &Value) -> impl Iterator<Item = &SmolStr> {
+        let key = IndexKey(value.clone());
+        match self {
+            Index::Hash(map) => map.get(&key),
+            Index::Ordered(map) => map.get(&key),
+        }
+        .into_iter()
+        .flatten()
+    }
+
+    /// Returns None if it's not an ordered index
+    pub fn range<'a>(
+        &'a self,
+        range: impl RangeBounds<Value>,
+    ) -> Option<impl Iterator<Item = &'a SmolStr> + 'a> {
+        let Index::Ordered(map) = self else {
+            return None;
+        };
+
+        let bound = |b: Bound<&Value>| match b {
+            Bound::Included(v) => Bound::Included(IndexKey(v.clone())),
+            Bound::Excluded(v) => Bound::Excluded(IndexKey(v.clone())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let (start, end) = (bound(range.start_bound()), bound(range.end_bound()));
+        // BTreeMap::range panics on these ranges
+        let is_empty = match (&start, &end) {
+            (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+                s > e
+            }
+            _ => false,
+        };
+        Some(
+            (!is_empty)
+                .then(|| map.range((start, end)))
+                .into_iter()
+                .flatten()
+                .flat_map(|(_, rows)| rows),
+        )
+    }
+
+    /// The rows whose string value starts with `prefix`. Returns None if it's
+    /// not an ordered index.
+    pub fn prefix<'a>(&'a self, prefix: &'a str) -> Option<impl Iterator<Item = &'a SmolStr> + 'a> {
+        let Index::Ordered(map) = self else {
+            return None;
+        };
+
+        Some(
+            map.range(IndexKey(Value::Str(prefix.into()))..)
+                .take_while(move |(k, _)| matches!(&k.0, Value::Str(s) if s.starts_with(prefix)))
+                .flat_map(|(_, rows)| rows),
+        )
+    }
+}
+
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Null | Value::Deleted => 0,
+        Value::False | Value::True => 1,
+        Value::I64(_) | Value::Double(_) => 2,
+        Value::Str(_) => 3,
+    }
+}
+
+/// The order of [IndexKey]: null < bool < number < string
+pub(crate) fn total_cmp(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => a.cmp(b),
+        (Value::I64(a), Value::Double(b)) => (*a as f64).total_cmp(b).then(Ordering::Less),
+        (Value::Double(a), Value::I64(b)) => a.total_cmp(&(*b as f64)).then(Ordering::Greater),
+        (Value::Double(a), Value::Double(b)) => a.total_cmp(b),
+        (Value::Str(a), Value::Str(b)) => a.cmp(b),
+        (Value::False, Value::True) => Ordering::Less,
+        (Value::True, Value::False) => Ordering::Greater,
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Compare two values of the same type with [total_cmp]. Returns None if they have different types or either of them is null.
+pub(crate) fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    (rank(a) != 0 && rank(a) == rank(b)).then(|| total_cmp(a, b))
+}
+
+fn is_indexed(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Deleted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn index_key_order() {
+        let mut keys: Vec<_> = [
+            Value::from("b"),
+            Value::from(2.5),
+            Value::from(true),
+            Value::from(2),
+            Value::from("a"),
+            Value::from(-1.0),
+            Value::from(false),
+        ]
+        .into_iter()
+        .map(IndexKey)
+        .collect();
+        keys.sort();
+        let values: Vec<_> = keys.into_iter().map(|k| k.0).collect();
+        assert_eq!(
+            values,
+            vec![
+                false.into(),
+                true.into(),
+                (-1.0).into(),
+                2.into(),
+                2.5.into(),
+                "a".into(),
+                "b".into()
+            ]
+        );
+    }
+
+    #[test]
+    fn distinct_number_types() {
+        for kind in [IndexKind::Hash, IndexKind::Ordered] {
+            let mut index = Index::new(kind);
+            index.update(&"a".into(), &Value::Null, &Value::I64(2));
+            index.update(&"b".into(), &Value::Null, &Value::Double(2.0));
+            let rows: Vec<_> = index.get(&Value::I64(2)).collect();
+            assert_eq!(rows, ["a"]);
+            let rows: Vec<_> = index.get(&Value::Double(2.0)).collect();
+            assert_eq!(rows, ["b"]);
+        }
+
+        assert!(IndexKey(Value::I64(2)) < IndexKey(Value::Double(2.0)));
+        assert!(IndexKey(Value::Double(2.0)) < IndexKey(Value::I64(3)));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    clock::{OpId, VectorClock},
+    value::Value,
+};
+
+/// A multi-value register cell. It keeps the concurrent writes to the cell
+/// until a write that has seen them supersedes them.
+///
+/// The writes without context are made by the peers that don't use the
+/// multi-value mode. They only supersede the older writes without context.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct MvRegister {
+    /// Ordered by id
+    values: Vec<MvValue>,
+    /// The latest write without context. It's kept after it's superseded, so
+    /// the older writes without context are rejected in any order.
+    contextless: Option<(OpId, Value)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MvValue {
+    pub id: OpId,
+    pub value: Value,
+    /// The version of the writer when the value was written
+    pub context: Option<VectorClock>,
+}
+
+impl MvRegister {
+    /// Returns false if the register is not changed
+    pub fn merge(&mut self, id: OpId, value: Value, context: Option<VectorClock>) -> bool {
+        if context.is_none() {
+            if self
+                .contextless
+                .as_ref()
+                .is_some_and(|(latest, _)| *latest >= id)
+            {
+                return false;
+            }
+
+            // it replaces the older writes without context even if it's
+            // superseded itself
+            self.values.retain(|v| v.context.is_some());
+            self.contextless = Some((id, value.clone()));
+        }
+
+        if self
+            .values
+            .iter()
+            .any(|v| v.id == id || v.context.as_ref().is_some_and(|c| c.includes(id)))
+        {
+            return context.is_none();
+        }
+
+        if let Some(ctx) = &context {
+            self.values.retain(|v| !ctx.includes(v.id));
+        }
+
+        let pos = self.values.partition_point(|v| v.id < id);
+        self.values.insert(pos, MvValue { id, value, context });
+        true
+    }
+
+    /// Remove the values older than `id`
+    pub fn retain_newer(&mut self, id: OpId) {
+        self.values.retain(|v| v.id > id);
+        self.contextless.take_if(|(latest, _)| *latest < id);
+    }
+
+    pub fn contains(&self, id: OpId) -> bool {
+        self.values.iter().any(|v| v.id == id)
+    }
+
+    /// The latest write without context if it's superseded. It's exported
+    /// with the values, so the replicas reject the same writes.
+    pub fn superseded(&self) -> Option<(OpId, &Value)> {
+        self.contextless
+            .as_ref()
+            .filter(|(id, _)| !self.contains(*id))
+            .map(|(id, value)| (*id, value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn values(&self) -> &[MvValue] {
+        &self.values
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn clock(entries: &[(u64, u32)]) -> Option<VectorClock> {
+        let mut c = VectorClock::new();
+        for (peer, lamport) in entries {
+            c.extend_to_include(OpId::new(*lamport, *peer));
+        }
+        Some(c)
+    }
+
+    #[test]
+    fn keep_concurrent_values() {
+        let mut reg = MvRegister::default();
+        assert!(reg.merge(OpId::new(1, 1), "a".into(), clock(&[])));
+        assert!(reg.merge(OpId::new(1, 2), "b".into(), clock(&[])));
+        assert_eq!(reg.values().len(), 2);
+        // a write that has seen both values supersedes them
+        assert!(reg.merge(OpId::new(2, 1), "c".into(), clock(&[(1, 1), (2, 1)])));
+        assert_eq!(reg.values().len(), 1);
+        assert!(!reg.merge(OpId::new(1, 2), "b".into(), clock(&[])));
+        // the writes without context only replace each other
+        assert!(reg.merge(OpId::new(3, 3), "d".into(), None));
+        assert!(!reg.merge(OpId::new(2, 3), "e".into(), None));
+        assert!(reg.merge(OpId::new(4, 3), "f".into(), None));
+        let values: Vec<_> = reg.values().iter().map(|v| v.value.clone()).collect();
+        assert_eq!(values, vec!["c".into(), "f".into()]);
+    }
+
+    #[test]
+    fn reject_older_writes_without_context() {
+        let mut reg = MvRegister::default();
+        assert!(reg.merge(OpId::new(6, 3), "z".into(), clock(&[(1, 5)])));
+        // superseded, but it still replaces the older writes without context
+        assert!(reg.merge(OpId::new(3, 2), "y".into(), None));
+        assert!(reg.merge(OpId::new(5, 1), "x".into(), None));
+        assert_eq!(reg.superseded(), Some((OpId::new(5, 1), &"x".into())));
+        assert!(!reg.merge(OpId::new(4, 2), "w".into(), None));
+        let values: Vec<_> = reg.values().iter().map(|v| v.value.clone()).collect();
+        assert_eq!(values, vec!["z".into()]);
+    }
+}
@@ -0,0 +1,213 @@
+use smol_str::SmolStr;
+
+use crate::{error::LwwResult, undo::UndoTarget, value::Value, LwwDb};
+
+/// A group of writes that are committed together.
+///
+/// The ops of a transaction get consecutive lamports, are exported together
+/// and are reported as one [crate::Event::Transaction] both locally and on the
+/// peers that import them.
+///
+/// ```
+/// use lww_table::LwwDb;
+///
+/// let mut db = LwwDb::new();
+/// db.transaction(|txn| {
+///     txn.set("issues", "1", "status", "closed");
+///     txn.set("issues", "1", "updated_by", "bob");
+/// });
+/// assert_eq!(db.get_cell("issues", "1", "status").unwrap(), &"closed".into());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    ops: Vec<TxnOp>,
+}
+
+#[derive(Debug, Clone)]
+enum TxnOp {
+    Set {
+        table: SmolStr,
+        row: SmolStr,
+        col: SmolStr,
+        value: Value,
+    },
+    DeleteRow {
+        table: SmolStr,
+        row: SmolStr,
+    },
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, table: &str, row: &str, col: &str, value: impl Into<Value>) {
+        self.ops.push(TxnOp::Set {
+            table: table.into(),
+            row: row.into(),
+            col: col.into(),
+            value: value.into(),
+        });
+    }
+
+    pub fn delete(&mut self, table: &str, row: &str, col: &str) {
+        self.set(table, row, col, Value::Null);
+    }
+
+    pub fn delete_row(&mut self, table: &str, row: &str) {
+        self.ops.push(TxnOp::DeleteRow {
+            table: table.into(),
+            row: row.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+}
+
+impl LwwDb {
+    /// Run `f` and commit the writes it makes to the transaction
+    pub fn transaction<R>(&mut self, f: impl FnOnce(&mut Transaction) -> R) -> R {
+        let mut txn = Transaction::new();
+        let ans = f(&mut txn);
+        self.commit(txn);
+        ans
+    }
+
+    /// Apply all the writes of the transaction
+    ///
+    /// # Panics
+    ///
+    /// Panics if a write violates the schema of its table. Use
+    /// [LwwDb::try_commit] to handle the violation.
+    pub fn commit(&mut self, txn: Transaction) {
+        self.try_commit(txn).unwrap()
+    }
+
+    /// Apply all the writes of the transaction. Nothing is applied if any
+    /// write violates the schema of its table.
+    pub fn try_commit(&mut self, txn: Transaction) -> LwwResult<()> {
+        for op in txn.ops.iter() {
+            if let TxnOp::Set {
+                table, col, value, ..
+            } = op
+            {
+                self.check_schema(table, col, value)?;
+            }
+        }
+
+        self.commit_(txn, UndoTarget::NewStep);
+        Ok(())
+    }
+
+    pub(crate) fn commit_(&mut self, txn: Transaction, undo_target: UndoTarget) {
+        if txn.is_empty() {
+            return;
+        }
+
+        let start = self.oplog.next_lamport();
+        self.observer.start_batch();
+        if let Some(undo) = &mut self.undo {
+            undo.start_group();
+        }
+
+        for op in txn.ops {
+            match op {
+                TxnOp::Set {
+                    table,
+                    row,
+                    col,
+                    value,
+                } => self.set_(&table, &row, &col, value, None),
+                TxnOp::DeleteRow { table, row } => self.delete_row_(&table, &row, None),
+            }
+        }
+
+        let end = self.oplog.next_lamport() - 1;
+        if end >= start {
+            self.oplog.record_txn(self.peer, start, end);
+        }
+
+        if let Some(undo) = &mut self.undo {
+            undo.end_group(undo_target);
+        }
+
+        self.observer.end_batch();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::Event;
+
+    use super::*;
+
+    fn record(db: &mut LwwDb) -> Arc<Mutex<Vec<Event>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        db.subscribe(Box::new(move |e| {
+            events_clone.lock().unwrap().push(e.clone())
+        }));
+        events
+    }
+
+    fn txn_sizes(events: &[Event]) -> Vec<usize> {
+        events
+            .iter()
+            .map(|e| match e {
+                Event::Transaction { events } => events.len(),
+                _ => 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn local_txn_is_one_event() {
+        let mut db = LwwDb::new();
+        db.set("t", "b", "x", 0);
+        let events = record(&mut db);
+        db.transaction(|txn| {
+            txn.set("t", "a", "status", "done");
+            txn.set("t", "a", "updated_by", "bob");
+            txn.delete_row("t", "b");
+        });
+        db.set("t", "c", "x", 1);
+        assert_eq!(txn_sizes(&events.lock().unwrap()), vec![3, 0]);
+    }
+
+    #[test]
+    fn imported_txn_is_one_event() {
+        let mut db = LwwDb::new();
+        db.set("t", "a", "x", 0);
+        let mut txn = Transaction::new();
+        txn.set("t", "a", "status", "done");
+        txn.set("t", "b", "updated_by", "bob");
+        db.commit(txn);
+        db.set("t", "c", "x", 1);
+
+        let mut new_db = LwwDb::new();
+        let events = record(&mut new_db);
+        new_db.import_updates(&db.export_updates(Default::default()));
+        let mut sizes = txn_sizes(&events.lock().unwrap());
+        sizes.sort();
+        assert_eq!(sizes, vec![0, 0, 2]);
+        assert!(db.check_eq(&mut new_db));
+
+        // the transaction survives a snapshot
+        let snapshot_db = LwwDb::from_snapshot(&db.export_snapshot());
+        let mut new_db = LwwDb::new();
+        let events = record(&mut new_db);
+        new_db.import_updates(&snapshot_db.export_updates(Default::default()));
+        let mut sizes = txn_sizes(&events.lock().unwrap());
+        sizes.sort();
+        assert_eq!(sizes, vec![0, 0, 2]);
+    }
+}
@@ -0,0 +1,477 @@
+use std::time::{Duration, Instant};
+
+use fxhash::FxHashMap;
+use smol_str::SmolStr;
+
+use crate::{clock::OpId, error::LwwResult, txn::Transaction, value::Value, LwwDb};
+
+/// Undo/redo of the local edits.
+///
+/// It records the value a local `set`/`delete`/`delete_row` overwrote. Undoing
+/// writes the old values back with new ops, so the undo is synced to the
+/// other peers like any other edit. A cell is only restored if it's still
+/// owned by the local op, so the edits imported from other peers are never
+/// undone.
+///
+/// Enable it with [LwwDb::enable_undo].
+#[derive(Debug, Clone)]
+pub struct UndoManager {
+    undo_stack: Vec<UndoStep>,
+    redo_stack: Vec<UndoStep>,
+    merge_interval: Duration,
+    max_steps: usize,
+    last_record: Option<Instant>,
+    /// The restores recorded by the transaction being committed
+    group: Option<Vec<CellRestore>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct UndoStep {
+    restores: Vec<CellRestore>,
+}
+
+/// Restore the cell to `value` if the cell is still owned by `id`
+#[derive(Debug, Clone)]
+pub(crate) struct CellRestore {
+    pub table: SmolStr,
+    pub row: SmolStr,
+    pub col: SmolStr,
+    /// The value before the op
+    pub value: Value,
+    /// The op that overwrote the value
+    pub id: OpId,
+    /// The op that owned the cell before `id`
+    pub prev: OpId,
+}
+
+/// Where the step recorded by a commit goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UndoTarget {
+    /// A new local edit, it clears the redo stack
+    NewStep,
+    /// The commit is undoing a step, so it can be redone
+    Redo,
+    /// The commit is redoing a step, so it can be undone again
+    Undo,
+}
+
+impl Default for UndoManager {
+    fn default() -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            merge_interval: Duration::ZERO,
+            max_steps: 100,
+            last_record: None,
+            group: None,
+        }
+    }
+}
+
+impl UndoManager {
+    /// Local edits made within `interval` of the previous one are undone together
+    pub fn set_merge_interval(&mut self, interval: Duration) {
+        self.merge_interval = interval;
+    }
+
+    pub fn set_max_undo_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+        self.trim();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.last_record = None;
+    }
+
+    pub(crate) fn record(&mut self, restores: Vec<CellRestore>) {
+        if restores.is_empty() {
+            return;
+        }
+
+        if let Some(group) = &mut self.group {
+            group.extend(restores);
+            return;
+        }
+
+        let now = Instant::now();
+        let merge = self
+            .last_record
+            .is_some_and(|last| now.duration_since(last) < self.merge_interval);
+        match self.undo_stack.last_mut() {
+            Some(step) if merge => step.restores.extend(restores),
+            _ => self.undo_stack.push(UndoStep { restores }),
+        }
+
+        self.last_record = Some(now);
+        self.redo_stack.clear();
+        self.trim();
+    }
+
+    pub(crate) fn start_group(&mut self) {
+        self.group = Some(Vec::new());
+    }
+
+    pub(crate) fn end_group(&mut self, target: UndoTarget) {
+        let Some(restores) = self.group.take() else {
+            return;
+        };
+
+        if restores.is_empty() {
+            return;
+        }
+
+        let step = UndoStep { restores };
+        match target {
+            UndoTarget::NewStep => {
+                self.undo_stack.push(step);
+                self.redo_stack.clear();
+                // a transaction is never merged with the following edits
+                self.last_record = None;
+            }
+            UndoTarget::Redo => self.redo_stack.push(step),
+            UndoTarget::Undo => self.undo_stack.push(step),
+        }
+
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.undo_stack.len() > self.max_steps {
+            let n = self.undo_stack.len() - self.max_steps;
+            self.undo_stack.drain(..n);
+        }
+    }
+}
+
+impl LwwDb {
+    /// Start recording the local edits so they can be undone
+    pub fn enable_undo(&mut self) {
+        if self.undo.is_none() {
+            self.undo = Some(UndoManager::default());
+        }
+    }
+
+    pub fn undo_manager(&self) -> Option<&UndoManager> {
+        self.undo.as_ref()
+    }
+
+    pub fn undo_manager_mut(&mut self) -> Option<&mut UndoManager> {
+        self.undo.as_mut()
+    }
+
+    /// Undo the last step of the local edits. Returns false if there is nothing to undo.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a restored value violates the schema of its table. Use
+    /// [LwwDb::try_undo] to handle the violation.
+    pub fn undo(&mut self) -> bool {
+        self.try_undo().unwrap()
+    }
+
+    /// Undo the last step of the local edits. Returns false if there is nothing to undo.
+    ///
+    /// If a restored value violates the schema of its table, nothing is
+    /// restored and the step is dropped.
+    pub fn try_undo(&mut self) -> LwwResult<bool> {
+        let Some(step) = self.undo.as_mut().and_then(|u| u.undo_stack.pop()) else {
+            return Ok(false);
+        };
+
+        self.apply_undo_step(step, UndoTarget::Redo)?;
+        Ok(true)
+    }
+
+    /// Redo the last undone step. Returns false if there is nothing to redo.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a restored value violates the schema of its table. Use
+    /// [LwwDb::try_redo] to handle the violation.
+    pub fn redo(&mut self) -> bool {
+        self.try_redo().unwrap()
+    }
+
+    /// Redo the last undone step. Returns false if there is nothing to redo.
+    ///
+    /// If a restored value violates the schema of its table, nothing is
+    /// restored and the step is dropped.
+    pub fn try_redo(&mut self) -> LwwResult<bool> {
+        let Some(step) = self.undo.as_mut().and_then(|u| u.redo_stack.pop()) else {
+            return Ok(false);
+        };
+
+        self.apply_undo_step(step, UndoTarget::Undo)?;
+        Ok(true)
+    }
+
+    fn apply_undo_step(&mut self, step: UndoStep, target: UndoTarget) -> LwwResult<()> {
+        // The restores of a cell are applied from the latest one, and every
+        // one of them moves the owner of the cell back to its previous op, so
+        // the cell gets the value from before its first edit in the step.
+        let mut owners: FxHashMap<(&str, &str, &str), OpId> = FxHashMap::default();
+        let mut values: Vec<&CellRestore> = Vec::new();
+        let mut restored = Vec::new();
+        for restore in step.restores.iter().rev() {
+            let cell = (&*restore.table, &*restore.row, &*restore.col);
+            let owner = match owners.get(&cell) {
+                Some(owner) => *owner,
+                None => self.cell_owner(cell.0, cell.1, cell.2),
+            };
+            if owner != restore.id {
+                continue;
+            }
+
+            owners.insert(cell, restore.prev);
+            match values.iter().position(|r| {
+                r.table == restore.table && r.row == restore.row && r.col == restore.col
+            }) {
+                Some(i) => values[i] = restore,
+                None => values.push(restore),
+            }
+
+            restored.push(restore);
+        }
+
+        for restore in values.iter() {
+            self.check_schema(&restore.table, &restore.col, &restore.value)?;
+        }
+
+        let mut txn = Transaction::new();
+        for restore in values {
+            txn.set(
+                &restore.table,
+                &restore.row,
+                &restore.col,
+                restore.value.clone(),
+            );
+        }
+
+        self.commit_(txn, target);
+
+        // The new ops take the place of the ops that owned the restored cells,
+        // so the steps referring to those ops can still be undone
+        let new_ids: Vec<OpId> = restored
+            .iter()
+            .map(|r| self.cell_owner(&r.table, &r.row, &r.col))
+            .collect();
+        let undo = self.undo.as_mut().unwrap();
+        for step in undo.undo_stack.iter_mut().chain(undo.redo_stack.iter_mut()) {
+            for r in step.restores.iter_mut() {
+                if let Some(i) = restored.iter().position(|x| {
+                    x.prev == r.id && x.table == r.table && x.row == r.row && x.col == r.col
+                }) {
+                    r.id = new_ids[i];
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The id of the op that decides the current value of the cell
+    fn cell_owner(&self, table_str: &str, row: &str, col: &str) -> OpId {
+        let Some(table) = self.tables.get(table_str) else {
+            return OpId::new(0, 0);
+        };
+
+        let mut id = table.removed.unwrap_or(OpId::new(0, 0));
+        if let Some(idx) = table.row_id_to_idx.get(row) {
+            if let Some(deleted) = table.rows[*idx].deleted {
+                id = id.max(deleted);
+            }
+
+            if let Some(c) = table.cols.get(col) {
+                id = id.max(c.id(*idx));
+            }
+        }
+
+        id
+    }
+
+    /// Record the cells that the local op is going to overwrite
+    pub(crate) fn cells_to_restore(
+        &self,
+        table_str: &str,
+        row: &str,
+        col: Option<&str>,
+        id: OpId,
+    ) -> Vec<CellRestore> {
+        let restore = |col: &str, value: &Value| CellRestore {
+            table: table_str.into(),
+            row: row.into(),
+            col: col.into(),
+            value: value.clone(),
+            id,
+            prev: self.cell_owner(table_str, row, col),
+        };
+        match col {
+            Some(col) => {
+                let value = self
+                    .get_cell(table_str, row, col)
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                vec![restore(col, &value)]
+            }
+            None => self
+                .tables
+                .get(table_str)
+                .map(|t| {
+                    t.iter_row_with_id(row)
+                        .filter(|c| c.id.lamport != 0 && c.id < id)
+                        .map(|c| restore(c.col_name, c.value))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ColumnSchema, LwwError, SchemaViolation, TableSchema, ValueType};
+
+    fn cell(db: &LwwDb, row: &str, col: &str) -> Value {
+        db.get_cell("t", row, col).cloned().unwrap_or(Value::Null)
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut db = LwwDb::new();
+        db.enable_undo();
+        db.set("t", "a", "x", 1);
+        db.set("t", "a", "x", 2);
+        db.set("t", "a", "y", 3);
+        db.delete_row("t", "a");
+        assert_eq!(cell(&db, "a", "x"), Value::Null);
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(2));
+        assert_eq!(cell(&db, "a", "y"), Value::I64(3));
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "y"), Value::Null);
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(1));
+        assert!(db.redo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(2));
+        assert!(db.redo());
+        assert!(db.redo());
+        assert_eq!(cell(&db, "a", "x"), Value::Null);
+        assert!(!db.redo());
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "y"), Value::I64(3));
+
+        // a new edit clears the redo stack
+        db.set("t", "b", "x", 1);
+        assert!(!db.redo());
+    }
+
+    #[test]
+    fn merge_interval_and_txn() {
+        let mut db = LwwDb::new();
+        db.enable_undo();
+        db.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::from_secs(1000));
+        db.set("t", "a", "x", 1);
+        db.set("t", "a", "y", 1);
+        db.transaction(|txn| {
+            txn.set("t", "a", "x", 2);
+            txn.set("t", "a", "y", 2);
+        });
+        db.set("t", "a", "z", 1);
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "z"), Value::Null);
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(1));
+        assert_eq!(cell(&db, "a", "y"), Value::I64(1));
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::Null);
+        assert_eq!(cell(&db, "a", "y"), Value::Null);
+        assert!(!db.undo());
+    }
+
+    #[test]
+    fn undo_repeated_edits_in_one_step() {
+        let mut db = LwwDb::new();
+        db.set("t", "a", "x", 0);
+        db.set("t", "b", "x", 0);
+        db.enable_undo();
+        db.undo_manager_mut()
+            .unwrap()
+            .set_merge_interval(Duration::from_secs(1000));
+        db.set("t", "a", "x", 1);
+        db.set("t", "a", "x", 2);
+        db.set("t", "b", "x", 1);
+        db.set("t", "a", "x", 3);
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(0));
+        assert_eq!(cell(&db, "b", "x"), Value::I64(0));
+        assert!(!db.undo());
+        assert!(db.redo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(3));
+        assert_eq!(cell(&db, "b", "x"), Value::I64(1));
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(0));
+    }
+
+    #[test]
+    fn remote_edits_are_not_undone() {
+        let mut a = LwwDb::new();
+        a.set_peer(1);
+        a.enable_undo();
+        let mut b = LwwDb::new();
+        b.set_peer(2);
+        a.set("t", "r", "x", "a");
+        a.set("t", "r", "y", "a");
+        b.import_updates(&a.export_updates(Default::default()));
+        b.set("t", "r", "x", "b");
+        a.import_updates(&b.export_updates(a.version().clone()));
+        assert!(a.undo());
+        assert_eq!(cell(&a, "r", "y"), Value::Null);
+        assert!(a.undo());
+        // overwritten by b, so it's kept
+        assert_eq!(cell(&a, "r", "x"), "b".into());
+        assert!(!a.undo());
+    }
+
+    #[test]
+    fn undo_checks_schema() {
+        let mut db = LwwDb::new();
+        db.register_schema(
+            "t",
+            TableSchema::new()
+                .column(ColumnSchema::new("x", ValueType::I64))
+                .column(ColumnSchema::new("y", ValueType::I64).nullable(true)),
+        );
+        db.enable_undo();
+        db.set("t", "a", "x", 1);
+        db.set("t", "a", "y", 1);
+        db.set("t", "a", "x", 2);
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "x"), Value::I64(1));
+        assert!(db.undo());
+        assert_eq!(cell(&db, "a", "y"), Value::Null);
+        // x can't be restored to null
+        assert!(matches!(
+            db.try_undo(),
+            Err(LwwError::SchemaViolation(
+                SchemaViolation::NotNullable { .. }
+            ))
+        ));
+        assert_eq!(cell(&db, "a", "x"), Value::I64(1));
+        assert!(!db.try_undo().unwrap());
+        assert!(db.redo());
+        assert_eq!(cell(&db, "a", "y"), Value::I64(1));
+    }
+}